@@ -0,0 +1,137 @@
+use crate::error::{Error, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A network in CIDR notation like `10.0.0.0/8` or `fd00::/8`. A bare address
+/// is a network of itself alone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // clients of dual-stack sockets come as IPv4-mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = ((prefix_len / 8) as usize, prefix_len % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidAcl(s.to_owned());
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap().parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Whether `ip` is in any network of `acl`.
+pub fn allows(acl: &[Cidr], ip: IpAddr) -> bool {
+    acl.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Everyone, the default of who may query.
+pub fn any() -> Vec<Cidr> {
+    vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()]
+}
+
+/// The loopback and private networks, the default of who may recurse, so that
+/// the server is not an open resolver.
+pub fn local_networks() -> Vec<Cidr> {
+    [
+        "127.0.0.0/8",
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "::1",
+        "fc00::/7",
+        "fe80::/10",
+    ]
+    .iter()
+    .map(|s| s.parse().unwrap())
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidr() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "10.0.0.1".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("fd00::/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn match_addresses() {
+        let acl: Vec<Cidr> = ["10.0.0.0/8", "192.168.1.128/25", "fd00::/8"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert!(allows(&acl, ip("10.1.2.3")));
+        assert!(allows(&acl, ip("192.168.1.200")));
+        assert!(!allows(&acl, ip("192.168.1.100")));
+        assert!(allows(&acl, ip("fd12::1")));
+        assert!(!allows(&acl, ip("fe80::1")));
+        assert!(allows(&acl, ip("::ffff:10.0.0.1")));
+        assert!(!allows(&acl, ip("11.0.0.1")));
+
+        assert!(allows(&any(), ip("8.8.8.8")));
+        assert!(allows(&any(), ip("2001:db8::1")));
+        assert!(allows(&local_networks(), ip("127.0.0.1")));
+        assert!(!allows(&local_networks(), ip("8.8.8.8")));
+        assert!(!allows(&[], ip("127.0.0.1")));
+    }
+}
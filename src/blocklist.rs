@@ -0,0 +1,323 @@
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use crate::error::{Error, Result};
+use log::*;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+
+/// TTL of the addresses answered for blocked names.
+const BLOCKED_TTL: u32 = 60;
+
+/// How to answer a query for a blocked name.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockResponse {
+    NxDomain,
+    /// `0.0.0.0` or `::`, and no data for the other types
+    Null,
+    Refused,
+}
+
+impl FromStr for BlockResponse {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            "null" => Ok(BlockResponse::Null),
+            "refused" => Ok(BlockResponse::Refused),
+            _ => Err(Error::InvalidBlockResponse(s.to_owned())),
+        }
+    }
+}
+
+/// Names that hosts files map for the local machine, rather than to block.
+fn is_local_host(name: &str) -> bool {
+    [
+        "localhost",
+        "localhost.localdomain",
+        "broadcasthost",
+        "local",
+    ]
+    .contains(&name)
+        || name.starts_with("ip6-")
+}
+
+/// A set of domains from lists in hosts-file or plain-domain format. Names in
+/// hosts files match exactly, plain domains match themselves and all names
+/// below them, and `*.domain` matches only the names below.
+#[derive(Debug, Default)]
+pub struct DomainSet {
+    exact: HashSet<String>,
+    suffixes: HashSet<String>,
+    wildcards: HashSet<String>,
+}
+
+impl DomainSet {
+    pub fn parse(text: &str) -> Self {
+        let mut set = Self::default();
+        for line in text.lines() {
+            set.insert_line(line);
+        }
+        set
+    }
+
+    fn insert_line(&mut self, line: &str) {
+        let line = line.split('#').next().unwrap();
+        let mut tokens = line.split_whitespace();
+        let first = match tokens.next() {
+            Some(first) => first,
+            None => return,
+        };
+        let normalize = |name: &str| name.trim_end_matches('.').to_ascii_lowercase();
+
+        if first.parse::<IpAddr>().is_ok() {
+            self.exact.extend(
+                tokens
+                    .map(normalize)
+                    .filter(|name| !name.is_empty() && !is_local_host(name)),
+            );
+        } else if tokens.next().is_some() {
+            debug!("Ignoring line of blocklist `{}`", line.trim());
+        } else if let Some(domain) = first.strip_prefix("*.") {
+            self.wildcards.insert(normalize(domain));
+        } else {
+            self.suffixes.insert(normalize(first));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.suffixes.len() + self.wildcards.len()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.exact.contains(&name) || self.suffixes.contains(&name) {
+            return true;
+        }
+        let mut rest = name.as_str();
+        while let Some(index) = rest.find('.') {
+            rest = &rest[index + 1..];
+            if self.suffixes.contains(rest) || self.wildcards.contains(rest) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The blocked domains, except the allowed ones.
+#[derive(Debug, Default)]
+pub struct Filter {
+    blocked: DomainSet,
+    allowed: DomainSet,
+}
+
+impl Filter {
+    pub fn load(blocklists: &[PathBuf], allowlists: &[PathBuf]) -> Result<Self> {
+        let mut filter = Self::default();
+        for (paths, set) in [
+            (blocklists, &mut filter.blocked),
+            (allowlists, &mut filter.allowed),
+        ] {
+            for path in paths {
+                for line in std::fs::read_to_string(path)?.lines() {
+                    set.insert_line(line);
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn is_blocked(&self, name: &str) -> bool {
+        self.blocked.contains(name) && !self.allowed.contains(name)
+    }
+}
+
+/// Blocks queries by the filter loaded from lists, which is reloaded whenever
+/// the files change.
+pub struct Blocker {
+    blocklists: Vec<PathBuf>,
+    allowlists: Vec<PathBuf>,
+    response: BlockResponse,
+    filter: RwLock<Arc<Filter>>,
+    modified: RwLock<Vec<Option<SystemTime>>>,
+}
+
+impl Blocker {
+    pub fn new(
+        blocklists: Vec<PathBuf>,
+        allowlists: Vec<PathBuf>,
+        response: BlockResponse,
+    ) -> Result<Self> {
+        let blocker = Self {
+            blocklists,
+            allowlists,
+            response,
+            filter: RwLock::new(Arc::new(Filter::default())),
+            modified: RwLock::new(vec![]),
+        };
+        blocker.reload()?;
+        Ok(blocker)
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.blocklists.iter().chain(self.allowlists.iter())
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Load the lists again, keeping the current filter on error.
+    pub fn reload(&self) -> Result<()> {
+        let modified = self.modified_times();
+        let filter = Filter::load(&self.blocklists, &self.allowlists)?;
+        if !self.blocklists.is_empty() {
+            println!(
+                "Loaded {} blocked and {} allowed domains",
+                filter.blocked.len(),
+                filter.allowed.len()
+            );
+        }
+        *self.filter.write().unwrap() = Arc::new(filter);
+        *self.modified.write().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Reload the lists if any of them has changed since the last load.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        if *self.modified.read().unwrap() == self.modified_times() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Check the lists for changes every `interval`.
+    pub async fn watch(&self, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            if let Err(e) = self.reload_if_changed() {
+                warn!("error reloading blocklists: {}", e);
+            }
+        }
+    }
+
+    pub fn is_blocked(&self, name: &str) -> bool {
+        self.filter.read().unwrap().is_blocked(name)
+    }
+
+    /// The response to a query for a blocked name.
+    pub fn block(&self, question: DnsQuestion) -> DnsPacket {
+        let mut response_packet = DnsPacket::default();
+        response_packet.header.rescode = match self.response {
+            BlockResponse::NxDomain => ResultCode::NXDOMAIN,
+            BlockResponse::Refused => ResultCode::REFUSED,
+            BlockResponse::Null => {
+                let name = question.name.clone();
+                match question.query_type {
+                    QueryType::A => response_packet.answers.push(DnsRecord::A {
+                        name,
+                        addr: Ipv4Addr::UNSPECIFIED,
+                        ttl: BLOCKED_TTL,
+                    }),
+                    QueryType::AAAA => response_packet.answers.push(DnsRecord::AAAA {
+                        name,
+                        addr: Ipv6Addr::UNSPECIFIED,
+                        ttl: BLOCKED_TTL,
+                    }),
+                    _ => {}
+                }
+                ResultCode::NOERROR
+            }
+        };
+        response_packet.questions.push(question);
+        response_packet.update_header_counts();
+        response_packet
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn question(name: &str, query_type: QueryType) -> DnsQuestion {
+        DnsQuestion {
+            name: name.into(),
+            query_type,
+        }
+    }
+
+    #[test]
+    fn match_domains() {
+        let set = DomainSet::parse(include_str!("../res/blocklist.hosts"));
+        assert!(set.contains("ads.example.com"));
+        assert!(set.contains("Tracker.Example.NET."));
+        assert!(!set.contains("sub.ads.example.com"));
+        assert!(!set.contains("localhost"));
+
+        let set = DomainSet::parse(include_str!("../res/blocklist.txt"));
+        assert!(set.contains("malware.test"));
+        assert!(set.contains("a.b.malware.test"));
+        assert!(!set.contains("notmalware.test"));
+        assert!(!set.contains("doubleclick.example"));
+        assert!(set.contains("ad.doubleclick.example"));
+        assert!(!set.contains("bad line"));
+    }
+
+    #[test]
+    fn allow_and_block() {
+        let res = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("res");
+        let blocker = Blocker::new(
+            vec![res.join("blocklist.hosts"), res.join("blocklist.txt")],
+            vec![res.join("allowlist.txt")],
+            BlockResponse::Null,
+        )
+        .unwrap();
+        assert!(blocker.is_blocked("ads.example.com"));
+        assert!(blocker.is_blocked("x.malware.test"));
+        assert!(!blocker.is_blocked("safe.malware.test"));
+        assert!(!blocker.is_blocked("bugen.dev"));
+
+        let response = blocker.block(question("ads.example.com", QueryType::AAAA));
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers[0].rdata_text(), "::");
+        let response = blocker.block(question("ads.example.com", QueryType::MX));
+        assert!(response.answers.is_empty());
+
+        let blocker = Blocker {
+            response: BlockResponse::NxDomain,
+            ..blocker
+        };
+        let response = blocker.block(question("ads.example.com", QueryType::A));
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(response.questions.len(), 1);
+    }
+
+    #[test]
+    fn reload_on_change() {
+        let path = std::env::temp_dir().join(format!("dnser-blocklist-{}", std::process::id()));
+        std::fs::write(&path, "one.test\n").unwrap();
+        let blocker = Blocker::new(vec![path.clone()], vec![], BlockResponse::Refused).unwrap();
+        assert!(blocker.is_blocked("one.test"));
+        assert!(!blocker.reload_if_changed().unwrap());
+
+        std::fs::write(&path, "two.test\n# and more to change the size\n").unwrap();
+        // in case the file system keeps coarse modification times
+        *blocker.modified.write().unwrap() = vec![None];
+        assert!(blocker.reload_if_changed().unwrap());
+        assert!(!blocker.is_blocked("one.test"));
+        assert!(blocker.is_blocked("two.test"));
+
+        // a vanished list keeps the last filter
+        std::fs::remove_file(&path).unwrap();
+        assert!(blocker.reload_if_changed().is_err());
+        assert!(blocker.is_blocked("two.test"));
+    }
+}
@@ -0,0 +1,695 @@
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, CLASS_IN};
+use crate::utils::is_subdomain_of;
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MAX_CNAME_CHAIN: usize = 8;
+// RFC 2308, section 5: negative answers should not be cached for more than 3 hours
+const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+// NXDOMAIN applies to every type of a name, so it is stored under a single key
+const NXDOMAIN_TYPE: QueryType = QueryType::Unknown;
+// RFC 8767, section 4: the TTL of stale answers
+const STALE_TTL: u32 = 30;
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct CacheKey {
+    pub name: String,
+    pub query_type: QueryType,
+    pub class: u16,
+}
+
+impl CacheKey {
+    pub fn new(name: &str, query_type: QueryType) -> Self {
+        Self {
+            name: name.to_ascii_lowercase(),
+            query_type,
+            class: CLASS_IN,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CacheData {
+    Records(Vec<DnsRecord>),
+    /// A cached NXDOMAIN or NODATA (`NOERROR` without records) answer, along
+    /// with the SOA record that limits its lifetime
+    Negative {
+        rescode: ResultCode,
+        soa: DnsRecord,
+    },
+}
+
+impl CacheData {
+    fn set_ttl(&mut self, ttl: u32) {
+        match self {
+            CacheData::Records(records) => records.iter_mut().for_each(|r| r.set_ttl(ttl)),
+            CacheData::Negative { soa, .. } => soa.set_ttl(ttl),
+        }
+    }
+}
+
+struct CacheEntry {
+    data: CacheData,
+    expires_at: Instant,
+    last_used: u64,
+    ttl: u32,
+    // hits since the entry was (re)inserted
+    hits: u32,
+    prefetching: bool,
+}
+
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // last_used tick -> key, the first one is the least recently used
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    capacity: usize,
+    stale_window: Duration,
+}
+
+impl CacheInner {
+    fn touch(&mut self, key: &CacheKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.lru.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey, now: Instant, stale: bool) -> Option<CacheData> {
+        let expires_at = self.entries.get(key)?.expires_at;
+        let ttl = if expires_at > now {
+            (expires_at - now).as_secs() as u32
+        } else if expires_at + self.stale_window <= now {
+            self.remove(key);
+            return None;
+        } else if stale {
+            STALE_TTL
+        } else {
+            // keep it for serving stale
+            return None;
+        };
+        self.touch(key);
+
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.hits = entry.hits.saturating_add(1);
+        let mut data = entry.data.clone();
+        data.set_ttl(ttl);
+        Some(data)
+    }
+
+    fn insert(&mut self, key: CacheKey, data: CacheData, ttl: u32, now: Instant) {
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            match self.lru.keys().next().cloned() {
+                Some(oldest) => {
+                    let oldest_key = self.lru.remove(&oldest).unwrap();
+                    self.entries.remove(&oldest_key);
+                }
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                data,
+                expires_at: now + Duration::from_secs(ttl as u64),
+                last_used: self.tick,
+                ttl,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+    }
+}
+
+/// A shared in-memory cache of RRsets, keyed by (name, type, class).
+///
+/// Entries expire at an absolute instant derived from the smallest TTL in the
+/// RRset, and the least recently used entry is evicted once `capacity` RRsets
+/// are stored. Expired entries are kept for `stale_window` so that they can
+/// be served stale as in RFC 8767.
+pub struct Cache {
+    inner: Mutex<CacheInner>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                capacity: capacity.max(1),
+                stale_window: Duration::from_secs(0),
+            }),
+        }
+    }
+
+    pub fn serve_stale(self, stale_window: Duration) -> Self {
+        self.inner.lock().unwrap().stale_window = stale_window;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    fn get_data(&self, name: &str, query_type: QueryType, stale: bool) -> Option<CacheData> {
+        self.inner
+            .lock()
+            .unwrap()
+            .get(&CacheKey::new(name, query_type), Instant::now(), stale)
+    }
+
+    fn get_records(
+        &self,
+        name: &str,
+        query_type: QueryType,
+        stale: bool,
+    ) -> Option<Vec<DnsRecord>> {
+        match self.get_data(name, query_type, stale)? {
+            CacheData::Records(records) => Some(records),
+            CacheData::Negative { .. } => None,
+        }
+    }
+
+    /// Get the RRset of `name` and `query_type`, with TTLs decremented by the
+    /// time spent in the cache.
+    pub fn get(&self, name: &str, query_type: QueryType) -> Option<Vec<DnsRecord>> {
+        self.get_records(name, query_type, false)
+    }
+
+    /// Build a response to `question` from the cache, following cached CNAMEs.
+    /// Returns `None` unless the whole chain can be answered from the cache,
+    /// either positively or negatively.
+    pub fn lookup(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        self.lookup_worker(question, false)
+    }
+
+    /// Like `lookup`, but expired entries within the stale window are used as
+    /// well, with their TTLs set to 30 seconds.
+    pub fn lookup_stale(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        self.lookup_worker(question, true)
+    }
+
+    /// Whether the cached answer of `question` has been hit at least
+    /// `min_hits` times and has less than `ttl_percent` of its TTL left. The
+    /// entry is marked so that it is prefetched only once until refreshed.
+    pub fn should_prefetch(&self, question: &DnsQuestion, min_hits: u32, ttl_percent: u32) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let key = [NXDOMAIN_TYPE, question.query_type, QueryType::CNAME]
+            .iter()
+            .map(|&t| CacheKey::new(&question.name, t))
+            .find(|key| inner.entries.contains_key(key));
+        let entry = match key.and_then(|key| inner.entries.get_mut(&key)) {
+            Some(entry) if !entry.prefetching && entry.expires_at > now => entry,
+            _ => return false,
+        };
+
+        let remaining = (entry.expires_at - now).as_secs();
+        if entry.hits >= min_hits && remaining * 100 < entry.ttl as u64 * ttl_percent as u64 {
+            entry.prefetching = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn lookup_worker(&self, question: &DnsQuestion, stale: bool) -> Option<DnsPacket> {
+        let query_type = question.query_type;
+        let mut packet = DnsPacket::default();
+        packet.questions.push(question.clone());
+
+        let mut name = question.name.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let data = self
+                .get_data(&name, NXDOMAIN_TYPE, stale)
+                .or_else(|| self.get_data(&name, query_type, stale));
+            match data {
+                Some(CacheData::Records(records)) => {
+                    packet.answers.extend(records);
+                    packet.update_header_counts();
+                    return Some(packet);
+                }
+                Some(CacheData::Negative { rescode, soa }) => {
+                    packet.header.rescode = rescode;
+                    packet.authorities.push(soa);
+                    packet.update_header_counts();
+                    return Some(packet);
+                }
+                None => {}
+            }
+            if query_type == QueryType::CNAME {
+                return None;
+            }
+            let record = self.get_records(&name, QueryType::CNAME, stale)?.pop()?;
+            match record {
+                DnsRecord::CNAME { ref host, .. } => name = host.clone(),
+                _ => return None,
+            }
+            packet.answers.push(record);
+        }
+        None
+    }
+
+    /// Insert records into the cache, grouped into RRsets by name and type.
+    /// Records with a zero TTL are not cached.
+    pub fn insert_records<'a>(&self, records: impl IntoIterator<Item = &'a DnsRecord>) {
+        let mut rrsets: HashMap<CacheKey, Vec<DnsRecord>> = HashMap::new();
+        for record in records {
+            rrsets
+                .entry(CacheKey::new(record.name(), record.query_type()))
+                .or_default()
+                .push(record.clone());
+        }
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        for (key, records) in rrsets {
+            let ttl = records.iter().map(DnsRecord::ttl).min().unwrap_or(0);
+            if ttl == 0 {
+                continue;
+            }
+            inner.insert(key, CacheData::Records(records), ttl, now);
+        }
+    }
+
+    /// Insert a negative answer for `name`, which lives for the smaller one of
+    /// the SOA's TTL and its MINIMUM field, as in RFC 2308.
+    pub fn insert_negative(
+        &self,
+        name: &str,
+        query_type: QueryType,
+        rescode: ResultCode,
+        soa: &DnsRecord,
+    ) {
+        let ttl = match *soa {
+            DnsRecord::SOA { ttl, minimum, .. } => ttl.min(minimum).min(MAX_NEGATIVE_TTL),
+            _ => return,
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let key = match rescode {
+            ResultCode::NXDOMAIN => CacheKey::new(name, NXDOMAIN_TYPE),
+            _ => CacheKey::new(name, query_type),
+        };
+        let mut soa = soa.clone();
+        soa.set_ttl(ttl);
+        self.inner.lock().unwrap().insert(
+            key,
+            CacheData::Negative { rescode, soa },
+            ttl,
+            Instant::now(),
+        );
+    }
+
+    /// Cache a final response to `question` from a server of `zone`: the
+    /// records of the question name and its CNAME chain as RRsets, and the
+    /// response itself if it is an NXDOMAIN or NODATA one carrying an SOA.
+    /// Names out of `zone` are not cached, so that a server cannot poison the
+    /// names it is not authoritative for.
+    pub fn insert_response(&self, question: &DnsQuestion, response: &DnsPacket, zone: &str) {
+        let rescode = response.header.rescode;
+        if rescode != ResultCode::NOERROR && rescode != ResultCode::NXDOMAIN {
+            return;
+        }
+
+        let mut chain = Vec::new();
+        let mut name = question.name.as_str();
+        let mut answered = false;
+        for _ in 0..MAX_CNAME_CHAIN {
+            if !is_subdomain_of(name, zone) {
+                break;
+            }
+            let owned: Vec<_> = response
+                .answers
+                .iter()
+                .filter(|r| r.name().eq_ignore_ascii_case(name))
+                .collect();
+            let answers: Vec<_> = owned
+                .iter()
+                .copied()
+                .filter(|r| r.query_type() == question.query_type)
+                .collect();
+            if !answers.is_empty() {
+                chain.extend(answers);
+                answered = true;
+                break;
+            }
+            match owned
+                .into_iter()
+                .find(|r| r.query_type() == QueryType::CNAME)
+            {
+                Some(cname @ DnsRecord::CNAME { host, .. }) => {
+                    chain.push(cname);
+                    name = host;
+                }
+                _ => break,
+            }
+        }
+        self.insert_records(chain);
+        if answered || !is_subdomain_of(name, zone) {
+            return;
+        }
+
+        // the negative answer is about the last name of the CNAME chain
+        let soa = response.authorities.iter().find(|r| {
+            r.query_type() == QueryType::SOA
+                && is_subdomain_of(r.name(), zone)
+                && is_subdomain_of(name, r.name())
+        });
+        if let Some(soa) = soa {
+            self.insert_negative(name, question.query_type, rescode, soa);
+        }
+    }
+
+    /// Find the closest enclosing zone cut of `name` whose name servers have
+    /// cached addresses.
+    pub fn closest_delegation(&self, name: &str) -> Option<(String, Vec<Ipv4Addr>)> {
+        let name = name.to_ascii_lowercase();
+        let mut zone = name.as_str();
+        loop {
+            if let Some(nss) = self.get(zone, QueryType::NS) {
+                let addrs: Vec<_> = nss
+                    .iter()
+                    .filter_map(|ns| match ns {
+                        DnsRecord::NS { host, .. } => self.get(host, QueryType::A),
+                        _ => None,
+                    })
+                    .flatten()
+                    .filter_map(|a| match a {
+                        DnsRecord::A { addr, .. } => Some(addr),
+                        _ => None,
+                    })
+                    .collect();
+                if !addrs.is_empty() {
+                    return Some((zone.to_owned(), addrs));
+                }
+            }
+            match zone.find('.') {
+                Some(i) => zone = &zone[i + 1..],
+                None => return None,
+            }
+        }
+    }
+
+    /// Get the hosts of the closest enclosing zone cut of `name` with cached
+    /// NS records.
+    pub fn closest_name_servers(&self, name: &str) -> Vec<String> {
+        let name = name.to_ascii_lowercase();
+        let mut zone = name.as_str();
+        loop {
+            if let Some(nss) = self.get(zone, QueryType::NS) {
+                return nss
+                    .into_iter()
+                    .filter_map(|ns| match ns {
+                        DnsRecord::NS { host, .. } => Some(host),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            match zone.find('.') {
+                Some(i) => zone = &zone[i + 1..],
+                None => return Vec::new(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn a(name: &str, addr: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            name: name.into(),
+            addr: addr.parse().unwrap(),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let cache = Cache::new(16);
+        cache.insert_records(&[
+            a("bugen.dev", "1.2.3.4", 300),
+            a("bugen.dev", "1.2.3.5", 60),
+        ]);
+
+        let records = cache.get("BUGEN.dev", QueryType::A).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.ttl() <= 60));
+        assert!(cache.get("bugen.dev", QueryType::AAAA).is_none());
+    }
+
+    #[test]
+    fn zero_ttl_not_cached() {
+        let cache = Cache::new(16);
+        cache.insert_records(&[a("bugen.dev", "1.2.3.4", 0)]);
+        assert!(cache.get("bugen.dev", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = Cache::new(2);
+        cache.insert_records(&[a("a.dev", "1.1.1.1", 300)]);
+        cache.insert_records(&[a("b.dev", "2.2.2.2", 300)]);
+        assert!(cache.get("a.dev", QueryType::A).is_some());
+
+        cache.insert_records(&[a("c.dev", "3.3.3.3", 300)]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("a.dev", QueryType::A).is_some());
+        assert!(cache.get("b.dev", QueryType::A).is_none());
+        assert!(cache.get("c.dev", QueryType::A).is_some());
+    }
+
+    fn question(name: &str, query_type: QueryType) -> DnsQuestion {
+        DnsQuestion {
+            name: name.into(),
+            query_type,
+        }
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            name: "bugen.dev".into(),
+            mname: "ns1.bugen.dev".into(),
+            rname: "i.bugenzhao.com".into(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn follow_cname_chain() {
+        let cache = Cache::new(16);
+        cache.insert_records(&[
+            DnsRecord::CNAME {
+                name: "www.bugen.dev".into(),
+                host: "bugen.dev".into(),
+                ttl: 300,
+            },
+            a("bugen.dev", "1.2.3.4", 300),
+        ]);
+
+        let packet = cache
+            .lookup(&question("www.bugen.dev", QueryType::A))
+            .unwrap();
+        assert_eq!(packet.header.answers, 2);
+        assert_eq!(packet.answers[0].query_type(), QueryType::CNAME);
+        assert_eq!(packet.answers[1].query_type(), QueryType::A);
+        assert!(cache
+            .lookup(&question("www.bugen.dev", QueryType::MX))
+            .is_none());
+    }
+
+    #[test]
+    fn negative_nxdomain() {
+        let cache = Cache::new(16);
+        let mut response = DnsPacket::default();
+        response.header.rescode = ResultCode::NXDOMAIN;
+        response.authorities.push(soa(3600, 60));
+        cache.insert_response(&question("nope.bugen.dev", QueryType::A), &response, "");
+
+        // NXDOMAIN holds for every type of the name
+        let packet = cache
+            .lookup(&question("nope.bugen.dev", QueryType::AAAA))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(packet.header.authoritative_entries, 1);
+        assert!(packet.authorities[0].ttl() <= 60);
+    }
+
+    #[test]
+    fn cache_only_in_zone_chain() {
+        let cache = Cache::new(16);
+        let mut response = DnsPacket::default();
+        response.answers.extend(vec![
+            DnsRecord::CNAME {
+                name: "www.evil.test".into(),
+                host: "cdn.evil.test".into(),
+                ttl: 300,
+            },
+            a("cdn.evil.test", "6.6.6.6", 300),
+            // unrelated to the question
+            a("bank.test", "6.6.6.6", 300),
+            a("other.evil.test", "6.6.6.6", 300),
+        ]);
+        cache.insert_response(
+            &question("www.evil.test", QueryType::A),
+            &response,
+            "evil.test",
+        );
+        assert!(cache.get("cdn.evil.test", QueryType::A).is_some());
+        assert!(cache.get("bank.test", QueryType::A).is_none());
+        assert!(cache.get("other.evil.test", QueryType::A).is_none());
+
+        // a chain leaving the zone of the server stops there
+        response.answers[0] = DnsRecord::CNAME {
+            name: "mail.evil.test".into(),
+            host: "bank.test".into(),
+            ttl: 300,
+        };
+        // and so is an SOA of a zone above it
+        let mut soa = soa(3600, 600);
+        if let DnsRecord::SOA { ref mut name, .. } = soa {
+            *name = "test".into();
+        }
+        response.authorities.push(soa);
+        cache.insert_response(
+            &question("mail.evil.test", QueryType::A),
+            &response,
+            "evil.test",
+        );
+        assert!(cache.get("mail.evil.test", QueryType::CNAME).is_some());
+        assert!(cache.get("bank.test", QueryType::A).is_none());
+        assert!(cache.lookup(&question("bank.test", QueryType::A)).is_none());
+    }
+
+    #[test]
+    fn negative_nodata_after_cname() {
+        let cache = Cache::new(16);
+        let mut response = DnsPacket::default();
+        response.answers.push(DnsRecord::CNAME {
+            name: "www.bugen.dev".into(),
+            host: "bugen.dev".into(),
+            ttl: 300,
+        });
+        response.authorities.push(soa(30, 600));
+        cache.insert_response(
+            &question("www.bugen.dev", QueryType::AAAA),
+            &response,
+            "bugen.dev",
+        );
+
+        let packet = cache
+            .lookup(&question("www.bugen.dev", QueryType::AAAA))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers.len(), 1);
+        assert!(packet.authorities[0].ttl() <= 30);
+        // NODATA only holds for the type asked
+        assert!(cache.lookup(&question("bugen.dev", QueryType::A)).is_none());
+        assert!(cache
+            .lookup(&question("bugen.dev", QueryType::AAAA))
+            .is_some());
+    }
+
+    #[test]
+    fn serve_stale() {
+        let cache = Cache::new(16).serve_stale(Duration::from_secs(60));
+        {
+            let mut inner = cache.inner.lock().unwrap();
+            let inserted = Instant::now() - Duration::from_secs(310);
+            let records = CacheData::Records(vec![a("bugen.dev", "1.2.3.4", 300)]);
+            inner.insert(
+                CacheKey::new("bugen.dev", QueryType::A),
+                records,
+                300,
+                inserted,
+            );
+            let records = CacheData::Records(vec![a("old.bugen.dev", "1.2.3.4", 300)]);
+            let inserted = Instant::now() - Duration::from_secs(400);
+            let key = CacheKey::new("old.bugen.dev", QueryType::A);
+            inner.insert(key, records, 300, inserted);
+        }
+
+        let q = question("bugen.dev", QueryType::A);
+        assert!(cache.lookup(&q).is_none());
+        let packet = cache.lookup_stale(&q).unwrap();
+        assert_eq!(packet.answers[0].ttl(), STALE_TTL);
+
+        assert!(cache
+            .lookup_stale(&question("old.bugen.dev", QueryType::A))
+            .is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn prefetch_hot_entries() {
+        let cache = Cache::new(16);
+        {
+            let mut inner = cache.inner.lock().unwrap();
+            let inserted = Instant::now() - Duration::from_secs(280);
+            let records = CacheData::Records(vec![a("bugen.dev", "1.2.3.4", 300)]);
+            inner.insert(
+                CacheKey::new("bugen.dev", QueryType::A),
+                records,
+                300,
+                inserted,
+            );
+        }
+
+        let q = question("bugen.dev", QueryType::A);
+        assert!(cache.lookup(&q).is_some());
+        assert!(!cache.should_prefetch(&q, 2, 10));
+        assert!(cache.lookup(&q).is_some());
+        assert!(cache.should_prefetch(&q, 2, 10));
+        // only once until refreshed
+        assert!(!cache.should_prefetch(&q, 2, 10));
+
+        cache.insert_records(&[a("bugen.dev", "1.2.3.4", 300)]);
+        cache.lookup(&q);
+        cache.lookup(&q);
+        assert!(!cache.should_prefetch(&q, 2, 10));
+    }
+
+    #[test]
+    fn closest_delegation() {
+        let cache = Cache::new(16);
+        cache.insert_records(&[
+            DnsRecord::NS {
+                name: "dev".into(),
+                host: "ns.nic.dev".into(),
+                ttl: 300,
+            },
+            a("ns.nic.dev", "9.9.9.9", 300),
+        ]);
+
+        let (zone, addrs) = cache.closest_delegation("www.bugen.dev").unwrap();
+        assert_eq!(zone, "dev");
+        assert_eq!(addrs, vec!["9.9.9.9".parse::<Ipv4Addr>().unwrap()]);
+        assert!(cache.closest_delegation("bugen.com").is_none());
+    }
+}
@@ -1,12 +1,26 @@
-use crate::dns_packet::{DnsPacket, DnsRecord, QueryType, ResultCode};
-use crate::dns_packet_buf::DnsPacketBuf;
+use crate::cache::Cache;
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use crate::dns_packet_buf::{DnsPacketBuf, TCP_PACKET_SIZE};
 use crate::error::{Error, Result};
+use crate::journal::{serial_lt, soa_serial, Diff, Journal};
+use crate::metrics::Metrics;
+use crate::recursive::AuthorityNsRecord;
+use crate::tsig::{Signer, TsigKey, Verifier};
+use crate::update::UpdateMessage;
+use crate::utils::is_subdomain_of;
 
 use async_recursion::async_recursion;
 use log::*;
-use tokio::net::UdpSocket;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
 
-use std::net::Ipv4Addr;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub async fn lookup(
     domain: &str,
@@ -21,45 +35,302 @@ pub async fn lookup(
     packet.write(&mut send_buf)?;
     socket.send(&send_buf.buf[0..send_buf.pos]).await?;
 
-    let mut recv_buf = DnsPacketBuf::new();
-    let (_, response_server) = socket.recv_from(&mut recv_buf.buf).await?;
-    let response_packet = DnsPacket::read_from(&mut recv_buf)?;
+    let response_packet = timeout(LOOKUP_TIMEOUT, recv_response(&socket, &packet))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response from the server"))??;
 
     info!(
-        "Received answer for {} from {} => {:#?}",
-        domain, response_server, response_packet
+        "Received answer for {} from {:?} => {:#?}",
+        domain, server, response_packet
     );
 
     Ok(response_packet)
 }
 
+/// Receive the response to `query`, dropping the datagrams that are not, like
+/// spoofed ones with another id or question.
+async fn recv_response(socket: &UdpSocket, query: &DnsPacket) -> io::Result<DnsPacket> {
+    let same_question = |a: &DnsQuestion, b: &DnsQuestion| {
+        a.query_type == b.query_type && a.name.eq_ignore_ascii_case(&b.name)
+    };
+    loop {
+        let mut recv_buf = DnsPacketBuf::new();
+        socket.recv(&mut recv_buf.buf).await?;
+        match DnsPacket::read_from(&mut recv_buf) {
+            Ok(response)
+                if response.header.response
+                    && response.header.id == query.header.id
+                    && response.questions.len() == query.questions.len()
+                    && response
+                        .questions
+                        .iter()
+                        .zip(query.questions.iter())
+                        .all(|(a, b)| same_question(a, b)) =>
+            {
+                return Ok(response)
+            }
+            Ok(response) => warn!(
+                "Dropping a response with id {} not matching the query {}",
+                response.header.id, query.header.id
+            ),
+            Err(e) => warn!("Dropping a malformed response: {}", e),
+        }
+    }
+}
+
+/// Send a dynamic update to `server` over UDP, signed with `key` if given,
+/// returning its response.
+pub async fn update(
+    server: SocketAddr,
+    message: &UpdateMessage,
+    key: Option<&TsigKey>,
+) -> Result<DnsPacket> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.connect(server).await?;
+
+    let mut send_buf = DnsPacketBuf::new();
+    message.write(&mut send_buf)?;
+    let mut verifier = match key {
+        Some(key) => {
+            let mut signer = Signer::new(key);
+            signer.sign(&mut send_buf)?;
+            Some(Verifier::new(&signer))
+        }
+        None => None,
+    };
+    socket.send(&send_buf.buf[0..send_buf.pos]).await?;
+
+    let mut recv_buf = DnsPacketBuf::new();
+    let len = timeout(LOOKUP_TIMEOUT, socket.recv(&mut recv_buf.buf))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response from the server"))??;
+    if let Some(verifier) = verifier.as_mut() {
+        verifier.verify(&recv_buf.buf[..len])?;
+    }
+    let response_packet = DnsPacket::read_from(&mut recv_buf)?;
+    if response_packet.header.id != message.header.id {
+        return Err(Error::InvalidUpdate(format!(
+            "response id {} does not match {}",
+            response_packet.header.id, message.header.id
+        )));
+    }
+    Ok(response_packet)
+}
+
+/// The result of transferring a zone from its primary.
+#[derive(Debug)]
+pub enum Transfer {
+    UpToDate,
+    Full(Vec<DnsRecord>),
+    Incremental(Vec<Diff>),
+}
+
+/// Transfer `zone` from `primary` over TCP, with IXFR if the SOA record of the
+/// current version is given, or AXFR otherwise. The query is signed with `key`
+/// if given, and so must be the responses.
+pub async fn transfer(
+    zone: &str,
+    primary: SocketAddr,
+    current_soa: Option<&DnsRecord>,
+    key: Option<&TsigKey>,
+) -> Result<Transfer> {
+    timeout(
+        TRANSFER_TIMEOUT,
+        transfer_messages(zone, primary, current_soa, key),
+    )
+    .await
+    .map_err(|_| Error::TransferFailed(format!("transfer of `{}` timed out", zone)))?
+}
+
+async fn transfer_messages(
+    zone: &str,
+    primary: SocketAddr,
+    current_soa: Option<&DnsRecord>,
+    key: Option<&TsigKey>,
+) -> Result<Transfer> {
+    let mut stream = TcpStream::connect(primary).await?;
+
+    let query_type = match current_soa {
+        Some(_) => QueryType::IXFR,
+        None => QueryType::AXFR,
+    };
+    let mut query = DnsPacket::example(zone, query_type);
+    query.header.recursion_desired = false;
+    query.authorities.extend(current_soa.cloned());
+    query.update_header_counts();
+    let mut buf = DnsPacketBuf::new();
+    query.write(&mut buf)?;
+    let mut verifier = match key {
+        Some(key) => {
+            let mut signer = Signer::new(key);
+            signer.sign(&mut buf)?;
+            Some(Verifier::new(&signer))
+        }
+        None => None,
+    };
+    stream.write_u16(buf.pos as u16).await?;
+    stream.write_all(&buf.buf[0..buf.pos]).await?;
+
+    let mut records = Vec::new();
+    loop {
+        let len = stream.read_u16().await? as usize;
+        let mut buf = DnsPacketBuf::with_size(len.min(TCP_PACKET_SIZE));
+        stream.read_exact(&mut buf.buf).await?;
+        if let Some(verifier) = verifier.as_mut() {
+            verifier.verify(&buf.buf)?;
+        }
+        let response = DnsPacket::read_from(&mut buf)?;
+        if response.header.id != query.header.id {
+            return Err(Error::TransferFailed(format!(
+                "unexpected message {} in transfer of `{}`",
+                response.header.id, zone
+            )));
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(Error::TransferFailed(format!(
+                "{} refused to transfer `{}` with {:?}",
+                primary, zone, response.header.rescode
+            )));
+        }
+
+        records.extend(response.answers);
+        if let Some(transfer) = finish_transfer(zone, &mut records, current_soa)? {
+            if let Some(verifier) = verifier.as_ref() {
+                verifier.finish()?;
+            }
+            info!("Transferred {} from {}", zone, primary);
+            return Ok(transfer);
+        }
+    }
+}
+
+/// Tell whether the records received so far complete a transfer. A full one
+/// is wrapped in the SOA records of the new version, and an incremental one
+/// consists of diffs between them, as in RFC 1995.
+fn finish_transfer(
+    zone: &str,
+    records: &mut Vec<DnsRecord>,
+    current_soa: Option<&DnsRecord>,
+) -> Result<Option<Transfer>> {
+    let is_soa = |r: &DnsRecord| r.query_type() == QueryType::SOA;
+    let serial = match records.first() {
+        None => return Ok(None),
+        Some(soa) if is_soa(soa) => soa_serial(soa)?,
+        Some(_) => {
+            return Err(Error::TransferFailed(format!(
+                "transfer of `{}` does not start with an SOA record",
+                zone
+            )))
+        }
+    };
+    if let Some(current_soa) = current_soa {
+        if !serial_lt(soa_serial(current_soa)?, serial) {
+            return Ok(Some(Transfer::UpToDate));
+        }
+    }
+
+    let last = records.last().unwrap();
+    if records.len() < 2 || soa_serial(last).ok() != Some(serial) {
+        return Ok(None);
+    }
+    let incremental = soa_serial(&records[1]).is_ok_and(|s| s != serial);
+    if !incremental {
+        records.pop();
+        return Ok(Some(Transfer::Full(std::mem::take(records))));
+    }
+    if current_soa.is_none() {
+        return Err(Error::TransferFailed(format!(
+            "incremental transfer of `{}` without a version to apply it to",
+            zone
+        )));
+    }
+
+    // the SOA records of diffs come in pairs, before the final one
+    if records[1..].iter().filter(|r| is_soa(r)).count() % 2 == 0 {
+        return Ok(None);
+    }
+    let diffs = records[1..records.len() - 1].to_vec();
+    Ok(Some(Transfer::Incremental(
+        Journal::from_records(diffs)?.diffs().to_vec(),
+    )))
+}
+
+/// Cache the NS records and glue of a referral, as long as the delegated zone
+/// lies within `zone`, the zone of the server that sent the referral.
+fn cache_delegation(cache: &Cache, response: &DnsPacket, nss: &[AuthorityNsRecord], zone: &str) {
+    let in_bailiwick = |name: &str| is_subdomain_of(name, zone);
+    let hosts: Vec<_> = nss
+        .iter()
+        .filter(|ns| in_bailiwick(&ns.ns_name))
+        .map(|ns| ns.ns_host.as_str())
+        .collect();
+
+    let ns_records = response.authorities.iter().filter(|r| match r {
+        DnsRecord::NS { name, host, .. } => in_bailiwick(name) && hosts.contains(&host.as_str()),
+        _ => false,
+    });
+    let glue_records = response.resources.iter().filter(|r| match r {
+        DnsRecord::A { name, .. } => in_bailiwick(name) && hosts.contains(&name.as_str()),
+        _ => false,
+    });
+    cache.insert_records(ns_records.chain(glue_records));
+}
+
 #[async_recursion]
 pub async fn recursive_lookup(
     domain: &str,
     query_type: QueryType,
     root_server: (Ipv4Addr, u16),
+    cache: Option<&'async_recursion Cache>,
+    metrics: Option<&'async_recursion Metrics>,
     depth: u8,
 ) -> Result<DnsPacket> {
     if depth > 10 {
         return Err(Error::TooManyRecursion(domain.to_owned()));
     }
 
-    let mut ns = root_server.to_owned();
+    // start from the closest known zone cut if possible
+    let (mut zone, mut ns) = match cache.and_then(|c| c.closest_delegation(domain)) {
+        Some((zone, addrs)) => (zone, (addrs[0], 53)),
+        None => (String::new(), root_server),
+    };
+    // from a server of `zone`
+    let cache_response = |response: &DnsPacket, zone: &str| {
+        if let Some(cache) = cache {
+            let question = DnsQuestion {
+                name: domain.to_owned(),
+                query_type,
+            };
+            cache.insert_response(&question, response, zone);
+        }
+    };
     'outer: loop {
-        let response = lookup(domain, query_type, ns).await?;
+        let sent = Instant::now();
+        let result = lookup(domain, query_type, ns).await;
+        if let Some(metrics) = metrics {
+            metrics.upstream_rtt(ns, sent.elapsed(), result.as_ref().err());
+        }
+        let response = result?;
         match response.header.rescode {
             ResultCode::NXDOMAIN => {
+                cache_response(&response, &zone);
                 return Ok(response);
             }
             ResultCode::NOERROR if !response.answers.is_empty() => {
+                cache_response(&response, &zone);
                 return Ok(response);
             }
             _ => {
                 let nss = response.get_authority_ns(domain);
                 // no ns provided
                 if nss.is_empty() {
+                    cache_response(&response, &zone);
                     return Ok(response);
                 }
+                if let Some(cache) = cache {
+                    cache_delegation(cache, &response, &nss, &zone);
+                }
+                zone = nss.first().unwrap().ns_name.clone();
                 // try resolving any ns
                 for ns_record in nss.iter() {
                     if let Some(ns_addr) = response.resolve_in_resources(&ns_record.ns_host) {
@@ -72,6 +343,8 @@ pub async fn recursive_lookup(
                     &nss.first().unwrap().ns_host,
                     QueryType::A,
                     root_server,
+                    cache,
+                    metrics,
                     depth + 1,
                 )
                 .await?;
@@ -84,3 +357,81 @@ pub async fn recursive_lookup(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zone_file::parse_zone;
+
+    const IXFR: &str = "
+$TTL 300
+@   SOA ns admin 2 2 3 4 5
+@   SOA ns admin 1 2 3 4 5
+www A   10.0.0.1
+@   SOA ns admin 2 2 3 4 5
+www A   10.0.0.2
+@   SOA ns admin 2 2 3 4 5
+";
+
+    #[tokio::test]
+    async fn drop_mismatched_responses() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = DnsPacketBuf::new();
+            let (_, from) = server.recv_from(&mut buf.buf).await.unwrap();
+            let query = DnsPacket::read_from(&mut buf).unwrap();
+            let send = |mut packet: DnsPacket, answer: [u8; 4]| {
+                packet.header.response = true;
+                packet.answers.push(DnsRecord::A {
+                    name: packet.questions[0].name.clone(),
+                    addr: answer.into(),
+                    ttl: 300,
+                });
+                packet.update_header_counts();
+                let mut buf = DnsPacketBuf::new();
+                packet.write(&mut buf).unwrap();
+                buf.buf[..buf.pos].to_vec()
+            };
+
+            let mut spoofed = query.clone();
+            spoofed.header.id = query.header.id.wrapping_add(1);
+            let other = DnsPacket {
+                header: query.header.clone(),
+                ..DnsPacket::example("evil.dev", QueryType::A)
+            };
+            for (packet, answer) in [
+                (spoofed, [6, 6, 6, 6]),
+                (other, [6, 6, 6, 6]),
+                (query, [10, 0, 0, 1]),
+            ] {
+                server.send_to(&send(packet, answer), from).await.unwrap();
+            }
+        });
+
+        let response = lookup("bugen.dev", QueryType::A, (Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        assert_eq!(response.questions[0].name, "bugen.dev");
+        assert_eq!(
+            response.answers[0],
+            DnsRecord::A {
+                name: "bugen.dev".into(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: 300,
+            }
+        );
+    }
+
+    #[test]
+    fn finish_incremental_transfer() {
+        let records = parse_zone(IXFR, "bugen.dev").unwrap();
+        let current_soa = parse_zone("@ 300 SOA ns admin 1 2 3 4 5", "bugen.dev").unwrap();
+        match finish_transfer("bugen.dev", &mut records.clone(), current_soa.first()) {
+            Ok(Some(Transfer::Incremental(diffs))) => assert_eq!(diffs.len(), 1),
+            _ => panic!("not an incremental transfer"),
+        }
+        // without a zone to apply the diffs to, like in response to AXFR
+        assert!(finish_transfer("bugen.dev", &mut records.clone(), None).is_err());
+    }
+}
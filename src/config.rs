@@ -0,0 +1,519 @@
+use crate::acl::{self, Cidr};
+use crate::blocklist::BlockResponse;
+use crate::dns_packet::DnsRecord;
+use crate::error::{Error, Result};
+use crate::forward::ForwardRule;
+use crate::local;
+use crate::rrl::RateLimitOptions;
+use crate::secondary::SecondarySpec;
+use crate::server::ServerOptions;
+use crate::tsig;
+use crate::view::{self, Scoped, ViewOptions};
+use crate::zone::ZoneSpec;
+use serde::de::{self, Deserialize, Deserializer};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Deserialize the types in the same forms as their flags.
+macro_rules! deserialize_from_str {
+    ($($type:ty),*) => {$(
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(de::Error::custom)
+            }
+        }
+    )*};
+}
+
+deserialize_from_str!(Cidr, ForwardRule, ZoneSpec, SecondarySpec, BlockResponse);
+
+/// Deserialize records in master file format, like `--local-data`.
+pub fn deserialize_records<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<DnsRecord>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| local::parse_record(s).map_err(de::Error::custom))
+        .collect()
+}
+
+/// Run the server, configured by flags over the TOML file given by
+/// `--config`, whose keys are the long flags
+#[derive(Clone, Debug, Default, StructOpt, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    /// Configuration file in TOML
+    #[structopt(short, long)]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    /// Root server to recurse from, or upstream to forward to with `--proxy`
+    /// [default: 198.41.0.4]
+    #[structopt(short, long)]
+    pub server: Option<Ipv4Addr>,
+    // spelled out for structopt to take it as a flag, not an optional value
+    #[structopt(long, parse(from_flag = set_if_present))]
+    pub proxy: std::option::Option<bool>,
+    /// Per-zone routing rule, in the form of
+    /// `<suffix>=<forward|recurse>:<addr>[:port][,<addr>[:port]...]`
+    #[structopt(long = "rule")]
+    pub rules: Vec<ForwardRule>,
+    /// Record to answer authoritatively before any zone, forwarding or
+    /// recursion, in master file format like `db.internal 300 A 10.0.0.5`,
+    /// where an owner of `*.domain` answers for all the names below
+    #[structopt(long = "local-data", parse(try_from_str = local::parse_record))]
+    #[serde(deserialize_with = "deserialize_records")]
+    pub local_data: Vec<DnsRecord>,
+    /// Domain whose whole subtree is answered from the local data only,
+    /// with NXDOMAIN for the names without any record
+    #[structopt(long = "local-zone")]
+    pub local_zones: Vec<String>,
+    /// Zone to serve authoritatively from a master file, in the form of
+    /// `<origin>=<path>`
+    #[structopt(long = "zone")]
+    pub zones: Vec<ZoneSpec>,
+    /// Zone to transfer from its primary and serve as a secondary, in the
+    /// form of `<origin>=<addr>[:port][@key]`, signing the transfers with
+    /// the TSIG key if given
+    #[structopt(long = "secondary")]
+    pub secondaries: Vec<SecondarySpec>,
+    /// Network in CIDR notation, or address, allowed to query [default:
+    /// any]
+    #[structopt(long)]
+    pub allow_query: Vec<Cidr>,
+    /// Network allowed to query names out of the local zones, which are
+    /// forwarded or resolved recursively [default: loopback and private
+    /// networks]
+    #[structopt(long)]
+    pub allow_recursion: Vec<Cidr>,
+    /// Network allowed to transfer the zones with AXFR or IXFR
+    #[structopt(long)]
+    pub allow_transfer: Vec<Cidr>,
+    /// Network allowed to update the zones given by `--zone` dynamically
+    #[structopt(long)]
+    pub allow_update: Vec<Cidr>,
+    /// File of TSIG keys in the format of BIND, with which clients sign
+    /// their messages and secondaries sign their transfers
+    #[structopt(long = "tsig-key")]
+    pub tsig_keys: Vec<PathBuf>,
+    /// Name of a TSIG key with which clients may transfer the zones from any
+    /// address
+    #[structopt(long = "allow-transfer-key")]
+    pub allow_transfer_keys: Vec<String>,
+    /// Name of a TSIG key with which clients may update the zones from any
+    /// address
+    #[structopt(long = "allow-update-key")]
+    pub allow_update_keys: Vec<String>,
+    /// Port to listen on all IPv4 interfaces, if no `--listen` is given
+    /// [default: 55553]
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+    /// Address to listen on, like `127.0.0.1:53` or `[::1]:53`. Responses
+    /// leave from the address the query arrived on, even for a wildcard one
+    #[structopt(long)]
+    pub listen: Vec<SocketAddr>,
+    /// Maximum number of RRsets kept in the cache [default: 4096]
+    #[structopt(long)]
+    pub cache_size: Option<usize>,
+    /// Seconds to keep serving expired cache entries when upstreams fail,
+    /// zero to disable [default: 86400]
+    #[structopt(long)]
+    pub serve_stale: Option<u64>,
+    /// Milliseconds to wait for fresh resolution before answering stale
+    /// [default: 1800]
+    #[structopt(long)]
+    pub stale_answer_timeout: Option<u64>,
+    /// Cache hits needed before a record close to expiry is prefetched,
+    /// zero to disable [default: 3]
+    #[structopt(long)]
+    pub prefetch_hits: Option<u32>,
+    /// Percentage of the TTL left that triggers prefetching [default: 10]
+    #[structopt(long)]
+    pub prefetch_ttl_percent: Option<u32>,
+    /// Seconds before an idle TCP connection is closed [default: 10]
+    #[structopt(long)]
+    pub tcp_idle_timeout: Option<u64>,
+    /// Maximum number of concurrent TCP connections [default: 128]
+    #[structopt(long)]
+    pub max_tcp_connections: Option<usize>,
+    /// Seconds to wait for the queries being answered on shutdown [default:
+    /// 5]
+    #[structopt(long)]
+    pub shutdown_timeout: Option<u64>,
+    /// Address to serve the metrics on over HTTP at `/metrics`, in the text
+    /// format of Prometheus, like `127.0.0.1:9153`
+    #[structopt(long)]
+    pub metrics: Option<SocketAddr>,
+    /// Responses per second over UDP to a client prefix, beyond which
+    /// they are dropped or slipped, zero to disable [default: 0]
+    #[structopt(long)]
+    pub rrl_responses_per_second: Option<u32>,
+    /// NXDOMAIN responses per second to a client prefix [default: as
+    /// `--rrl-responses-per-second`]
+    #[structopt(long)]
+    pub rrl_nxdomains_per_second: Option<u32>,
+    /// Error responses per second to a client prefix [default: as
+    /// `--rrl-responses-per-second`]
+    #[structopt(long)]
+    pub rrl_errors_per_second: Option<u32>,
+    /// Every this many limited responses, one is sent truncated instead of
+    /// dropped, so that real clients retry over TCP, zero to drop all
+    /// [default: 2]
+    #[structopt(long)]
+    pub rrl_slip: Option<u32>,
+    /// Length of the IPv4 prefixes limited as a whole [default: 24]
+    #[structopt(long)]
+    pub rrl_ipv4_prefix_len: Option<u8>,
+    /// Length of the IPv6 prefixes limited as a whole [default: 56]
+    #[structopt(long)]
+    pub rrl_ipv6_prefix_len: Option<u8>,
+    /// List of domains to block, in hosts-file format or one domain per
+    /// line, where `example.com` also blocks its subdomains and
+    /// `*.example.com` blocks only them
+    #[structopt(long = "blocklist")]
+    pub blocklists: Vec<PathBuf>,
+    /// List of domains never to block, in the same formats
+    #[structopt(long = "allowlist")]
+    pub allowlists: Vec<PathBuf>,
+    /// Answer to blocked queries: `nxdomain`, `null` for 0.0.0.0 and ::,
+    /// or `refused` [default: nxdomain]
+    #[structopt(long)]
+    pub block_response: Option<BlockResponse>,
+    /// Seconds between checks of the lists for changes, zero to disable
+    /// reloading [default: 60]
+    #[structopt(long)]
+    pub blocklist_reload_interval: Option<u64>,
+    /// Response policy zone to apply to the names resolved, in the form of
+    /// `<origin>=<path>`, where the first of several with a matching
+    /// trigger decides the action
+    #[structopt(long)]
+    pub rpz: Vec<ZoneSpec>,
+    /// View for the clients from some networks, in the form of
+    /// `<name>=<cidr>[,<cidr>...]`. The first matching view answers a query
+    /// with its own options below, instead of the local data, zones, rules
+    /// and ACLs above
+    #[structopt(long = "view")]
+    #[serde(rename = "view")]
+    pub views: Vec<ViewOptions>,
+    /// Local record of a view, in the form of `<view>:<record>`
+    #[structopt(long, parse(try_from_str = view::parse_scoped_record))]
+    #[serde(skip)]
+    pub view_local_data: Vec<Scoped<DnsRecord>>,
+    /// Static local subtree of a view, in the form of `<view>:<domain>`
+    #[structopt(long)]
+    #[serde(skip)]
+    pub view_local_zone: Vec<Scoped<String>>,
+    /// Zone of a view, in the form of `<view>:<origin>=<path>`
+    #[structopt(long)]
+    #[serde(skip)]
+    pub view_zone: Vec<Scoped<ZoneSpec>>,
+    /// Routing rule of a view, in the form of `<view>:<rule>`
+    #[structopt(long)]
+    #[serde(skip)]
+    pub view_rule: Vec<Scoped<ForwardRule>>,
+    /// Network allowed to query a view, in the form of `<view>:<cidr>`
+    #[structopt(long)]
+    #[serde(skip)]
+    pub view_allow_query: Vec<Scoped<Cidr>>,
+    /// Network allowed to recurse in a view, in the form of `<view>:<cidr>`
+    #[structopt(long)]
+    #[serde(skip)]
+    pub view_allow_recursion: Vec<Scoped<Cidr>>,
+    /// Network allowed to transfer the zones of a view, in the form of
+    /// `<view>:<cidr>`
+    #[structopt(long)]
+    #[serde(skip)]
+    pub view_allow_transfer: Vec<Scoped<Cidr>>,
+}
+
+/// Taking the values of another layer of configuration over this one.
+trait Merge {
+    fn merge(&mut self, other: Self);
+}
+
+impl<T> Merge for Option<T> {
+    fn merge(&mut self, other: Self) {
+        if other.is_some() {
+            *self = other;
+        }
+    }
+}
+
+impl<T> Merge for Vec<T> {
+    fn merge(&mut self, other: Self) {
+        if !other.is_empty() {
+            *self = other;
+        }
+    }
+}
+
+/// A flag given on the command line as set, and as unset otherwise so that
+/// it does not override the file.
+fn set_if_present(present: bool) -> Option<bool> {
+    if present {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+macro_rules! merge_fields {
+    ($self:ident, $other:ident, $($field:ident),*) => {
+        $($self.$field.merge($other.$field);)*
+    };
+}
+
+impl ServerConfig {
+    /// Load the file at `path`, whose paths are relative to the working
+    /// directory like the ones of flags.
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid =
+            |message: String| Error::InvalidConfig(format!("`{}`: {}", path.display(), message));
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        toml::from_str(&text).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// The file given by `--config` if any, overridden by the flags.
+    pub fn resolve(self) -> Result<Self> {
+        let mut config = match self.config {
+            Some(ref path) => Self::load(path)?,
+            None => Self::default(),
+        };
+        config.merge(self);
+        Ok(config)
+    }
+
+    fn merge(&mut self, other: Self) {
+        merge_fields!(
+            self,
+            other,
+            config,
+            server,
+            proxy,
+            rules,
+            local_data,
+            local_zones,
+            zones,
+            secondaries,
+            allow_query,
+            allow_recursion,
+            allow_transfer,
+            allow_update,
+            tsig_keys,
+            allow_transfer_keys,
+            allow_update_keys,
+            port,
+            listen,
+            cache_size,
+            serve_stale,
+            stale_answer_timeout,
+            prefetch_hits,
+            prefetch_ttl_percent,
+            tcp_idle_timeout,
+            max_tcp_connections,
+            shutdown_timeout,
+            metrics,
+            rrl_responses_per_second,
+            rrl_nxdomains_per_second,
+            rrl_errors_per_second,
+            rrl_slip,
+            rrl_ipv4_prefix_len,
+            rrl_ipv6_prefix_len,
+            blocklists,
+            allowlists,
+            block_response,
+            blocklist_reload_interval,
+            rpz,
+            views,
+            view_local_data,
+            view_local_zone,
+            view_zone,
+            view_rule,
+            view_allow_query,
+            view_allow_recursion,
+            view_allow_transfer
+        );
+    }
+
+    /// The options to run the server with, filling in the defaults and
+    /// loading the TSIG keys.
+    pub fn into_options(self) -> Result<ServerOptions> {
+        let mut views = self.views;
+        view::distribute(&mut views, self.view_local_data, |v| &mut v.local_data)?;
+        view::distribute(&mut views, self.view_local_zone, |v| &mut v.local_zones)?;
+        view::distribute(&mut views, self.view_zone, |v| &mut v.zones)?;
+        view::distribute(&mut views, self.view_rule, |v| &mut v.rules)?;
+        view::distribute(&mut views, self.view_allow_query, |v| &mut v.allow_query)?;
+        view::distribute(&mut views, self.view_allow_recursion, |v| {
+            &mut v.allow_recursion
+        })?;
+        view::distribute(&mut views, self.view_allow_transfer, |v| {
+            &mut v.allow_transfer
+        })?;
+
+        let mut tsig_keys = Vec::new();
+        for path in self.tsig_keys.iter() {
+            tsig_keys.extend(tsig::load_keys(path)?);
+        }
+        let key_names = |names: Vec<String>| {
+            names
+                .into_iter()
+                .map(|name| {
+                    let name = name.trim_end_matches('.').to_ascii_lowercase();
+                    if tsig_keys.iter().any(|key| key.name == name) {
+                        Ok(name)
+                    } else {
+                        Err(Error::InvalidTsigKey(format!("no key `{}`", name)))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let allow_transfer_keys = key_names(self.allow_transfer_keys)?;
+        let allow_update_keys = key_names(self.allow_update_keys)?;
+        let responses_per_second = self.rrl_responses_per_second.unwrap_or(0);
+
+        Ok(ServerOptions {
+            remote_server: (self.server.unwrap_or(Ipv4Addr::new(198, 41, 0, 4)), 53),
+            listen: if self.listen.is_empty() {
+                vec![SocketAddr::from(([0, 0, 0, 0], self.port.unwrap_or(55553)))]
+            } else {
+                self.listen
+            },
+            proxy: self.proxy.unwrap_or(false),
+            rules: self.rules,
+            local_data: self.local_data,
+            local_zones: self.local_zones,
+            zones: self.zones,
+            secondaries: self.secondaries,
+            allow_query: if self.allow_query.is_empty() {
+                acl::any()
+            } else {
+                self.allow_query
+            },
+            allow_recursion: if self.allow_recursion.is_empty() {
+                acl::local_networks()
+            } else {
+                self.allow_recursion
+            },
+            allow_transfer: self.allow_transfer,
+            allow_update: self.allow_update,
+            tsig_keys,
+            allow_transfer_keys,
+            allow_update_keys,
+            cache_size: self.cache_size.unwrap_or(4096),
+            stale_window: Duration::from_secs(self.serve_stale.unwrap_or(86400)),
+            stale_answer_timeout: Duration::from_millis(self.stale_answer_timeout.unwrap_or(1800)),
+            prefetch_hits: self.prefetch_hits.unwrap_or(3),
+            prefetch_ttl_percent: self.prefetch_ttl_percent.unwrap_or(10),
+            tcp_idle_timeout: Duration::from_secs(self.tcp_idle_timeout.unwrap_or(10)),
+            max_tcp_connections: self.max_tcp_connections.unwrap_or(128),
+            shutdown_timeout: Duration::from_secs(self.shutdown_timeout.unwrap_or(5)),
+            metrics: self.metrics,
+            rate_limit: RateLimitOptions {
+                responses_per_second,
+                nxdomains_per_second: self
+                    .rrl_nxdomains_per_second
+                    .unwrap_or(responses_per_second),
+                errors_per_second: self.rrl_errors_per_second.unwrap_or(responses_per_second),
+                slip: self.rrl_slip.unwrap_or(2),
+                ipv4_prefix_len: self.rrl_ipv4_prefix_len.unwrap_or(24).min(32),
+                ipv6_prefix_len: self.rrl_ipv6_prefix_len.unwrap_or(56).min(128),
+            },
+            blocklists: self.blocklists,
+            allowlists: self.allowlists,
+            block_response: self.block_response.unwrap_or(BlockResponse::NxDomain),
+            blocklist_reload_interval: Duration::from_secs(
+                self.blocklist_reload_interval.unwrap_or(60),
+            ),
+            rpz: self.rpz,
+            views,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(text: &str) -> Result<ServerConfig> {
+        toml::from_str(text).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    #[test]
+    fn load_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("res/dnser.toml");
+        let config = ServerConfig::load(&path).unwrap();
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.zones[0].origin, "bugen.dev");
+        assert_eq!(config.local_data[0].name(), "db.internal");
+        assert_eq!(config.block_response, Some(BlockResponse::Null));
+        assert_eq!(config.views[0].name, "lab");
+        assert_eq!(config.views[0].local_data.len(), 1);
+
+        let options = config.into_options().unwrap();
+        assert_eq!(options.cache_size, 10000);
+        assert_eq!(options.prefetch_hits, 3);
+        assert_eq!(options.tsig_keys.len(), 2);
+        assert_eq!(options.rate_limit.nxdomains_per_second, 20);
+        assert_eq!(options.allow_transfer[0].to_string(), "10.0.0.2/32");
+        assert_eq!(options.allow_update.len(), 0);
+        assert_eq!(options.allow_transfer_keys, ["transfer"]);
+        assert_eq!(options.allow_update_keys, ["update"]);
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let mut config = parse("port = 5353\ncache-size = 100\nzones = [\"a=a.zone\"]\n").unwrap();
+        let flags = ServerConfig::from_iter(&[
+            "server",
+            "--cache-size",
+            "200",
+            "--zone",
+            "b=b.zone",
+            "--zone",
+            "c=c.zone",
+            "--proxy",
+        ]);
+        config.merge(flags);
+        let options = config.into_options().unwrap();
+        assert_eq!(options.listen[0].port(), 5353);
+        assert_eq!(options.cache_size, 200);
+        assert_eq!(options.zones.len(), 2);
+        assert!(options.proxy);
+        assert_eq!(options.allow_recursion, acl::local_networks());
+    }
+
+    #[test]
+    fn merge_flags() {
+        let proxy = |text: &str, args: &[&str]| {
+            let mut config = parse(text).unwrap();
+            config.merge(ServerConfig::from_iter(args));
+            config.into_options().unwrap().proxy
+        };
+        assert!(!proxy("", &["server"]));
+        assert!(proxy("proxy = true\n", &["server"]));
+        assert!(!proxy("proxy = false\n", &["server"]));
+        assert!(proxy("proxy = false\n", &["server", "--proxy"]));
+    }
+
+    #[test]
+    fn report_errors() {
+        let error = |text: &str| parse(text).unwrap_err().to_string();
+        assert!(error("cache-sise = 1\n").contains("unknown field `cache-sise`"));
+        assert!(error("allow-query = [\"10.0.0.0/33\"]\n").contains("key `allow-query`"));
+        assert!(error("port = \"53\"\n").contains("invalid type"));
+        assert!(error("[[view]]\nmatch-clients = []\n").contains("missing field `name`"));
+        assert!(error("local-data = [\"x A\"]\n").contains("missing fields of A record"));
+
+        let config = parse("view-zone = [\"lab:a=a.zone\"]\n");
+        assert!(config.is_err());
+        let mut config = parse("").unwrap();
+        config.view_zone = vec!["lab:a=a.zone".parse().unwrap()];
+        assert!(config.into_options().is_err());
+        assert!(ServerConfig::load(Path::new("/nonexistent.toml")).is_err());
+    }
+}
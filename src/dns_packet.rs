@@ -1,14 +1,18 @@
 use clap::arg_enum;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use log::*;
 
 use crate::dns_packet_buf::DnsPacketBuf;
 use crate::error::{Error, Result};
+use crate::tsig;
+use crate::utils::{fqdn, quote_character_string};
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug, FromPrimitive, ToPrimitive)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, FromPrimitive, ToPrimitive)]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR,
@@ -16,8 +20,21 @@ pub enum ResultCode {
     NXDOMAIN,
     NOTIMP,
     REFUSED,
+    YXDOMAIN,
+    YXRRSET,
+    NXRRSET,
+    NOTAUTH,
+    NOTZONE,
 }
 
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_NONE: u16 = 254;
+pub const CLASS_ANY: u16 = 255;
+
+pub const OPCODE_QUERY: u8 = 0;
+pub const OPCODE_NOTIFY: u8 = 4;
+pub const OPCODE_UPDATE: u8 = 5;
+
 #[derive(Debug, Clone)]
 pub struct DnsHeader {
     pub id: u16, // 16 bits
@@ -104,14 +121,20 @@ impl DnsHeader {
 }
 
 arg_enum! {
-    #[derive(Copy, Clone, Eq, PartialEq, Debug, FromPrimitive, ToPrimitive)]
+    #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, FromPrimitive, ToPrimitive)]
     pub enum QueryType {
         Unknown = 0,
         A = 1,
         NS = 2,
         CNAME = 5,
+        SOA = 6,
         MX = 15,
+        TXT = 16,
         AAAA = 28,
+        TSIG = 250,
+        IXFR = 251,
+        AXFR = 252,
+        ANY = 255,
     }
 }
 
@@ -157,17 +180,45 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     },
+    SOA {
+        name: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
     MX {
         name: String,
         preference: u16,
         host: String,
         ttl: u32,
     },
+    TXT {
+        name: String,
+        data: Vec<String>,
+        ttl: u32,
+    },
     AAAA {
         name: String,
         addr: Ipv6Addr,
         ttl: u32,
     },
+    /// The signature of a message as in RFC 8945, owned by the name of the key.
+    /// It is always of class ANY with a zero TTL.
+    TSIG {
+        name: String,
+        algorithm: String,
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other: Vec<u8>,
+    },
 }
 
 impl DnsRecord {
@@ -202,6 +253,27 @@ impl DnsRecord {
 
                 Ok(DnsRecord::CNAME { name, host, ttl })
             }
+            QueryType::SOA => {
+                let mname = buf.read_name()?;
+                let rname = buf.read_name()?;
+                let serial = buf.read_u32()?;
+                let refresh = buf.read_u32()?;
+                let retry = buf.read_u32()?;
+                let expire = buf.read_u32()?;
+                let minimum = buf.read_u32()?;
+
+                Ok(DnsRecord::SOA {
+                    name,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                })
+            }
             QueryType::MX => {
                 let preference = buf.read_u16()?;
                 let host = buf.read_name()?;
@@ -213,6 +285,18 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::TXT => {
+                let end = buf.pos + data_len as usize;
+                let mut data = Vec::new();
+                while buf.pos < end {
+                    let len = buf.read_u8()? as usize;
+                    let bytes = buf.peek_range(buf.pos, len)?;
+                    data.push(String::from_utf8_lossy(bytes).into_owned());
+                    buf.step(len);
+                }
+
+                Ok(DnsRecord::TXT { name, data, ttl })
+            }
             QueryType::AAAA => {
                 let addr = Ipv6Addr::new(
                     buf.read_u16()?,
@@ -227,6 +311,30 @@ impl DnsRecord {
 
                 Ok(DnsRecord::AAAA { name, addr, ttl })
             }
+            QueryType::TSIG => {
+                let algorithm = buf.read_name()?;
+                let time_signed = (buf.read_u16()? as u64) << 32 | buf.read_u32()? as u64;
+                let fudge = buf.read_u16()?;
+                let mac_len = buf.read_u16()? as usize;
+                let mac = buf.peek_range(buf.pos, mac_len)?.to_vec();
+                buf.step(mac_len);
+                let original_id = buf.read_u16()?;
+                let error = buf.read_u16()?;
+                let other_len = buf.read_u16()? as usize;
+                let other = buf.peek_range(buf.pos, other_len)?.to_vec();
+                buf.step(other_len);
+
+                Ok(DnsRecord::TSIG {
+                    name,
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other,
+                })
+            }
             _ => {
                 buf.step(data_len as usize);
 
@@ -241,6 +349,12 @@ impl DnsRecord {
     }
 
     pub fn write(&self, buf: &mut DnsPacketBuf) -> Result<()> {
+        self.write_with_class(buf, CLASS_IN)
+    }
+
+    /// Write the record with another class, as in the update section of an
+    /// UPDATE message.
+    pub fn write_with_class(&self, buf: &mut DnsPacketBuf, class: u16) -> Result<()> {
         macro_rules! write_host_name {
             ($host:ident) => {
                 let data_len_pos = buf.pos;
@@ -269,7 +383,7 @@ impl DnsRecord {
             } => {
                 buf.write_name_simple(name)?;
                 buf.write_u16(QueryType::A.to_u16().unwrap())?;
-                buf.write_u16(1)?; // class
+                buf.write_u16(class)?;
                 buf.write_u32(ttl)?;
 
                 buf.write_u16(4)?; // data_len
@@ -284,7 +398,7 @@ impl DnsRecord {
             } => {
                 buf.write_name_simple(name)?;
                 buf.write_u16(QueryType::NS.to_u16().unwrap())?;
-                buf.write_u16(1)?; // class
+                buf.write_u16(class)?;
                 buf.write_u32(ttl)?;
 
                 write_host_name!(host);
@@ -296,11 +410,40 @@ impl DnsRecord {
             } => {
                 buf.write_name_simple(name)?;
                 buf.write_u16(QueryType::CNAME.to_u16().unwrap())?;
-                buf.write_u16(1)?; // class
+                buf.write_u16(class)?;
                 buf.write_u32(ttl)?;
 
                 write_host_name!(host);
             }
+            DnsRecord::SOA {
+                ref name,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buf.write_name_simple(name)?;
+                buf.write_u16(QueryType::SOA.to_u16().unwrap())?;
+                buf.write_u16(class)?;
+                buf.write_u32(ttl)?;
+
+                let data_len_pos = buf.pos;
+                buf.write_u16(0)?; // temp data_len
+                buf.write_name_simple(mname)?;
+                buf.write_name_simple(rname)?;
+                buf.write_u32(serial)?;
+                buf.write_u32(refresh)?;
+                buf.write_u32(retry)?;
+                buf.write_u32(expire)?;
+                buf.write_u32(minimum)?;
+
+                let data_len = buf.pos - data_len_pos - 2;
+                buf.set_u16(data_len_pos, data_len as u16)?;
+            }
             DnsRecord::MX {
                 ref name,
                 preference,
@@ -309,11 +452,36 @@ impl DnsRecord {
             } => {
                 buf.write_name_simple(name)?;
                 buf.write_u16(QueryType::MX.to_u16().unwrap())?;
-                buf.write_u16(1)?; // class
+                buf.write_u16(class)?;
                 buf.write_u32(ttl)?;
 
                 write_host_name!(host, preference);
             }
+            DnsRecord::TXT {
+                ref name,
+                ref data,
+                ttl,
+            } => {
+                buf.write_name_simple(name)?;
+                buf.write_u16(QueryType::TXT.to_u16().unwrap())?;
+                buf.write_u16(class)?;
+                buf.write_u32(ttl)?;
+
+                let data_len_pos = buf.pos;
+                buf.write_u16(0)?; // temp data_len
+                for string in data {
+                    if string.len() > 0xFF {
+                        return Err(Error::CharacterStringLengthExceeded(string.clone()));
+                    }
+                    buf.write_u8(string.len() as u8)?;
+                    for &b in string.as_bytes() {
+                        buf.write_u8(b)?;
+                    }
+                }
+
+                let data_len = buf.pos - data_len_pos - 2;
+                buf.set_u16(data_len_pos, data_len as u16)?;
+            }
             DnsRecord::AAAA {
                 ref name,
                 ref addr,
@@ -321,7 +489,7 @@ impl DnsRecord {
             } => {
                 buf.write_name_simple(name)?;
                 buf.write_u16(QueryType::AAAA.to_u16().unwrap())?;
-                buf.write_u16(1)?; // class
+                buf.write_u16(class)?;
                 buf.write_u32(ttl)?;
 
                 buf.write_u16(16)?; // data_len
@@ -329,12 +497,164 @@ impl DnsRecord {
                     buf.write_u16(o)?;
                 }
             }
+            DnsRecord::TSIG {
+                ref name,
+                ref algorithm,
+                time_signed,
+                fudge,
+                ref mac,
+                original_id,
+                error,
+                ref other,
+            } => {
+                buf.write_name_simple(name)?;
+                buf.write_u16(QueryType::TSIG.to_u16().unwrap())?;
+                buf.write_u16(CLASS_ANY)?;
+                buf.write_u32(0)?;
+
+                let data_len_pos = buf.pos;
+                buf.write_u16(0)?; // temp data_len
+                buf.write_name_simple(algorithm)?;
+                buf.write_u16((time_signed >> 32) as u16)?;
+                buf.write_u32(time_signed as u32)?;
+                buf.write_u16(fudge)?;
+                buf.write_u16(mac.len() as u16)?;
+                for &b in mac {
+                    buf.write_u8(b)?;
+                }
+                buf.write_u16(original_id)?;
+                buf.write_u16(error)?;
+                buf.write_u16(other.len() as u16)?;
+                for &b in other {
+                    buf.write_u8(b)?;
+                }
+
+                let data_len = buf.pos - data_len_pos - 2;
+                buf.set_u16(data_len_pos, data_len as u16)?;
+            }
         }
 
         Ok(())
     }
 }
 
+impl DnsRecord {
+    pub fn name(&self) -> &str {
+        match self {
+            DnsRecord::A { name, .. }
+            | DnsRecord::NS { name, .. }
+            | DnsRecord::CNAME { name, .. }
+            | DnsRecord::SOA { name, .. }
+            | DnsRecord::MX { name, .. }
+            | DnsRecord::TXT { name, .. }
+            | DnsRecord::AAAA { name, .. }
+            | DnsRecord::TSIG { name, .. } => name,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => ttl,
+            DnsRecord::TSIG { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::TSIG { .. } => {}
+        }
+    }
+
+    /// The RDATA in presentation format, with absolute names.
+    pub fn rdata_text(&self) -> String {
+        match self {
+            DnsRecord::A { addr, .. } => addr.to_string(),
+            DnsRecord::NS { host, .. } | DnsRecord::CNAME { host, .. } => fqdn(host),
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::MX {
+                preference, host, ..
+            } => format!("{} {}", preference, fqdn(host)),
+            DnsRecord::TXT { data, .. } => data
+                .iter()
+                .map(|s| quote_character_string(s))
+                .collect::<Vec<_>>()
+                .join(" "),
+            DnsRecord::AAAA { addr, .. } => addr.to_string(),
+            DnsRecord::TSIG {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {} {}",
+                fqdn(algorithm),
+                time_signed,
+                fudge,
+                mac.len(),
+                base64::encode(mac),
+                original_id,
+                tsig::error_name(*error),
+                other.len()
+            ),
+        }
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match self {
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::TSIG { .. } => QueryType::TSIG,
+        }
+    }
+
+    pub fn class(&self) -> u16 {
+        match self {
+            DnsRecord::TSIG { .. } => CLASS_ANY,
+            _ => CLASS_IN,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -420,6 +740,13 @@ impl DnsPacket {
 }
 
 impl DnsPacket {
+    pub fn update_header_counts(&mut self) {
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
     pub fn example(domain: &str, query_type: QueryType) -> Self {
         use rand::Rng;
 
@@ -443,6 +770,168 @@ impl DnsPacket {
     }
 }
 
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ";{}\t\tIN\t{}", fqdn(&self.name), self.query_type)
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            fqdn(self.name()),
+            self.ttl(),
+            if self.class() == CLASS_ANY {
+                "ANY"
+            } else {
+                "IN"
+            },
+            self.query_type(),
+            self.rdata_text()
+        )
+    }
+}
+
+/// Formats like the output of `dig`.
+impl fmt::Display for DnsPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let h = &self.header;
+        let opcode = match h.opcode {
+            0 => "QUERY".to_owned(),
+            1 => "IQUERY".to_owned(),
+            2 => "STATUS".to_owned(),
+            4 => "NOTIFY".to_owned(),
+            5 => "UPDATE".to_owned(),
+            n => n.to_string(),
+        };
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            opcode, h.rescode, h.id
+        )?;
+
+        let flags: Vec<_> = [
+            (h.response, "qr"),
+            (h.authoritative_answer, "aa"),
+            (h.truncated_message, "tc"),
+            (h.recursion_desired, "rd"),
+            (h.recursion_available, "ra"),
+            (h.authed_data, "ad"),
+            (h.checking_disabled, "cd"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect();
+        writeln!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len()
+        )?;
+
+        if !self.questions.is_empty() {
+            writeln!(f, "\n;; QUESTION SECTION:")?;
+            for q in &self.questions {
+                writeln!(f, "{}", q)?;
+            }
+        }
+        for (section, records) in [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.resources),
+        ]
+        .iter()
+        {
+            if !records.is_empty() {
+                writeln!(f, "\n;; {} SECTION:", section)?;
+                for r in records.iter() {
+                    writeln!(f, "{}", r)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Serialization follows the JSON shape of RFC 8427.
+
+impl DnsHeader {
+    fn serialize_fields<M: SerializeMap>(&self, map: &mut M) -> std::result::Result<(), M::Error> {
+        map.serialize_entry("ID", &self.id)?;
+        map.serialize_entry("QR", &(self.response as u8))?;
+        map.serialize_entry("Opcode", &self.opcode)?;
+        map.serialize_entry("AA", &(self.authoritative_answer as u8))?;
+        map.serialize_entry("TC", &(self.truncated_message as u8))?;
+        map.serialize_entry("RD", &(self.recursion_desired as u8))?;
+        map.serialize_entry("RA", &(self.recursion_available as u8))?;
+        map.serialize_entry("AD", &(self.authed_data as u8))?;
+        map.serialize_entry("CD", &(self.checking_disabled as u8))?;
+        map.serialize_entry("RCODE", &(self.rescode as u8))?;
+        map.serialize_entry("rcodeName", &format!("{:?}", self.rescode))?;
+        map.serialize_entry("QDCOUNT", &self.questions)?;
+        map.serialize_entry("ANCOUNT", &self.answers)?;
+        map.serialize_entry("NSCOUNT", &self.authoritative_entries)?;
+        map.serialize_entry("ARCOUNT", &self.resource_entries)
+    }
+}
+
+impl Serialize for DnsHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        self.serialize_fields(&mut map)?;
+        map.end()
+    }
+}
+
+impl Serialize for DnsQuestion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(4))?;
+        map.serialize_entry("NAME", &fqdn(&self.name))?;
+        map.serialize_entry("TYPE", &(self.query_type as u16))?;
+        map.serialize_entry("TYPEname", &self.query_type.to_string())?;
+        map.serialize_entry("CLASS", &1)?;
+        map.end()
+    }
+}
+
+impl Serialize for DnsRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let query_type = self.query_type();
+        let mut map = serializer.serialize_map(Some(6))?;
+        map.serialize_entry("NAME", &fqdn(self.name()))?;
+        map.serialize_entry("TYPE", &(query_type as u16))?;
+        map.serialize_entry("TYPEname", &query_type.to_string())?;
+        map.serialize_entry("CLASS", &self.class())?;
+        map.serialize_entry("TTL", &self.ttl())?;
+        map.serialize_entry(&format!("rdata{}", query_type), &self.rdata_text())?;
+        map.end()
+    }
+}
+
+impl Serialize for DnsPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut header = self.header.clone();
+        header.questions = self.questions.len() as u16;
+        header.answers = self.answers.len() as u16;
+        header.authoritative_entries = self.authorities.len() as u16;
+        header.resource_entries = self.resources.len() as u16;
+
+        let mut map = serializer.serialize_map(None)?;
+        header.serialize_fields(&mut map)?;
+        map.serialize_entry("questionRRs", &self.questions)?;
+        map.serialize_entry("answerRRs", &self.answers)?;
+        map.serialize_entry("authorityRRs", &self.authorities)?;
+        map.serialize_entry("additionalRRs", &self.resources)?;
+        map.end()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -594,4 +1083,99 @@ mod test {
             _ => false,
         });
     }
+
+    #[test]
+    fn write_soa_record() {
+        let record = DnsRecord::SOA {
+            name: "bugen.dev".into(),
+            mname: "ns1.bugen.dev".into(),
+            rname: "i.bugenzhao.com".into(),
+            serial: 2020110101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 3600,
+        };
+        let mut buf = DnsPacketBuf::new();
+        record.write(&mut buf).unwrap();
+        let len = buf.pos;
+
+        buf.seek(0);
+        assert_eq!(DnsRecord::read_from(&mut buf).unwrap(), record);
+        assert_eq!(buf.pos, len);
+    }
+
+    #[test]
+    fn display_packet() {
+        let mut packet = DnsPacket::example("bugen.dev", QueryType::MX);
+        packet.header.id = 4242;
+        packet.header.response = true;
+        packet.header.recursion_available = true;
+        packet.answers.push(DnsRecord::MX {
+            name: "bugen.dev".into(),
+            preference: 10,
+            host: "mail.bugen.dev".into(),
+            ttl: 300,
+        });
+        packet.resources.push(DnsRecord::A {
+            name: "mail.bugen.dev".into(),
+            addr: "10.0.0.2".parse().unwrap(),
+            ttl: 300,
+        });
+
+        assert_eq!(
+            packet.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4242
+;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1
+
+;; QUESTION SECTION:
+;bugen.dev.\t\tIN\tMX
+
+;; ANSWER SECTION:
+bugen.dev.\t300\tIN\tMX\t10 mail.bugen.dev.
+
+;; ADDITIONAL SECTION:
+mail.bugen.dev.\t300\tIN\tA\t10.0.0.2
+"
+        );
+    }
+
+    #[test]
+    fn serialize_json() {
+        let mut packet = DnsPacket::example("bugen.dev", QueryType::A);
+        packet.header.id = 4242;
+        packet.header.response = true;
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet.authorities.push(DnsRecord::SOA {
+            name: "bugen.dev".into(),
+            mname: "ns1.bugen.dev".into(),
+            rname: "admin.bugen.dev".into(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 300,
+        });
+
+        let json = serde_json::to_value(&packet).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "ID": 4242, "QR": 1, "Opcode": 0, "AA": 0, "TC": 0, "RD": 1,
+                "RA": 0, "AD": 0, "CD": 0, "RCODE": 3, "rcodeName": "NXDOMAIN",
+                "QDCOUNT": 1, "ANCOUNT": 0, "NSCOUNT": 1, "ARCOUNT": 0,
+                "questionRRs": [
+                    { "NAME": "bugen.dev.", "TYPE": 1, "TYPEname": "A", "CLASS": 1 }
+                ],
+                "answerRRs": [],
+                "authorityRRs": [{
+                    "NAME": "bugen.dev.", "TYPE": 6, "TYPEname": "SOA", "CLASS": 1, "TTL": 300,
+                    "rdataSOA": "ns1.bugen.dev. admin.bugen.dev. 1 7200 3600 1209600 300"
+                }],
+                "additionalRRs": [],
+            })
+        );
+    }
 }
@@ -1,15 +1,25 @@
 use crate::error::{Error, Result};
+use crate::utils::{escape_label, labels, unescape_label};
+
+/// The size of a DNS message over UDP
+pub const UDP_PACKET_SIZE: usize = 512;
+/// The maximum size of a DNS message over TCP, which is length-prefixed
+pub const TCP_PACKET_SIZE: usize = 65535;
 
 #[derive(Clone)]
 pub struct DnsPacketBuf {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
 impl DnsPacketBuf {
     pub fn new() -> Self {
+        Self::with_size(UDP_PACKET_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Self {
         Self {
-            buf: [0u8; 512],
+            buf: vec![0u8; size],
             pos: 0,
         }
     }
@@ -25,7 +35,7 @@ impl DnsPacketBuf {
     }
 
     pub fn peek_u8(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             Err(Error::EndOfBuffer(pos).into())
         } else {
             Ok(self.buf[pos])
@@ -33,7 +43,7 @@ impl DnsPacketBuf {
     }
 
     pub fn peek_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             Err(Error::EndOfBuffer(start + len).into())
         } else {
             Ok(&self.buf[start..start + len])
@@ -75,7 +85,7 @@ impl DnsPacketBuf {
             }
         } else {
             let label_bytes = self.peek_range(self.pos, len as usize)?;
-            let r = String::from_utf8_lossy(label_bytes).into_owned();
+            let r = escape_label(label_bytes);
             self.seek(src_pos + 1 + len as usize);
 
             Ok((r, false))
@@ -109,7 +119,10 @@ impl DnsPacketBuf {
 
 impl DnsPacketBuf {
     pub fn set_u8(&mut self, pos: usize, v: u8) -> Result<()> {
-        self.buf[pos] = v;
+        match self.buf.get_mut(pos) {
+            Some(b) => *b = v,
+            None => return Err(Error::EndOfBuffer(pos)),
+        }
         Ok(())
     }
 
@@ -120,7 +133,7 @@ impl DnsPacketBuf {
     }
 
     pub fn write_u8(&mut self, v: u8) -> Result<()> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(Error::EndOfBuffer(self.pos));
         }
         self.buf[self.pos] = v;
@@ -141,13 +154,13 @@ impl DnsPacketBuf {
     }
 
     pub fn write_name_simple(&mut self, name: &str) -> Result<()> {
-        for label in name.split(".") {
-            let len = label.len();
-            if len > 0b0011_1111 {
+        for label in labels(name).into_iter().filter(|l| !l.is_empty()) {
+            let bytes = unescape_label(label);
+            if bytes.len() > 0b0011_1111 {
                 return Err(Error::LabelLengthExceeded(label.into()));
             }
-            self.write_u8(len as u8)?;
-            for &b in label.as_bytes() {
+            self.write_u8(bytes.len() as u8)?;
+            for b in bytes {
                 self.write_u8(b)?;
             }
         }
@@ -158,11 +171,8 @@ impl DnsPacketBuf {
 
 impl DnsPacketBuf {
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        use std::io::Read;
-
-        let mut cursor = std::io::Cursor::new(bytes);
-        let mut buf = DnsPacketBuf::new();
-        cursor.read(&mut buf.buf).unwrap();
+        let mut buf = DnsPacketBuf::with_size(bytes.len().max(UDP_PACKET_SIZE));
+        buf.buf[..bytes.len()].copy_from_slice(bytes);
         buf
     }
 }
@@ -207,4 +217,13 @@ mod test {
             _ => false,
         })
     }
+
+    #[test]
+    fn write_name_with_escapes() {
+        let mut buf = DnsPacketBuf::new();
+        buf.write_name_simple("a\\.b\\\\c.dev").unwrap();
+        assert_eq!(&buf.buf[..7], b"\x05a.b\\c\x03");
+        buf.seek(0);
+        assert_eq!(buf.read_name().unwrap(), "a\\.b\\\\c.dev");
+    }
 }
@@ -19,8 +19,73 @@ pub enum Error {
     },
     #[error("label `{0}` exceeds the length limitation")]
     LabelLengthExceeded(String),
+    #[error("character string `{0}` exceeds the length limitation")]
+    CharacterStringLengthExceeded(String),
     #[error("too many recursion while looking up `{0}`")]
     TooManyRecursion(String),
+    #[error("resolution of `{0}` failed")]
+    ResolutionFailed(String),
+    #[error("invalid forwarding rule `{0}`")]
+    InvalidForwardRule(String),
+    #[error("zone file error at {file}:{line}:{column}: {message}")]
+    ZoneFile {
+        file: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("invalid zone: {0}")]
+    InvalidZone(String),
+    #[error("invalid update: {0}")]
+    InvalidUpdate(String),
+    #[error("zone transfer failed: {0}")]
+    TransferFailed(String),
+    #[error("invalid secondary zone `{0}`")]
+    InvalidSecondary(String),
+    #[error("invalid block response `{0}`, expected nxdomain, null or refused")]
+    InvalidBlockResponse(String),
+    #[error("invalid network `{0}` in access control list")]
+    InvalidAcl(String),
+    #[error("invalid local data {0}")]
+    InvalidLocalData(String),
+    #[error("invalid view {0}")]
+    InvalidView(String),
+    #[error("invalid configuration {0}")]
+    InvalidConfig(String),
+    #[error("invalid TSIG key: {0}")]
+    InvalidTsigKey(String),
+    #[error("TSIG verification failed: {0}")]
+    TsigFailed(String),
     #[error("network error: {0}")]
     NetworkError(#[from] std::io::Error), // thus io::Error can implicitly `into` NetworkError
 }
+
+impl Error {
+    /// The name of the variant, as a label of metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::EndOfBuffer(_) => "EndOfBuffer",
+            Error::TooManyJumps(_) => "TooManyJumps",
+            Error::InvalidResultCode(_) => "InvalidResultCode",
+            Error::UnknownQuery { .. } => "UnknownQuery",
+            Error::LabelLengthExceeded(_) => "LabelLengthExceeded",
+            Error::CharacterStringLengthExceeded(_) => "CharacterStringLengthExceeded",
+            Error::TooManyRecursion(_) => "TooManyRecursion",
+            Error::ResolutionFailed(_) => "ResolutionFailed",
+            Error::InvalidForwardRule(_) => "InvalidForwardRule",
+            Error::ZoneFile { .. } => "ZoneFile",
+            Error::InvalidZone(_) => "InvalidZone",
+            Error::InvalidUpdate(_) => "InvalidUpdate",
+            Error::TransferFailed(_) => "TransferFailed",
+            Error::InvalidSecondary(_) => "InvalidSecondary",
+            Error::InvalidBlockResponse(_) => "InvalidBlockResponse",
+            Error::InvalidAcl(_) => "InvalidAcl",
+            Error::InvalidLocalData(_) => "InvalidLocalData",
+            Error::InvalidView(_) => "InvalidView",
+            Error::InvalidConfig(_) => "InvalidConfig",
+            Error::InvalidTsigKey(_) => "InvalidTsigKey",
+            Error::TsigFailed(_) => "TsigFailed",
+            Error::NetworkError(_) => "NetworkError",
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::{Error, Result};
+use crate::utils::is_subdomain_of;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ResolveMode {
+    /// Send the query to the upstreams as is, and let them recurse
+    Forward,
+    /// Resolve iteratively, using the upstreams as root servers
+    Recurse,
+}
+
+/// A routing rule for the names under `suffix`, written as
+/// `<suffix>=<forward|recurse>:<addr>[:port][,<addr>[:port]...]`, where the
+/// root suffix is written as `.`.
+#[derive(Clone, Debug)]
+pub struct ForwardRule {
+    pub suffix: String,
+    pub mode: ResolveMode,
+    pub upstreams: Vec<(Ipv4Addr, u16)>,
+}
+
+fn parse_upstream(s: &str) -> Option<(Ipv4Addr, u16)> {
+    let mut parts = s.splitn(2, ':');
+    let addr = parts.next()?.parse().ok()?;
+    let port = match parts.next() {
+        Some(port) => port.parse().ok()?,
+        None => 53,
+    };
+    Some((addr, port))
+}
+
+impl FromStr for ForwardRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidForwardRule(s.to_owned());
+
+        let mut parts = s.splitn(2, '=');
+        let suffix = parts.next().ok_or_else(invalid)?.trim_end_matches('.');
+        let mut parts = parts.next().ok_or_else(invalid)?.splitn(2, ':');
+        let mode = match parts.next().ok_or_else(invalid)? {
+            "forward" => ResolveMode::Forward,
+            "recurse" => ResolveMode::Recurse,
+            _ => return Err(invalid()),
+        };
+        let upstreams = parts
+            .next()
+            .ok_or_else(invalid)?
+            .split(',')
+            .map(parse_upstream)
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            suffix: suffix.to_ascii_lowercase(),
+            mode,
+            upstreams,
+        })
+    }
+}
+
+pub struct ForwardRules {
+    rules: Vec<ForwardRule>,
+}
+
+impl ForwardRules {
+    /// `default` is used for the names not covered by any of `rules`.
+    pub fn new(rules: Vec<ForwardRule>, default: ForwardRule) -> Self {
+        let mut rules = rules;
+        if rules.iter().all(|r| !r.suffix.is_empty()) {
+            rules.push(default);
+        }
+        Self { rules }
+    }
+
+    /// Find the rule with the longest suffix matching `name`.
+    pub fn find(&self, name: &str) -> &ForwardRule {
+        self.rules
+            .iter()
+            .filter(|r| is_subdomain_of(name, &r.suffix))
+            .max_by_key(|r| r.suffix.len())
+            .expect("the root rule always matches")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules() -> ForwardRules {
+        ForwardRules::new(
+            vec![
+                "corp.example=forward:10.0.0.1,10.0.0.2".parse().unwrap(),
+                "dev.corp.example=forward:10.0.1.1".parse().unwrap(),
+                "consul=forward:127.0.0.1:8600".parse().unwrap(),
+            ],
+            "=recurse:198.41.0.4".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn parse_rule() {
+        let rule: ForwardRule = "Consul.=forward:127.0.0.1:8600,127.0.0.2".parse().unwrap();
+        assert_eq!(rule.suffix, "consul");
+        assert_eq!(rule.mode, ResolveMode::Forward);
+        assert_eq!(
+            rule.upstreams,
+            vec![
+                ("127.0.0.1".parse().unwrap(), 8600),
+                ("127.0.0.2".parse().unwrap(), 53)
+            ]
+        );
+
+        let rule: ForwardRule = ".=recurse:198.41.0.4".parse().unwrap();
+        assert_eq!(rule.suffix, "");
+        assert_eq!(rule.mode, ResolveMode::Recurse);
+
+        assert!("consul".parse::<ForwardRule>().is_err());
+        assert!("consul=proxy:127.0.0.1".parse::<ForwardRule>().is_err());
+        assert!("consul=forward:localhost".parse::<ForwardRule>().is_err());
+    }
+
+    #[test]
+    fn longest_suffix_match() {
+        let rules = rules();
+        assert_eq!(rules.find("git.corp.example").upstreams.len(), 2);
+        assert_eq!(rules.find("corp.example").upstreams.len(), 2);
+        assert_eq!(rules.find("ci.dev.corp.example").suffix, "dev.corp.example");
+        assert_eq!(rules.find("web.service.consul").suffix, "consul");
+        assert_eq!(rules.find("notconsul").mode, ResolveMode::Recurse);
+        assert_eq!(rules.find("bugen.dev").mode, ResolveMode::Recurse);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::cache::CacheKey;
+use crate::dns_packet::DnsPacket;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+type Waiters = Mutex<HashMap<CacheKey, broadcast::Sender<Option<DnsPacket>>>>;
+
+/// Removes the pending entry even if the leading resolution is cancelled, so
+/// that the waiters are woken up with an error instead of hanging forever.
+struct PendingGuard<'a> {
+    pending: &'a Waiters,
+    key: &'a CacheKey,
+    done: bool,
+}
+
+impl PendingGuard<'_> {
+    /// Remove the entry once the resolution is done, with the sender to wake
+    /// up the waiters. The entry of a later resolution of the same key is
+    /// then left alone on drop.
+    fn finish(mut self) -> Option<broadcast::Sender<Option<DnsPacket>>> {
+        self.done = true;
+        self.pending.lock().unwrap().remove(self.key)
+    }
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.pending.lock().unwrap().remove(self.key);
+        }
+    }
+}
+
+/// Deduplicates concurrent resolutions of the same (name, type, class): the
+/// first caller resolves upstream, and the later ones wait for its result.
+pub struct InFlight {
+    pending: Waiters,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn resolve<F, Fut>(&self, key: CacheKey, resolve: F) -> Result<DnsPacket>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<DnsPacket>>,
+    {
+        let receiver = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    let (sender, _) = broadcast::channel(1);
+                    pending.insert(key.clone(), sender);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut receiver) = receiver {
+            return match receiver.recv().await {
+                Ok(Some(packet)) => Ok(packet),
+                _ => Err(Error::ResolutionFailed(key.name)),
+            };
+        }
+
+        let guard = PendingGuard {
+            pending: &self.pending,
+            key: &key,
+            done: false,
+        };
+        let result = resolve().await;
+
+        if let Some(sender) = guard.finish() {
+            // no receivers is fine
+            let _ = sender.send(result.as_ref().ok().cloned());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns_packet::QueryType;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn coalesce_concurrent_queries() {
+        let inflight = Arc::new(InFlight::new());
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let inflight = inflight.clone();
+                let count = count.clone();
+                tokio::spawn(async move {
+                    let key = CacheKey::new("bugen.dev", QueryType::A);
+                    inflight
+                        .resolve(key, || async {
+                            count.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            Ok(DnsPacket::example("bugen.dev", QueryType::A))
+                        })
+                        .await
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap().questions[0].name, "bugen.dev");
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(inflight.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failure_shared_with_waiters() {
+        let inflight = Arc::new(InFlight::new());
+        let key = CacheKey::new("bugen.dev", QueryType::A);
+
+        let leader = {
+            let inflight = inflight.clone();
+            let key = key.clone();
+            tokio::spawn(async move {
+                inflight
+                    .resolve(key, || async {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        Err(Error::TooManyRecursion("bugen.dev".into()))
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let waiter = inflight.resolve(key, || async { unreachable!() }).await;
+
+        assert!(leader.await.unwrap().is_err());
+        assert!(matches!(waiter, Err(Error::ResolutionFailed(_))));
+    }
+
+    #[test]
+    fn guard_leaves_next_entry() {
+        let inflight = InFlight::new();
+        let key = CacheKey::new("bugen.dev", QueryType::A);
+        let guard = || {
+            let (sender, _) = broadcast::channel(1);
+            inflight.pending.lock().unwrap().insert(key.clone(), sender);
+            PendingGuard {
+                pending: &inflight.pending,
+                key: &key,
+                done: false,
+            }
+        };
+
+        let first = guard();
+        assert!(first.finish().is_some());
+        // a new leader of the same key
+        let next = guard();
+        assert!(inflight.pending.lock().unwrap().contains_key(&key));
+        // cancelled
+        drop(next);
+        assert!(inflight.pending.lock().unwrap().is_empty());
+    }
+}
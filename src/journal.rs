@@ -0,0 +1,313 @@
+use crate::dns_packet::{DnsRecord, QueryType};
+use crate::error::{Error, Result};
+use crate::utils::replace_file;
+use crate::zone_file::parse_zone_file;
+use std::path::Path;
+
+/// The most diffs a journal keeps once trimmed. Clients further behind get a
+/// full transfer instead.
+pub const MAX_DIFFS: usize = 100;
+
+/// Whether serial `a` is before `b` in the arithmetic of RFC 1982.
+pub fn serial_lt(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000_0000
+}
+
+pub fn soa_serial(soa: &DnsRecord) -> Result<u32> {
+    match soa {
+        DnsRecord::SOA { serial, .. } => Ok(*serial),
+        _ => Err(Error::InvalidZone(format!(
+            "`{}` is not an SOA record",
+            soa
+        ))),
+    }
+}
+
+/// The changes between two versions of a zone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diff {
+    old_soa: DnsRecord,
+    old_serial: u32,
+    pub removed: Vec<DnsRecord>,
+    new_soa: DnsRecord,
+    new_serial: u32,
+    pub added: Vec<DnsRecord>,
+}
+
+impl Diff {
+    /// A diff between the versions of two SOA records, with no other changes
+    /// yet.
+    pub fn new(old_soa: DnsRecord, new_soa: DnsRecord) -> Result<Self> {
+        Ok(Self {
+            old_serial: soa_serial(&old_soa)?,
+            old_soa,
+            removed: vec![],
+            new_serial: soa_serial(&new_soa)?,
+            new_soa,
+            added: vec![],
+        })
+    }
+
+    pub fn old_soa(&self) -> &DnsRecord {
+        &self.old_soa
+    }
+
+    pub fn new_soa(&self) -> &DnsRecord {
+        &self.new_soa
+    }
+
+    pub fn old_serial(&self) -> u32 {
+        self.old_serial
+    }
+
+    pub fn new_serial(&self) -> u32 {
+        self.new_serial
+    }
+
+    fn set_new_soa(&mut self, soa: DnsRecord) -> Result<()> {
+        self.new_serial = soa_serial(&soa)?;
+        self.new_soa = soa;
+        Ok(())
+    }
+
+    /// The records in the order of an IXFR response: the old SOA, the removed
+    /// records, the new SOA and the added records.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        std::iter::once(&self.old_soa)
+            .chain(self.removed.iter())
+            .chain(std::iter::once(&self.new_soa))
+            .chain(self.added.iter())
+    }
+}
+
+/// The history of serial changes of a zone, for answering IXFR.
+#[derive(Clone, Debug, Default)]
+pub struct Journal {
+    diffs: Vec<Diff>,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Split records in the order of `Diff::records` into diffs.
+    pub fn from_records(records: impl IntoIterator<Item = DnsRecord>) -> Result<Self> {
+        let mut journal = Self::new();
+        let mut current: Option<Diff> = None;
+        for record in records {
+            match (record.query_type(), current.as_mut()) {
+                (QueryType::SOA, None) => current = Some(Diff::new(record.clone(), record)?),
+                // the new SOA, if it is still the same as the old one
+                (QueryType::SOA, Some(diff)) if diff.old_soa == diff.new_soa => {
+                    diff.set_new_soa(record)?
+                }
+                (QueryType::SOA, Some(_)) => {
+                    journal.append(current.take().unwrap())?;
+                    current = Some(Diff::new(record.clone(), record)?);
+                }
+                (_, Some(diff)) if diff.old_soa == diff.new_soa => diff.removed.push(record),
+                (_, Some(diff)) => diff.added.push(record),
+                (_, None) => {
+                    return Err(Error::InvalidZone(format!(
+                        "journal does not start with an SOA record: `{}`",
+                        record
+                    )))
+                }
+            }
+        }
+        match current {
+            Some(diff) if diff.old_soa == diff.new_soa => Err(Error::InvalidZone(format!(
+                "incomplete journal entry from serial {}",
+                diff.old_serial()
+            ))),
+            Some(diff) => journal.append(diff),
+            None => Ok(()),
+        }?;
+        Ok(journal)
+    }
+
+    /// Load the journal kept in a master file at `path`, which may not exist.
+    pub fn load(path: &Path, origin: &str) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        Self::from_records(parse_zone_file(path, origin)?)
+    }
+
+    /// Save the journal to a master file at `path`, replacing it at once so
+    /// that a crash never leaves a partial one.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut text = String::new();
+        for diff in self.diffs.iter() {
+            text.push_str(&format!(
+                "; serial {} to {}\n",
+                diff.old_serial(),
+                diff.new_serial()
+            ));
+            for record in diff.records() {
+                text.push_str(&record.to_string());
+                text.push('\n');
+            }
+        }
+        Ok(replace_file(path, &text)?)
+    }
+
+    /// Add a diff, which must continue from the last one.
+    pub fn append(&mut self, diff: Diff) -> Result<()> {
+        if let Some(last) = self.diffs.last() {
+            if last.new_serial() != diff.old_serial() {
+                return Err(Error::InvalidZone(format!(
+                    "journal entry from serial {} does not follow serial {}",
+                    diff.old_serial(),
+                    last.new_serial()
+                )));
+            }
+        }
+        self.diffs.push(diff);
+        Ok(())
+    }
+
+    /// Forget the oldest diffs beyond `MAX_DIFFS`, returning whether there
+    /// were any. The journal no longer reaches back to their serials, so the
+    /// master file must have been written at a later one before.
+    pub fn trim(&mut self) -> bool {
+        let excess = self.diffs.len().saturating_sub(MAX_DIFFS);
+        self.diffs.drain(..excess);
+        excess > 0
+    }
+
+    pub fn diffs(&self) -> &[Diff] {
+        &self.diffs
+    }
+
+    pub fn last_serial(&self) -> Option<u32> {
+        self.diffs.last().map(Diff::new_serial)
+    }
+
+    /// Split the journal into the history up to `serial`, and the diffs after
+    /// it, if the journal passes through that serial.
+    pub fn split_at(mut self, serial: u32) -> Option<(Journal, Vec<Diff>)> {
+        let index = match self.diffs.iter().position(|d| d.old_serial() == serial) {
+            Some(index) => index,
+            None if self.last_serial().unwrap_or(serial) == serial => self.diffs.len(),
+            None => return None,
+        };
+        let pending = self.diffs.split_off(index);
+        Some((self, pending))
+    }
+
+    /// The diffs that bring a zone from `serial` up to date, if the journal
+    /// reaches back that far.
+    pub fn since(&self, serial: u32) -> Option<&[Diff]> {
+        self.diffs
+            .iter()
+            .position(|d| d.old_serial() == serial)
+            .map(|i| &self.diffs[i..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zone_file::parse_zone;
+
+    const JOURNAL: &str = "
+$TTL 300
+@   SOA ns admin 1 2 3 4 5
+www A   10.0.0.1
+@   SOA ns admin 2 2 3 4 5
+www A   10.0.0.2
+@   SOA ns admin 2 2 3 4 5
+@   SOA ns admin 3 2 3 4 5
+new A   10.0.0.3
+";
+
+    #[test]
+    fn split_diffs() {
+        let journal = Journal::from_records(parse_zone(JOURNAL, "bugen.dev").unwrap()).unwrap();
+        assert_eq!(journal.last_serial(), Some(3));
+
+        let diffs = journal.since(1).unwrap();
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[0].removed.len(), 1);
+        assert_eq!(diffs[0].added.len(), 1);
+        assert!(diffs[1].removed.is_empty());
+        assert_eq!(diffs[1].added[0].name(), "new.bugen.dev");
+
+        assert_eq!(journal.since(2).unwrap().len(), 1);
+        assert!(journal.since(3).is_none());
+        assert!(journal.since(0).is_none());
+
+        let (history, pending) = journal.clone().split_at(2).unwrap();
+        assert_eq!(history.last_serial(), Some(2));
+        assert_eq!(pending.len(), 1);
+        let (history, pending) = journal.clone().split_at(3).unwrap();
+        assert_eq!(history.last_serial(), Some(3));
+        assert!(pending.is_empty());
+        assert!(journal.split_at(4).is_none());
+    }
+
+    #[test]
+    fn broken_journals() {
+        let records = parse_zone(JOURNAL, "bugen.dev").unwrap();
+        // not starting with an SOA
+        assert!(Journal::from_records(records[1..].to_vec()).is_err());
+        // without the new SOA of the last entry
+        assert!(Journal::from_records(records[..5].to_vec()).is_err());
+        // with a gap between serials
+        let mut journal = Journal::from_records(records[..4].to_vec()).unwrap();
+        let diff = journal.since(1).unwrap()[0].clone();
+        assert!(journal.append(diff).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let journal = Journal::from_records(parse_zone(JOURNAL, "bugen.dev").unwrap()).unwrap();
+        let path = std::env::temp_dir().join(format!("dnser-journal-{}", std::process::id()));
+        std::fs::write(&path, "stale").unwrap();
+        journal.save(&path).unwrap();
+        let loaded = Journal::load(&path, "bugen.dev").unwrap();
+        assert_eq!(loaded.diffs(), journal.diffs());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn forget_oldest_diffs() {
+        let soa = |serial| {
+            let text = format!("@ 300 SOA ns admin {} 2 3 4 5", serial);
+            parse_zone(&text, "bugen.dev").unwrap().remove(0)
+        };
+        let mut journal = Journal::new();
+        for serial in 0..MAX_DIFFS as u32 + 10 {
+            journal
+                .append(Diff::new(soa(serial), soa(serial + 1)).unwrap())
+                .unwrap();
+        }
+        assert_eq!(journal.diffs().len(), MAX_DIFFS + 10);
+        assert!(journal.since(0).is_some());
+
+        assert!(journal.trim());
+        assert!(!journal.trim());
+        assert_eq!(journal.diffs().len(), MAX_DIFFS);
+        assert!(journal.since(9).is_none());
+        assert_eq!(journal.since(10).unwrap().len(), MAX_DIFFS);
+        assert_eq!(journal.last_serial(), Some(MAX_DIFFS as u32 + 10));
+    }
+
+    #[test]
+    fn serial_of_non_soa() {
+        let records = parse_zone(JOURNAL, "bugen.dev").unwrap();
+        assert_eq!(soa_serial(&records[0]).unwrap(), 1);
+        assert!(soa_serial(&records[1]).is_err());
+        assert!(Diff::new(records[0].clone(), records[1].clone()).is_err());
+    }
+
+    #[test]
+    fn serial_arithmetic() {
+        assert!(serial_lt(1, 2));
+        assert!(!serial_lt(2, 2));
+        assert!(!serial_lt(2, 1));
+        assert!(serial_lt(u32::MAX, 0));
+    }
+}
@@ -0,0 +1,263 @@
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use crate::error::{Error, Result};
+use crate::utils::is_subdomain_of;
+use crate::zone::synthesize;
+use crate::zone_file::parse_zone;
+use std::collections::{HashMap, HashSet};
+
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// TTL of local records given without one, and of negative answers.
+const LOCAL_TTL: u32 = 3600;
+
+/// Parse a single record in master file format, like `db.internal A 10.0.0.5`,
+/// where names are absolute with or without the trailing dot.
+pub fn parse_record(s: &str) -> Result<DnsRecord> {
+    let invalid = |message: String| Error::InvalidLocalData(format!("`{}`: {}", s, message));
+    let mut records = match parse_zone(&format!("$TTL {}\n{}", LOCAL_TTL, s), "") {
+        Ok(records) => records,
+        // the position is of no use in a single line
+        Err(Error::ZoneFile { message, .. }) => return Err(invalid(message)),
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+    match records.len() {
+        1 => Ok(records.pop().unwrap()),
+        _ => Err(invalid("expected exactly one record".into())),
+    }
+}
+
+/// Records pinned by the configuration, answered authoritatively for their
+/// names, and for all the names below `domain` if owned by `*.domain`. Names
+/// in the static subtrees without any record are answered with NXDOMAIN
+/// rather than looked up elsewhere.
+#[derive(Debug, Default)]
+pub struct LocalData {
+    // lowercased owner -> records
+    records: HashMap<String, Vec<DnsRecord>>,
+    // lowercased domain of `*.domain` -> records
+    wildcards: HashMap<String, Vec<DnsRecord>>,
+    // all owners and the names above them, for empty non-terminals
+    names: HashSet<String>,
+    subtrees: Vec<String>,
+}
+
+impl LocalData {
+    pub fn new(records: Vec<DnsRecord>, subtrees: Vec<String>) -> Self {
+        let mut data = Self {
+            subtrees: subtrees
+                .iter()
+                .map(|s| s.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+            ..Self::default()
+        };
+        for record in records {
+            let owner = record.name().to_ascii_lowercase();
+            let mut name = owner.as_str();
+            while let Some(index) = name.find('.') {
+                name = &name[index + 1..];
+                data.names.insert(name.to_owned());
+            }
+            let map = match owner.strip_prefix("*.") {
+                Some(domain) => data.wildcards.entry(domain.to_owned()),
+                None => {
+                    data.names.insert(owner.clone());
+                    data.records.entry(owner.clone())
+                }
+            };
+            map.or_default().push(record);
+        }
+        data
+    }
+
+    /// The records of `name` itself, or else synthesized from the wildcard of
+    /// the closest enclosing domain.
+    fn find(&self, name: &str) -> Option<Vec<DnsRecord>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records.clone());
+        }
+        let mut rest = name;
+        while let Some(index) = rest.find('.') {
+            rest = &rest[index + 1..];
+            if let Some(records) = self.wildcards.get(rest) {
+                return Some(
+                    records
+                        .iter()
+                        .cloned()
+                        .map(|r| synthesize(r, name))
+                        .collect(),
+                );
+            }
+        }
+        None
+    }
+
+    /// The SOA record for negative answers in the static subtree of `name`.
+    fn subtree_soa(&self, name: &str) -> Option<DnsRecord> {
+        let subtree = self
+            .subtrees
+            .iter()
+            .filter(|subtree| is_subdomain_of(name, subtree))
+            .max_by_key(|subtree| subtree.len())?;
+        Some(DnsRecord::SOA {
+            name: subtree.clone(),
+            mname: "localhost".into(),
+            rname: "nobody.invalid".into(),
+            serial: 1,
+            refresh: LOCAL_TTL,
+            retry: LOCAL_TTL,
+            expire: LOCAL_TTL,
+            minimum: LOCAL_TTL,
+            ttl: LOCAL_TTL,
+        })
+    }
+
+    /// Answer `question` from the local data, following CNAMEs within it.
+    /// `None` if the name is neither pinned nor in a static subtree.
+    pub fn lookup(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let mut packet = DnsPacket::default();
+        packet.questions.push(question.clone());
+        packet.header.authoritative_answer = true;
+
+        let query_type = question.query_type;
+        let mut name = question.name.trim_end_matches('.').to_ascii_lowercase();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let records = match self.find(&name) {
+                Some(records) => records,
+                None => {
+                    match self.subtree_soa(&name) {
+                        Some(soa) => {
+                            if !self.names.contains(&name) {
+                                packet.header.rescode = ResultCode::NXDOMAIN;
+                            }
+                            packet.authorities.push(soa);
+                        }
+                        None if packet.answers.is_empty() => return None,
+                        // CNAME out of the local data, left to the caller
+                        None => {}
+                    }
+                    break;
+                }
+            };
+
+            let cname = records
+                .iter()
+                .find(|r| r.query_type() == QueryType::CNAME)
+                .cloned();
+            if let Some(DnsRecord::CNAME { ref host, .. }) = cname {
+                if query_type != QueryType::CNAME {
+                    name = host.to_ascii_lowercase();
+                    packet.answers.push(cname.unwrap());
+                    continue;
+                }
+            }
+
+            let answers: Vec<_> = records
+                .into_iter()
+                .filter(|r| r.query_type() == query_type)
+                .collect();
+            if answers.is_empty() {
+                packet.authorities.extend(self.subtree_soa(&name));
+            }
+            packet.answers.extend(answers);
+            break;
+        }
+
+        packet.update_header_counts();
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn question(name: &str, query_type: QueryType) -> DnsQuestion {
+        DnsQuestion {
+            name: name.into(),
+            query_type,
+        }
+    }
+
+    fn local_data() -> LocalData {
+        let records = [
+            "db.internal A 10.0.0.5",
+            "db.internal. 60 AAAA fd00::5",
+            "www.internal CNAME db.internal",
+            "search.internal CNAME example.com.",
+            "*.apps.internal A 10.0.0.9",
+            "x.dev.internal A 10.0.1.1",
+        ]
+        .iter()
+        .map(|s| parse_record(s).unwrap())
+        .collect();
+        LocalData::new(records, vec!["dev.internal.".into()])
+    }
+
+    #[test]
+    fn parse_records() {
+        let record = parse_record("db.internal A 10.0.0.5").unwrap();
+        assert_eq!(record.to_string(), "db.internal.\t3600\tIN\tA\t10.0.0.5");
+        let record = parse_record("host.example. 60 TXT \"pinned\"").unwrap();
+        assert_eq!(record.name(), "host.example");
+        assert!(parse_record("db.internal A").is_err());
+        assert!(parse_record("db.internal A 10.0.0.5\nx A 10.0.0.6").is_err());
+        assert!(parse_record("").is_err());
+    }
+
+    #[test]
+    fn lookup_local_data() {
+        let data = local_data();
+        let packet = data.lookup(&question("DB.internal", QueryType::A)).unwrap();
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.answers[0].rdata_text(), "10.0.0.5");
+        let packet = data
+            .lookup(&question("db.internal", QueryType::MX))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty() && packet.authorities.is_empty());
+
+        let packet = data
+            .lookup(&question("www.internal", QueryType::AAAA))
+            .unwrap();
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.answers[1].rdata_text(), "fd00::5");
+        let packet = data
+            .lookup(&question("search.internal", QueryType::A))
+            .unwrap();
+        assert_eq!(packet.answers.len(), 1);
+
+        let packet = data
+            .lookup(&question("a.b.apps.internal", QueryType::A))
+            .unwrap();
+        assert_eq!(packet.answers[0].name(), "a.b.apps.internal");
+        assert!(data
+            .lookup(&question("apps.internal", QueryType::A))
+            .is_none());
+        assert!(data
+            .lookup(&question("other.internal", QueryType::A))
+            .is_none());
+    }
+
+    #[test]
+    fn lookup_static_subtree() {
+        let data = local_data();
+        let packet = data
+            .lookup(&question("y.dev.internal", QueryType::A))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(packet.authorities[0].name(), "dev.internal");
+        let packet = data
+            .lookup(&question("dev.internal", QueryType::A))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        let packet = data
+            .lookup(&question("x.dev.internal", QueryType::A))
+            .unwrap();
+        assert_eq!(packet.answers[0].rdata_text(), "10.0.1.1");
+        let packet = data
+            .lookup(&question("x.dev.internal", QueryType::TXT))
+            .unwrap();
+        assert_eq!(packet.authorities.len(), 1);
+    }
+}
@@ -8,36 +8,87 @@ extern crate lazy_static;
 extern crate num_derive;
 extern crate tokio;
 
+mod acl;
+mod blocklist;
+mod cache;
 mod client;
+mod config;
 mod dns_packet;
 mod dns_packet_buf;
 mod error;
+mod forward;
+mod inflight;
+mod journal;
+mod local;
+mod metrics;
 mod recursive;
+mod rpz;
+mod rrl;
+mod secondary;
 mod server;
+mod transfer;
+mod tsig;
+mod udp;
+mod update;
 mod utils;
+mod view;
+mod zone;
+mod zone_file;
 
 // use dns_packet::QueryType;
 use dns_packet::QueryType;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "dnser", about = "A DNS utility by Bugen.")]
+#[allow(clippy::large_enum_variant)]
 enum Dnser {
     Lookup {
         #[structopt(short, long, default_value = "198.41.0.4")]
         server: String,
         #[structopt(short, long, possible_values = &QueryType::variants(), case_insensitive = true, default_value = "A")]
         r#type: QueryType,
+        /// Print only the RDATA of the answers
+        #[structopt(long)]
+        short: bool,
+        /// Print the full response as JSON, in the shape of RFC 8427
+        #[structopt(long, conflicts_with = "short")]
+        json: bool,
         #[structopt()]
         domain: String,
     },
-    Server {
-        #[structopt(short, long, default_value = "198.41.0.4")]
-        server: String,
+    Server(config::ServerConfig),
+    /// Check a configuration file of the server, loading its zones, keys and
+    /// lists without serving
+    CheckConfig {
+        #[structopt()]
+        path: PathBuf,
+    },
+    /// Send a dynamic update of RFC 2136 to a primary server
+    Update {
+        #[structopt(short, long, default_value = "127.0.0.1:55553")]
+        server: SocketAddr,
+        /// Origin of the zone to update
+        #[structopt(short, long)]
+        zone: String,
+        /// Record to add, in master file format like `www 300 A 10.0.0.1`
+        #[structopt(long)]
+        add: Vec<String>,
+        /// Records to delete, as `<name> [TYPE [rdata]]`
         #[structopt(long)]
-        proxy: bool,
-        #[structopt(short, long, default_value = "55553")]
-        port: u16,
+        delete: Vec<String>,
+        /// Require a name or an RRset to exist, as `<name> [TYPE]`
+        #[structopt(long)]
+        require_exists: Vec<String>,
+        /// Require a name or an RRset not to exist, as `<name> [TYPE]`
+        #[structopt(long)]
+        require_absent: Vec<String>,
+        /// File of TSIG keys in the format of BIND, whose first key signs the
+        /// update
+        #[structopt(long)]
+        tsig_key: Option<PathBuf>,
     },
 }
 
@@ -51,22 +102,99 @@ async fn main() {
         Dnser::Lookup {
             server,
             r#type,
+            short,
+            json,
             domain,
         } => {
-            let answer =
-                client::recursive_lookup(&domain, r#type, (server.parse().unwrap(), 53), 0)
-                    .await
-                    .unwrap();
-            println!("{:?}", answer.answers);
+            let answer = client::recursive_lookup(
+                &domain,
+                r#type,
+                (server.parse().unwrap(), 53),
+                None,
+                None,
+                0,
+            )
+            .await
+            .unwrap();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&answer).unwrap());
+            } else if short {
+                for record in &answer.answers {
+                    println!("{}", record.rdata_text());
+                }
+            } else {
+                print!("{}", answer);
+            }
         }
-        Dnser::Server {
+        Dnser::Server(config) => {
+            // the file is read again on reload, under the same flags
+            let load = move || config.clone().resolve().and_then(|c| c.into_options());
+            let options = match load() {
+                Ok(options) => options,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            server::run(options, load).await.unwrap();
+        }
+        Dnser::CheckConfig { path } => {
+            let result = config::ServerConfig::load(&path)
+                .and_then(|c| c.into_options())
+                .and_then(server::check);
+            match result {
+                Ok(()) => println!("Configuration `{}` is valid", path.display()),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Dnser::Update {
             server,
-            port,
-            proxy,
+            zone,
+            add,
+            delete,
+            require_exists,
+            require_absent,
+            tsig_key,
         } => {
-            server::run((server.parse().unwrap(), 53), port, proxy)
+            let zone = zone.trim_end_matches('.');
+            let mut message = update::UpdateMessage::new(zone);
+            for text in require_exists.iter() {
+                let (name, query_type) = update::parse_rrset(text, zone).unwrap();
+                message
+                    .prerequisites
+                    .push(update::UpdateRecord::exists(&name, query_type));
+            }
+            for text in require_absent.iter() {
+                let (name, query_type) = update::parse_rrset(text, zone).unwrap();
+                message
+                    .prerequisites
+                    .push(update::UpdateRecord::absent(&name, query_type));
+            }
+            for text in delete.iter() {
+                message
+                    .updates
+                    .push(update::parse_delete(text, zone).unwrap());
+            }
+            for text in add.iter() {
+                for record in zone_file::parse_zone(text, zone).unwrap() {
+                    message.updates.push(update::UpdateRecord::add(record));
+                }
+            }
+
+            let key = tsig_key.map(|path| {
+                tsig::load_keys(&path)
+                    .unwrap()
+                    .into_iter()
+                    .next()
+                    .expect("no key in the TSIG key file")
+            });
+            let response = client::update(server, &message, key.as_ref())
                 .await
                 .unwrap();
+            print!("{}", response);
         }
     }
 }
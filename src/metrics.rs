@@ -0,0 +1,481 @@
+use crate::dns_packet::{QueryType, ResultCode, OPCODE_NOTIFY, OPCODE_QUERY, OPCODE_UPDATE};
+use crate::error::Error;
+use log::*;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Upper bounds of the buckets of upstream RTTs, in seconds.
+const RTT_BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Longest request accepted by the HTTP endpoint, headers included.
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    fn name(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    // not cumulative, summed up when rendered
+    buckets: [u64; RTT_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = RTT_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[index] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// The opcode, the type, the response code and the transport of queries, with
+/// no response code for the ones dropped. Updates have the type of their zone
+/// section, SOA.
+type QueryKey = (u8, QueryType, Option<ResultCode>, Transport);
+
+/// The upstream server and the result of queries to it: `ok`, `error` or
+/// `timeout`.
+type RttKey = ((Ipv4Addr, u16), &'static str);
+
+/// Counters of the server, kept across reloads and rendered in the text format
+/// of Prometheus.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    queries: Mutex<HashMap<QueryKey, u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    upstream_rtts: Mutex<HashMap<RttKey, Histogram>>,
+    in_flight: AtomicI64,
+    errors: Mutex<HashMap<&'static str, u64>>,
+    rate_limited_dropped: AtomicU64,
+    rate_limited_slipped: AtomicU64,
+}
+
+/// A query counted as in flight until dropped.
+pub struct InFlightGuard<'a>(&'a Metrics);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn query(
+        &self,
+        opcode: u8,
+        query_type: QueryType,
+        rescode: Option<ResultCode>,
+        transport: Transport,
+    ) {
+        *self
+            .queries
+            .lock()
+            .unwrap()
+            .entry((opcode, query_type, rescode, transport))
+            .or_default() += 1;
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The time a query to an upstream server took, until the response or
+    /// the `error` it failed with.
+    pub fn upstream_rtt(&self, server: (Ipv4Addr, u16), rtt: Duration, error: Option<&Error>) {
+        let result = match error {
+            None => "ok",
+            Some(Error::NetworkError(e)) if e.kind() == io::ErrorKind::TimedOut => "timeout",
+            Some(_) => "error",
+        };
+        self.upstream_rtts
+            .lock()
+            .unwrap()
+            .entry((server, result))
+            .or_default()
+            .observe(rtt.as_secs_f64());
+    }
+
+    pub fn start_query(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self)
+    }
+
+    pub fn error(&self, error: &Error) {
+        *self.errors.lock().unwrap().entry(error.name()).or_default() += 1;
+    }
+
+    /// A response limited by RRL, which is sent truncated if `slipped`.
+    pub fn rate_limited(&self, slipped: bool) {
+        let counter = if slipped {
+            &self.rate_limited_slipped
+        } else {
+            &self.rate_limited_dropped
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// All the metrics, followed by the entries in the cache of each view.
+    pub fn render(&self, cache_entries: &[(&str, usize)]) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "dnser_queries_total",
+            "counter",
+            "Queries answered, by opcode, type, response code and transport",
+        );
+        let mut queries: Vec<_> = self.queries.lock().unwrap().clone().into_iter().collect();
+        queries.sort_by_key(|((opcode, query_type, rescode, transport), _)| {
+            (
+                *opcode,
+                format!("{:?}", query_type),
+                format!("{:?}", rescode),
+                transport.name(),
+            )
+        });
+        for ((opcode, query_type, rescode, transport), count) in queries {
+            let rescode = match rescode {
+                Some(rescode) => format!("{:?}", rescode),
+                None => "DROPPED".into(),
+            };
+            let _ = writeln!(
+                out,
+                "dnser_queries_total{{opcode=\"{}\",type=\"{:?}\",rcode=\"{}\",transport=\"{}\"}} {}",
+                opcode_name(opcode),
+                query_type,
+                rescode,
+                transport.name(),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "dnser_cache_lookups_total",
+            "counter",
+            "Lookups of the cache before resolving, by result",
+        );
+        for (result, counter) in [("hit", &self.cache_hits), ("miss", &self.cache_misses)] {
+            let _ = writeln!(
+                out,
+                "dnser_cache_lookups_total{{result=\"{}\"}} {}",
+                result,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        header(
+            &mut out,
+            "dnser_cache_entries",
+            "gauge",
+            "RRsets in the cache, by view",
+        );
+        for (view, entries) in cache_entries {
+            let _ = writeln!(
+                out,
+                "dnser_cache_entries{{view=\"{}\"}} {}",
+                escape(view),
+                entries
+            );
+        }
+
+        header(
+            &mut out,
+            "dnser_upstream_rtt_seconds",
+            "histogram",
+            "Round-trip times of the queries to upstream servers, by result",
+        );
+        let mut rtts: Vec<_> = self
+            .upstream_rtts
+            .lock()
+            .unwrap()
+            .clone()
+            .into_iter()
+            .collect();
+        rtts.sort_by_key(|&(key, _)| key);
+        for (((addr, port), result), histogram) in rtts {
+            let labels = format!("server=\"{}:{}\",result=\"{}\"", addr, port, result);
+            let mut cumulative = 0;
+            for (bound, count) in RTT_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "dnser_upstream_rtt_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "dnser_upstream_rtt_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "dnser_upstream_rtt_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "dnser_upstream_rtt_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+
+        header(
+            &mut out,
+            "dnser_queries_in_flight",
+            "gauge",
+            "Queries being answered",
+        );
+        let _ = writeln!(
+            out,
+            "dnser_queries_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "dnser_errors_total",
+            "counter",
+            "Errors serving queries, by kind",
+        );
+        let mut errors: Vec<_> = self.errors.lock().unwrap().clone().into_iter().collect();
+        errors.sort();
+        for (kind, count) in errors {
+            let _ = writeln!(out, "dnser_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        header(
+            &mut out,
+            "dnser_rate_limited_responses_total",
+            "counter",
+            "Responses limited by RRL, by action",
+        );
+        for (action, counter) in [
+            ("drop", &self.rate_limited_dropped),
+            ("slip", &self.rate_limited_slipped),
+        ] {
+            let _ = writeln!(
+                out,
+                "dnser_rate_limited_responses_total{{action=\"{}\"}} {}",
+                action,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        OPCODE_QUERY => "QUERY".into(),
+        OPCODE_NOTIFY => "NOTIFY".into(),
+        OPCODE_UPDATE => "UPDATE".into(),
+        _ => opcode.to_string(),
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}.", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value as in the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer a single HTTP/1.x request with the output of `render` for
+/// `GET /metrics`, closing the connection afterwards.
+async fn handle_request(mut stream: TcpStream, render: impl Fn() -> String) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = match timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(len) => len?,
+            Err(_) => return Ok(()),
+        };
+        if len == 0 || request.len() + len > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".into()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".into()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Serve the metrics rendered by `render` over HTTP at `/metrics`.
+pub async fn serve<F>(listener: TcpListener, render: F)
+where
+    F: Fn() -> String + Clone + Send + 'static,
+{
+    loop {
+        let (stream, from_addr): (TcpStream, SocketAddr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("error {}", e);
+                continue;
+            }
+        };
+        let render = render.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, render).await {
+                debug!("error serving metrics to {}: {}", from_addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::default();
+        metrics.query(
+            OPCODE_QUERY,
+            QueryType::A,
+            Some(ResultCode::NOERROR),
+            Transport::Udp,
+        );
+        metrics.query(
+            OPCODE_QUERY,
+            QueryType::A,
+            Some(ResultCode::NOERROR),
+            Transport::Udp,
+        );
+        metrics.query(OPCODE_QUERY, QueryType::MX, None, Transport::Tcp);
+        metrics.query(
+            OPCODE_UPDATE,
+            QueryType::SOA,
+            Some(ResultCode::REFUSED),
+            Transport::Udp,
+        );
+        metrics.cache_lookup(true);
+        metrics.cache_lookup(false);
+        metrics.cache_lookup(true);
+        let server = (Ipv4Addr::new(198, 41, 0, 4), 53);
+        metrics.upstream_rtt(server, Duration::from_millis(20), None);
+        metrics.upstream_rtt(server, Duration::from_millis(300), None);
+        metrics.upstream_rtt(server, Duration::from_secs(5), None);
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "no response").into();
+        metrics.upstream_rtt(server, Duration::from_secs(5), Some(&timed_out));
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        metrics.upstream_rtt(server, Duration::from_millis(1), Some(&refused));
+        metrics.error(&Error::ResolutionFailed("bugen.dev".into()));
+        metrics.rate_limited(true);
+        let guard = metrics.start_query();
+
+        let text = metrics.render(&[("_default", 12), ("lab \"1\"", 0)]);
+        assert!(text.contains(
+            "dnser_queries_total{opcode=\"QUERY\",type=\"A\",rcode=\"NOERROR\",transport=\"udp\"} 2\n"
+        ));
+        assert!(text.contains(
+            "dnser_queries_total{opcode=\"QUERY\",type=\"MX\",rcode=\"DROPPED\",transport=\"tcp\"} 1"
+        ));
+        assert!(text.contains(
+            "dnser_queries_total{opcode=\"UPDATE\",type=\"SOA\",rcode=\"REFUSED\",transport=\"udp\"} 1"
+        ));
+        assert!(text.contains("dnser_cache_lookups_total{result=\"hit\"} 2\n"));
+        assert!(text.contains("dnser_cache_entries{view=\"lab \\\"1\\\"\"} 0\n"));
+        assert!(text.contains(
+            "dnser_upstream_rtt_seconds_bucket{server=\"198.41.0.4:53\",result=\"ok\",le=\"0.01\"} 0\n"
+        ));
+        assert!(text.contains(
+            "dnser_upstream_rtt_seconds_bucket{server=\"198.41.0.4:53\",result=\"ok\",le=\"0.5\"} 2\n"
+        ));
+        assert!(text.contains(
+            "dnser_upstream_rtt_seconds_bucket{server=\"198.41.0.4:53\",result=\"ok\",le=\"+Inf\"} 3\n"
+        ));
+        assert!(text.contains(
+            "dnser_upstream_rtt_seconds_count{server=\"198.41.0.4:53\",result=\"timeout\"} 1\n"
+        ));
+        assert!(text.contains(
+            "dnser_upstream_rtt_seconds_count{server=\"198.41.0.4:53\",result=\"error\"} 1\n"
+        ));
+        assert!(text.contains("dnser_queries_in_flight 1\n"));
+        assert!(text.contains("dnser_errors_total{kind=\"ResolutionFailed\"} 1\n"));
+        assert!(text.contains("dnser_rate_limited_responses_total{action=\"slip\"} 1\n"));
+        assert!(text.contains("# TYPE dnser_upstream_rtt_seconds histogram\n"));
+
+        drop(guard);
+        assert!(metrics.render(&[]).contains("dnser_queries_in_flight 0\n"));
+    }
+
+    #[tokio::test]
+    async fn serve_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, || "dnser_up 1\n".to_owned()));
+
+        let request = |request: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 11\r\n"));
+        assert!(response.ends_with("\r\n\r\ndnser_up 1\n"));
+        let response = request("GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        let response = request("POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+    }
+}
@@ -0,0 +1,439 @@
+use crate::acl::Cidr;
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use crate::error::{Error, Result};
+use crate::zone::{synthesize, ZoneSpec};
+use crate::zone_file::parse_zone_file;
+use log::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// What a policy does with a query or a response that triggers it, given by
+/// the records of the trigger in the policy zone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// `CNAME .`
+    NxDomain,
+    /// `CNAME *.`
+    NoData,
+    /// `CNAME rpz-passthru.`, exempting the name from all the policies
+    PassThru,
+    /// `CNAME rpz-drop.`, sending no response at all
+    Drop,
+    /// Any other records, answered in place of the real ones
+    LocalData(Vec<DnsRecord>),
+}
+
+impl PolicyAction {
+    fn of(records: Vec<DnsRecord>) -> Option<Self> {
+        let special = records.iter().find_map(|r| match r {
+            DnsRecord::CNAME { host, .. } => match host.to_ascii_lowercase().as_str() {
+                "" => Some(Some(PolicyAction::NxDomain)),
+                "*" => Some(Some(PolicyAction::NoData)),
+                "rpz-passthru" => Some(Some(PolicyAction::PassThru)),
+                "rpz-drop" => Some(Some(PolicyAction::Drop)),
+                "rpz-tcp-only" => Some(None),
+                _ => None,
+            },
+            _ => None,
+        });
+        match special {
+            Some(action) => action,
+            None => Some(PolicyAction::LocalData(records)),
+        }
+    }
+}
+
+/// Names matched exactly, or by `*.domain` for all the names below.
+#[derive(Debug, Default)]
+struct NameTriggers {
+    exact: HashMap<String, PolicyAction>,
+    wildcards: HashMap<String, PolicyAction>,
+}
+
+impl NameTriggers {
+    fn insert(&mut self, name: &str, action: PolicyAction) {
+        match name.strip_prefix("*.") {
+            Some(domain) => self.wildcards.insert(domain.to_owned(), action),
+            None => self.exact.insert(name.to_owned(), action),
+        };
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.wildcards.len()
+    }
+
+    /// The exact trigger, or else the wildcard of the closest enclosing
+    /// domain.
+    fn find(&self, name: &str) -> Option<&PolicyAction> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(action) = self.exact.get(&name) {
+            return Some(action);
+        }
+        let mut rest = name.as_str();
+        while let Some(index) = rest.find('.') {
+            rest = &rest[index + 1..];
+            if let Some(action) = self.wildcards.get(rest) {
+                return Some(action);
+            }
+        }
+        None
+    }
+}
+
+/// Parse the network of an IP trigger, written as the prefix length followed
+/// by the labels of the address in reverse, with `zz` for the longest run of
+/// zeros in IPv6, like `24.0.2.0.192` or `48.zz.db8.2001`.
+fn parse_ip_trigger(labels: &str) -> Option<Cidr> {
+    let mut labels = labels.split('.');
+    let prefix_len = labels.next()?;
+    let labels: Vec<_> = labels.rev().collect();
+    let addr = if labels.len() == 4 && labels.iter().all(|l| l.parse::<u8>().is_ok()) {
+        labels.join(".")
+    } else {
+        let groups: Vec<_> = labels
+            .iter()
+            .map(|&l| if l.eq_ignore_ascii_case("zz") { "" } else { l })
+            .collect();
+        let mut addr = groups.join(":");
+        if addr.starts_with(':') {
+            addr.insert(0, ':');
+        }
+        if addr.ends_with(':') {
+            addr.push(':');
+        }
+        addr
+    };
+    format!("{}/{}", addr, prefix_len).parse().ok()
+}
+
+/// A Response Policy Zone, whose owner names encode the triggers below its
+/// origin: the query names themselves, addresses in the answers under
+/// `rpz-ip`, and names of the authoritative servers under `rpz-nsdname`.
+#[derive(Debug)]
+pub struct PolicyZone {
+    pub origin: String,
+    qnames: NameTriggers,
+    ips: Vec<(Cidr, PolicyAction)>,
+    nsdnames: NameTriggers,
+}
+
+impl PolicyZone {
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> Result<Self> {
+        let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+        let mut zone = Self {
+            origin,
+            qnames: NameTriggers::default(),
+            ips: Vec::new(),
+            nsdnames: NameTriggers::default(),
+        };
+
+        let mut owners: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+        for record in records {
+            let owner = record.name().to_ascii_lowercase();
+            let trigger = match owner.strip_suffix(&format!(".{}", zone.origin)) {
+                Some(trigger) => trigger.to_owned(),
+                // the SOA and NS records of the zone itself
+                None => continue,
+            };
+            owners.entry(trigger).or_default().push(record);
+        }
+
+        for (trigger, records) in owners {
+            let action = match PolicyAction::of(records) {
+                Some(action) => action,
+                None => {
+                    warn!("Ignoring unsupported action of policy `{}`", trigger);
+                    continue;
+                }
+            };
+            if let Some(labels) = trigger.strip_suffix(".rpz-ip") {
+                let cidr = parse_ip_trigger(labels).ok_or_else(|| {
+                    Error::InvalidZone(format!(
+                        "bad IP trigger `{}` in policy zone {}",
+                        trigger, zone.origin
+                    ))
+                })?;
+                zone.ips.push((cidr, action));
+            } else if let Some(name) = trigger.strip_suffix(".rpz-nsdname") {
+                zone.nsdnames.insert(name, action);
+            } else if trigger.contains(".rpz-") {
+                warn!("Ignoring unsupported trigger of policy `{}`", trigger);
+            } else {
+                zone.qnames.insert(&trigger, action);
+            }
+        }
+        Ok(zone)
+    }
+
+    pub fn load(spec: &ZoneSpec) -> Result<Self> {
+        let records = parse_zone_file(spec.path.as_ref(), &spec.origin)?;
+        Self::new(&spec.origin, records)
+    }
+
+    pub fn len(&self) -> usize {
+        self.qnames.len() + self.ips.len() + self.nsdnames.len()
+    }
+
+    /// The trigger of the longest prefix containing `ip`.
+    fn find_ip(&self, ip: IpAddr) -> Option<&PolicyAction> {
+        self.ips
+            .iter()
+            .filter(|(cidr, _)| cidr.contains(ip))
+            .max_by_key(|(cidr, _)| cidr.prefix_len())
+            .map(|(_, action)| action)
+    }
+
+    /// Check the names the answers alias to, then the addresses in them, then
+    /// the name servers of the zone they come from.
+    fn check_response(&self, response: &DnsPacket, ns_names: &[String]) -> Option<&PolicyAction> {
+        let aliases = response.answers.iter().filter_map(|r| match r {
+            DnsRecord::CNAME { host, .. } => Some(host),
+            _ => None,
+        });
+        let ips = response.answers.iter().filter_map(|r| match r {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        });
+        aliases
+            .filter_map(|name| self.qnames.find(name))
+            .chain(ips.filter_map(|ip| self.find_ip(ip)))
+            .chain(ns_names.iter().filter_map(|ns| self.nsdnames.find(ns)))
+            .next()
+    }
+}
+
+/// Response policy zones, the first of which with a matching trigger of any
+/// kind decides the action.
+///
+/// Within a zone, a trigger on the query name comes before the ones on the
+/// answer, so a query is checked against the zones up to the first one its
+/// name triggers, and once resolved, the answer against the zones before
+/// that.
+#[derive(Debug, Default)]
+pub struct Rpz {
+    zones: Vec<PolicyZone>,
+}
+
+impl Rpz {
+    pub fn new(zones: Vec<PolicyZone>) -> Self {
+        Self { zones }
+    }
+
+    pub fn load(specs: &[ZoneSpec]) -> Result<Self> {
+        let mut zones = Vec::new();
+        for spec in specs {
+            let zone = PolicyZone::load(spec)?;
+            println!(
+                "Loaded {} policies of {} from {}",
+                zone.len(),
+                zone.origin,
+                spec.path
+            );
+            zones.push(zone);
+        }
+        Ok(Self::new(zones))
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    /// The policy on a query name, with the index of the first zone it
+    /// triggers.
+    pub fn check_query(&self, name: &str) -> Option<(usize, &PolicyAction)> {
+        self.zones
+            .iter()
+            .enumerate()
+            .find_map(|(i, zone)| zone.qnames.find(name).map(|action| (i, action)))
+    }
+
+    /// The policy on a resolved response among the first `zones` zones, given
+    /// the names of the servers authoritative for it.
+    pub fn check_response(
+        &self,
+        response: &DnsPacket,
+        ns_names: &[String],
+        zones: usize,
+    ) -> Option<&PolicyAction> {
+        self.zones
+            .iter()
+            .take(zones)
+            .find_map(|zone| zone.check_response(response, ns_names))
+    }
+}
+
+/// The response to a query rewritten by a policy, `None` for no response.
+/// Local data is answered under the query name, of the query type or a CNAME.
+pub fn respond(action: &PolicyAction, question: DnsQuestion) -> Option<DnsPacket> {
+    let mut response_packet = DnsPacket::default();
+    response_packet.header.rescode = match action {
+        PolicyAction::Drop => return None,
+        PolicyAction::NxDomain => ResultCode::NXDOMAIN,
+        PolicyAction::NoData | PolicyAction::PassThru => ResultCode::NOERROR,
+        PolicyAction::LocalData(records) => {
+            let query_type = question.query_type;
+            let matches =
+                |r: &&DnsRecord| query_type == QueryType::ANY || r.query_type() == query_type;
+            let answers: Vec<_> = match records.iter().filter(matches).count() {
+                0 => records
+                    .iter()
+                    .filter(|r| r.query_type() == QueryType::CNAME)
+                    .collect(),
+                _ => records.iter().filter(matches).collect(),
+            };
+            response_packet.answers = answers
+                .into_iter()
+                .map(|r| synthesize(r.clone(), &question.name))
+                .collect();
+            ResultCode::NOERROR
+        }
+    };
+    response_packet.questions.push(question);
+    response_packet.update_header_counts();
+    Some(response_packet)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn question(name: &str, query_type: QueryType) -> DnsQuestion {
+        DnsQuestion {
+            name: name.into(),
+            query_type,
+        }
+    }
+
+    fn rpz() -> Rpz {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("res/rpz.zone");
+        let zone = PolicyZone::new("rpz.local", parse_zone_file(&path, "rpz.local").unwrap());
+        Rpz::new(vec![zone.unwrap()])
+    }
+
+    fn response(answers: Vec<DnsRecord>) -> DnsPacket {
+        let mut packet = DnsPacket {
+            answers,
+            ..DnsPacket::default()
+        };
+        packet.update_header_counts();
+        packet
+    }
+
+    #[test]
+    fn parse_ip_triggers() {
+        let parse = |s: &str| parse_ip_trigger(s).map(|cidr| cidr.to_string());
+        assert_eq!(parse("32.1.0.0.10").unwrap(), "10.0.0.1/32");
+        assert_eq!(parse("24.0.2.0.192").unwrap(), "192.0.2.0/24");
+        assert_eq!(parse("48.zz.db8.2001").unwrap(), "2001:db8::/48");
+        assert_eq!(parse("128.1.zz.db8.2001").unwrap(), "2001:db8::1/128");
+        assert_eq!(parse("128.1.zz").unwrap(), "::1/128");
+        assert!(parse("33.1.0.0.10").is_none());
+        assert!(parse("1.0.0.10").is_none());
+    }
+
+    #[test]
+    fn match_query_names() {
+        let rpz = rpz();
+        let check = |name| rpz.check_query(name).map(|(_, action)| action);
+        use PolicyAction::*;
+        assert_eq!(check("bad.example"), Some(&NxDomain));
+        assert_eq!(check("x.y.bad.example"), Some(&NxDomain));
+        assert_eq!(check("ok.bad.example"), Some(&PassThru));
+        assert_eq!(check("Empty.Example."), Some(&NoData));
+        assert_eq!(check("silent.example"), Some(&Drop));
+        assert_eq!(check("example"), None);
+        assert_eq!(check("tcp.example"), None);
+
+        let action = check("garden.example").unwrap();
+        let response = respond(action, question("garden.example", QueryType::A)).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name(), "garden.example");
+        assert_eq!(response.answers[0].rdata_text(), "10.9.9.9");
+        let response = respond(action, question("garden.example", QueryType::MX)).unwrap();
+        assert!(response.answers.is_empty());
+
+        let action = check("a.redirect.example").unwrap();
+        let response = respond(action, question("a.redirect.example", QueryType::A)).unwrap();
+        assert_eq!(response.answers[0].name(), "a.redirect.example");
+        assert_eq!(response.answers[0].rdata_text(), "walled.garden.");
+
+        let response = respond(&NxDomain, question("bad.example", QueryType::A)).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert!(respond(&Drop, question("silent.example", QueryType::A)).is_none());
+    }
+
+    #[test]
+    fn match_responses() {
+        let rpz = rpz();
+        let check = |response, ns_names: &[String]| rpz.check_response(response, ns_names, 1);
+        use PolicyAction::*;
+        let a = |name: &str, addr: &str| DnsRecord::A {
+            name: name.into(),
+            addr: addr.parse().unwrap(),
+            ttl: 300,
+        };
+
+        let clean = response(vec![a("www.example", "192.0.3.1")]);
+        assert_eq!(check(&clean, &[]), None);
+        let bad = response(vec![a("www.example", "192.0.2.55")]);
+        assert_eq!(check(&bad, &[]), Some(&NxDomain));
+        // the longest prefix wins
+        let good = response(vec![a("www.example", "192.0.2.1")]);
+        assert_eq!(check(&good, &[]), Some(&PassThru));
+        let aaaa = response(vec![DnsRecord::AAAA {
+            name: "www.example".into(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 300,
+        }]);
+        assert_eq!(check(&aaaa, &[]), Some(&NoData));
+
+        let alias = response(vec![DnsRecord::CNAME {
+            name: "www.example".into(),
+            host: "cdn.bad.example".into(),
+            ttl: 300,
+        }]);
+        assert_eq!(check(&alias, &[]), Some(&NxDomain));
+
+        let ns = |name: &str| vec![name.to_owned()];
+        assert_eq!(check(&clean, &ns("ns1.evil-dns.example")), Some(&NxDomain));
+        assert_eq!(check(&clean, &ns("a.ns.shady.example")), Some(&Drop));
+        assert_eq!(check(&clean, &ns("ns.shady.example")), None);
+    }
+
+    #[test]
+    fn check_zones_in_order() {
+        let zone = |text: &str| {
+            let records = crate::zone_file::parse_zone(text, "rpz.local").unwrap();
+            PolicyZone::new("rpz.local", records).unwrap()
+        };
+        let rpz = Rpz::new(vec![
+            zone("24.0.2.0.192.rpz-ip 60 CNAME .\n"),
+            zone("www.example 60 CNAME *.\n"),
+        ]);
+        let (zones, action) = rpz.check_query("www.example").unwrap();
+        assert_eq!((zones, action), (1, &PolicyAction::NoData));
+
+        // the IP trigger of the first zone wins over the name of the second
+        let bad = response(vec![DnsRecord::A {
+            name: "www.example".into(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 300,
+        }]);
+        assert_eq!(
+            rpz.check_response(&bad, &[], zones),
+            Some(&PolicyAction::NxDomain)
+        );
+        let clean = response(vec![]);
+        assert_eq!(rpz.check_response(&clean, &[], zones), None);
+    }
+
+    #[test]
+    fn reject_bad_triggers() {
+        let records =
+            crate::zone_file::parse_zone("300.1.0.0.10.rpz-ip 60 CNAME .\n", "rpz.local").unwrap();
+        assert!(PolicyZone::new("rpz.local", records).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::dns_packet::{DnsPacket, ResultCode};
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Buckets kept at most, beyond which the least recently used one is
+/// forgotten.
+const MAX_ENTRIES: usize = 65536;
+
+/// Limits of Response Rate Limiting, in responses per second to a client
+/// prefix, zero for no limit.
+#[derive(Clone, Debug)]
+pub struct RateLimitOptions {
+    pub responses_per_second: u32,
+    pub nxdomains_per_second: u32,
+    pub errors_per_second: u32,
+    /// Every this many limited responses, one is sent truncated instead of
+    /// dropped, so that real clients retry over TCP. Zero to drop all
+    pub slip: u32,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+impl RateLimitOptions {
+    pub fn disabled() -> Self {
+        Self {
+            responses_per_second: 0,
+            nxdomains_per_second: 0,
+            errors_per_second: 0,
+            slip: 2,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum ResponseClass {
+    Answer,
+    NxDomain,
+    Error,
+}
+
+impl ResponseClass {
+    fn of(packet: &DnsPacket) -> Self {
+        match packet.header.rescode {
+            ResultCode::NOERROR => ResponseClass::Answer,
+            ResultCode::NXDOMAIN => ResponseClass::NxDomain,
+            _ => ResponseClass::Error,
+        }
+    }
+}
+
+/// What to do with a response.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Send,
+    /// Send it truncated, with the header and the question only
+    Slip,
+    Drop,
+}
+
+type BucketKey = (IpAddr, ResponseClass);
+
+struct Bucket {
+    credit: f64,
+    last: Instant,
+    limited: u64,
+    last_used: u64,
+}
+
+struct Buckets {
+    entries: HashMap<BucketKey, Bucket>,
+    // last_used tick -> key, the first one is the least recently used
+    lru: BTreeMap<u64, BucketKey>,
+    tick: u64,
+}
+
+impl Buckets {
+    /// The bucket of `key`, full of `credit` if it is a new one.
+    fn get(&mut self, key: BucketKey, credit: f64, now: Instant) -> &mut Bucket {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(&key) {
+            Some(bucket) => {
+                self.lru.remove(&bucket.last_used);
+            }
+            None => {
+                if self.entries.len() >= MAX_ENTRIES {
+                    if let Some((_, oldest)) = self.lru.pop_first() {
+                        self.entries.remove(&oldest);
+                    }
+                }
+                self.entries.insert(
+                    key,
+                    Bucket {
+                        credit,
+                        last: now,
+                        limited: 0,
+                        last_used: tick,
+                    },
+                );
+            }
+        }
+        self.lru.insert(tick, key);
+        let bucket = self.entries.get_mut(&key).unwrap();
+        bucket.last_used = tick;
+        bucket
+    }
+}
+
+/// Response Rate Limiting against reflection attacks over UDP, with a token
+/// bucket for each client prefix and class of response.
+pub struct RateLimiter {
+    options: RateLimitOptions,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(options: RateLimitOptions) -> Self {
+        Self {
+            options,
+            buckets: Mutex::new(Buckets {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr, response: &DnsPacket) -> Action {
+        self.check_at(ip, ResponseClass::of(response), Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, class: ResponseClass, now: Instant) -> Action {
+        let options = &self.options;
+        let rate = match class {
+            ResponseClass::Answer => options.responses_per_second,
+            ResponseClass::NxDomain => options.nxdomains_per_second,
+            ResponseClass::Error => options.errors_per_second,
+        } as f64;
+        if rate == 0.0 {
+            return Action::Send;
+        }
+
+        let prefix = self.prefix(ip);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get((prefix, class), rate, now);
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.credit = (bucket.credit + elapsed * rate).min(rate);
+        bucket.last = now;
+
+        if bucket.credit >= 1.0 {
+            bucket.credit -= 1.0;
+            if bucket.limited > 0 {
+                info!(
+                    "Stopped limiting {:?} responses to {} after {} limited",
+                    class, prefix, bucket.limited
+                );
+                bucket.limited = 0;
+            }
+            return Action::Send;
+        }
+
+        bucket.limited += 1;
+        if bucket.limited == 1 {
+            warn!("Limiting {:?} responses to {}", class, prefix);
+        }
+        if options.slip > 0 && bucket.limited.is_multiple_of(options.slip as u64) {
+            Action::Slip
+        } else {
+            Action::Drop
+        }
+    }
+
+    /// The network of `ip` that is limited as a whole.
+    fn prefix(&self, ip: IpAddr) -> IpAddr {
+        fn mask(octets: &mut [u8], prefix_len: u8) {
+            for (i, octet) in octets.iter_mut().enumerate() {
+                let bits = (prefix_len as usize).saturating_sub(i * 8).min(8);
+                *octet &= !(0xffu16 >> bits) as u8;
+            }
+        }
+
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let mut octets = ip.octets();
+                mask(&mut octets, self.options.ipv4_prefix_len);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            IpAddr::V6(ip) => {
+                let mut octets = ip.octets();
+                mask(&mut octets, self.options.ipv6_prefix_len);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter(slip: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitOptions {
+            responses_per_second: 5,
+            nxdomains_per_second: 2,
+            errors_per_second: 0,
+            slip,
+            ..RateLimitOptions::disabled()
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn limit_by_prefix_and_class() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+        let check = |s: &str, class| limiter.check_at(ip(s), class, now);
+
+        for i in 0..5 {
+            let client = format!("10.0.0.{}", i);
+            assert_eq!(check(&client, ResponseClass::Answer), Action::Send);
+        }
+        // the same /24
+        assert_eq!(check("10.0.0.200", ResponseClass::Answer), Action::Drop);
+        // other prefixes and classes
+        assert_eq!(check("10.0.1.1", ResponseClass::Answer), Action::Send);
+        assert_eq!(check("10.0.0.1", ResponseClass::NxDomain), Action::Send);
+        assert_eq!(check("10.0.0.1", ResponseClass::NxDomain), Action::Send);
+        assert_eq!(check("10.0.0.1", ResponseClass::NxDomain), Action::Drop);
+        // unlimited
+        for _ in 0..100 {
+            assert_eq!(check("10.0.0.1", ResponseClass::Error), Action::Send);
+        }
+
+        // the credit comes back over time
+        let later = now + Duration::from_millis(400);
+        assert_eq!(
+            limiter.check_at(ip("10.0.0.1"), ResponseClass::Answer, later),
+            Action::Send
+        );
+        assert_eq!(
+            limiter.check_at(ip("10.0.0.1"), ResponseClass::Answer, later),
+            Action::Send
+        );
+        assert_eq!(
+            limiter.check_at(ip("10.0.0.1"), ResponseClass::Answer, later),
+            Action::Drop
+        );
+    }
+
+    #[test]
+    fn slip_and_ipv6_prefix() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+        let actions: Vec<_> = (0..9)
+            .map(|i| {
+                let client = format!("2001:db8:0:{:x}::1", i);
+                limiter.check_at(ip(&client), ResponseClass::Answer, now)
+            })
+            .collect();
+        use Action::*;
+        assert_eq!(
+            actions,
+            vec![Send, Send, Send, Send, Send, Drop, Slip, Drop, Slip]
+        );
+
+        // out of the /56
+        assert_eq!(
+            limiter.check_at(ip("2001:db8:0:100::1"), ResponseClass::Answer, now),
+            Send
+        );
+    }
+
+    #[test]
+    fn forget_least_recently_used() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+        let check = |ip: IpAddr| limiter.check_at(ip, ResponseClass::NxDomain, now);
+        let nth = |i: usize| IpAddr::V4(Ipv4Addr::from((i as u32) << 8));
+
+        for _ in 0..2 {
+            assert_eq!(check(nth(0)), Action::Send);
+            assert_eq!(check(nth(1)), Action::Send);
+        }
+        assert_eq!(check(nth(1)), Action::Drop);
+        for i in 2..=MAX_ENTRIES {
+            check(nth(i));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().entries.len(), MAX_ENTRIES);
+        // the limited one was used after the first one, which is forgotten
+        assert_eq!(check(nth(1)), Action::Drop);
+        assert_eq!(check(nth(0)), Action::Send);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::client::{transfer, Transfer};
+use crate::dns_packet::DnsRecord;
+use crate::error::{Error, Result};
+use crate::tsig::TsigKey;
+use crate::zone::{Catalog, Zone};
+use log::*;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+/// How long to wait before retrying the first transfer of a zone, which has no
+/// SOA record to tell the intervals yet.
+const INITIAL_RETRY: Duration = Duration::from_secs(10);
+
+/// A zone pulled from a primary, written as `<origin>=<addr>[:port][@key]`,
+/// with transfers signed by the named TSIG key if given.
+#[derive(Clone, Debug)]
+pub struct SecondarySpec {
+    pub origin: String,
+    pub primary: SocketAddr,
+    pub key: Option<String>,
+}
+
+impl FromStr for SecondarySpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, '=');
+        let (origin, primary) = match (parts.next(), parts.next()) {
+            (Some(origin), Some(primary)) => (origin, primary),
+            _ => return Err(Error::InvalidSecondary(s.to_owned())),
+        };
+        let mut parts = primary.splitn(2, '@');
+        let (primary, key) = (parts.next().unwrap(), parts.next());
+        if key.is_some_and(str::is_empty) {
+            return Err(Error::InvalidSecondary(s.to_owned()));
+        }
+        let primary = primary
+            .parse()
+            .or_else(|_| primary.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .map_err(|_| Error::InvalidSecondary(s.to_owned()))?;
+        Ok(Self {
+            origin: origin.trim_end_matches('.').to_ascii_lowercase(),
+            primary,
+            key: key.map(|k| k.trim_end_matches('.').to_ascii_lowercase()),
+        })
+    }
+}
+
+/// The refresh, retry and expire intervals in the SOA record of `zone`.
+fn soa_timers(zone: &Zone) -> Result<(Duration, Duration, Duration)> {
+    match zone.soa() {
+        Some(DnsRecord::SOA {
+            refresh,
+            retry,
+            expire,
+            ..
+        }) => Ok((
+            Duration::from_secs(*refresh as u64),
+            Duration::from_secs(*retry as u64),
+            Duration::from_secs(*expire as u64),
+        )),
+        _ => Err(Error::InvalidZone(format!(
+            "no SOA record at `{}`",
+            zone.origin
+        ))),
+    }
+}
+
+pub struct Secondary {
+    pub spec: SecondarySpec,
+    key: Option<TsigKey>,
+    notify: Notify,
+}
+
+impl Secondary {
+    pub fn new(spec: SecondarySpec, key: Option<TsigKey>) -> Self {
+        Self {
+            spec,
+            key,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Refresh the zone as soon as possible, like on a NOTIFY from the primary.
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Keep the zone in `catalog` up to date with the primary, following the
+    /// timers in its SOA record as in RFC 1034, section 4.3.5. The zone is
+    /// dropped once it expires, until the primary is reachable again.
+    pub async fn run(&self, catalog: &Catalog) {
+        let origin = &self.spec.origin;
+        let mut last_refreshed = Instant::now();
+        loop {
+            let result = self.refresh(catalog).await;
+            let timers = catalog.get(origin).map(|zone| soa_timers(&zone));
+            let wait = match (result, timers.transpose()) {
+                (_, Err(e)) => {
+                    warn!("error refreshing {}: {}", origin, e);
+                    INITIAL_RETRY
+                }
+                (Ok(_), Ok(Some((refresh, _, _)))) => {
+                    last_refreshed = Instant::now();
+                    refresh
+                }
+                (Err(e), Ok(Some((_, retry, expire)))) => {
+                    warn!("error refreshing {}: {}", origin, e);
+                    if last_refreshed.elapsed() >= expire {
+                        warn!("Zone {} expired", origin);
+                        catalog.remove(origin);
+                    }
+                    retry
+                }
+                (result, Ok(None)) => {
+                    if let Err(e) = result {
+                        warn!("error transferring {}: {}", origin, e);
+                    }
+                    INITIAL_RETRY
+                }
+            };
+
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = self.notify.notified() => info!("Refreshing {} on NOTIFY", origin),
+            }
+        }
+    }
+
+    async fn refresh(&self, catalog: &Catalog) -> Result<()> {
+        let origin = &self.spec.origin;
+        let current = catalog.get(origin);
+        let current_soa = current.as_ref().and_then(|z| z.soa());
+
+        let zone = match transfer(origin, self.spec.primary, current_soa, self.key.as_ref()).await?
+        {
+            Transfer::UpToDate => {
+                debug!("Zone {} is up to date", origin);
+                return Ok(());
+            }
+            Transfer::Full(records) => Zone::new(origin, records)?,
+            Transfer::Incremental(diffs) => {
+                let current = current.ok_or_else(|| {
+                    Error::TransferFailed(format!(
+                        "incremental transfer of `{}` without a version to apply it to",
+                        origin
+                    ))
+                })?;
+                let mut zone = current.apply(&diffs[0])?;
+                for diff in diffs[1..].iter() {
+                    zone = zone.apply(diff)?;
+                }
+                zone
+            }
+        };
+        info!("Zone {} updated to serial {}", origin, zone.serial());
+        catalog.insert(zone);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns_packet::{DnsPacket, QueryType};
+    use crate::dns_packet_buf::{DnsPacketBuf, TCP_PACKET_SIZE};
+    use crate::transfer::{pack_messages, transfer_records};
+    use crate::zone_file::parse_zone;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn zone(serial: u32, extra: &str) -> Zone {
+        let text = format!(
+            "$TTL 60\n@ SOA ns admin {} 3600 600 86400 60\n@ NS ns\nns A 10.0.0.1\n{}",
+            serial, extra
+        );
+        Zone::new("bugen.dev", parse_zone(&text, "bugen.dev").unwrap()).unwrap()
+    }
+
+    /// Serve transfers of `zone` like a primary.
+    async fn primary_stand_in(zone: Arc<Mutex<Arc<Zone>>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut buf = DnsPacketBuf::with_size(len as usize);
+                stream.read_exact(&mut buf.buf).await.unwrap();
+                let query = DnsPacket::read_from(&mut buf).unwrap();
+
+                let zone = zone.lock().unwrap().clone();
+                for message in pack_messages(&query, transfer_records(&zone, &query)) {
+                    let mut buf = DnsPacketBuf::with_size(TCP_PACKET_SIZE);
+                    message.write(&mut buf).unwrap();
+                    stream.write_u16(buf.pos as u16).await.unwrap();
+                    stream.write_all(&buf.buf[0..buf.pos]).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    async fn wait_for_serial(catalog: &Catalog, serial: u32) -> Arc<Zone> {
+        for _ in 0..100 {
+            if let Some(zone) = catalog.get("bugen.dev") {
+                if zone.serial() == serial {
+                    return zone;
+                }
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("zone not transferred at serial {}", serial);
+    }
+
+    #[test]
+    fn parse_spec() {
+        let spec: SecondarySpec = "Bugen.dev.=10.0.0.1".parse().unwrap();
+        assert_eq!(spec.origin, "bugen.dev");
+        assert_eq!(spec.primary, "10.0.0.1:53".parse().unwrap());
+        let spec: SecondarySpec = "bugen.dev=[::1]:5353".parse().unwrap();
+        assert_eq!(spec.primary, "[::1]:5353".parse().unwrap());
+        assert_eq!(spec.key, None);
+        let spec: SecondarySpec = "bugen.dev=10.0.0.1@Transfer".parse().unwrap();
+        assert_eq!(spec.primary, "10.0.0.1:53".parse().unwrap());
+        assert_eq!(spec.key, Some("transfer".into()));
+        assert!("bugen.dev=10.0.0.1@".parse::<SecondarySpec>().is_err());
+        assert!("bugen.dev".parse::<SecondarySpec>().is_err());
+        assert!("bugen.dev=nowhere".parse::<SecondarySpec>().is_err());
+    }
+
+    #[tokio::test]
+    async fn transfer_and_notify() {
+        let primary_zone = Arc::new(Mutex::new(Arc::new(zone(1, "www A 10.0.0.2"))));
+        let primary = primary_stand_in(primary_zone.clone()).await;
+
+        let secondary = Arc::new(Secondary::new(
+            SecondarySpec {
+                origin: "bugen.dev".into(),
+                primary,
+                key: None,
+            },
+            None,
+        ));
+        let catalog = Arc::new(Catalog::new(vec![]));
+        {
+            let secondary = secondary.clone();
+            let catalog = catalog.clone();
+            tokio::spawn(async move { secondary.run(&catalog).await });
+        }
+
+        // full transfer at first
+        let transferred = wait_for_serial(&catalog, 1).await;
+        assert_eq!(transferred.records().count(), 3);
+        assert!(transferred.journal().diffs().is_empty());
+
+        // then incremental ones on NOTIFY
+        {
+            let mut primary_zone = primary_zone.lock().unwrap();
+            let new = zone(2, "www A 10.0.0.3");
+            *primary_zone = Arc::new(
+                primary_zone
+                    .apply(&primary_zone.diff(&new).unwrap())
+                    .unwrap(),
+            );
+        }
+        secondary.notify();
+        let transferred = wait_for_serial(&catalog, 2).await;
+        assert_eq!(transferred.journal().diffs().len(), 1);
+        let question = crate::dns_packet::DnsQuestion {
+            name: "www.bugen.dev".into(),
+            query_type: QueryType::A,
+        };
+        assert_eq!(
+            transferred.lookup(&question).answers[0],
+            DnsRecord::A {
+                name: "www.bugen.dev".into(),
+                addr: "10.0.0.3".parse().unwrap(),
+                ttl: 60,
+            }
+        );
+
+        // nothing to transfer
+        let result = transfer("bugen.dev", primary, transferred.soa(), None)
+            .await
+            .unwrap();
+        assert!(matches!(result, Transfer::UpToDate));
+    }
+}
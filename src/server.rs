@@ -1,101 +1,1480 @@
+use crate::acl::{self, Cidr};
+use crate::blocklist::{BlockResponse, Blocker};
+use crate::cache::{Cache, CacheKey};
 use crate::client::{lookup, recursive_lookup};
-use crate::dns_packet::{DnsHeader, DnsPacket, ResultCode};
-use crate::dns_packet_buf::DnsPacketBuf;
+use crate::dns_packet::{
+    DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, OPCODE_NOTIFY,
+    OPCODE_UPDATE,
+};
+use crate::dns_packet_buf::{DnsPacketBuf, TCP_PACKET_SIZE, UDP_PACKET_SIZE};
+use crate::forward::{ForwardRule, ForwardRules, ResolveMode};
+use crate::inflight::InFlight;
+use crate::local::LocalData;
+use crate::metrics::{self, Metrics, Transport};
+use crate::rpz::{self, PolicyAction, Rpz};
+use crate::rrl::{Action, RateLimitOptions, RateLimiter};
+use crate::secondary::{Secondary, SecondarySpec};
+use crate::transfer::{is_transfer_query, pack_messages, transfer_records};
+use crate::tsig::{self, Signer, TsigKey, Verified};
+use crate::udp::{LocalAddr, UdpSocket};
+use crate::update::{apply_update, UpdateMessage};
+use crate::view::{View, ViewOptions};
+use crate::zone::{Catalog, ZoneSpec};
 use log::*;
-use std::{net::Ipv4Addr, sync::Arc};
-use tokio::net::UdpSocket;
+use socket2::{Domain, Socket, Type};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, Mutex, Notify, Semaphore};
+use tokio::time::timeout;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
-async fn handle_query(
-    socket: Arc<UdpSocket>,
-    mut query_buf: DnsPacketBuf,
-    from_addr: std::net::SocketAddr,
-    remote_server: (Ipv4Addr, u16),
-    proxy: bool,
-) -> Result<()> {
-    let mut query_packet = DnsPacket::read_from(&mut query_buf)?;
+pub struct ServerOptions {
+    pub remote_server: (Ipv4Addr, u16),
+    /// Addresses to listen on, each with a UDP socket and a TCP listener
+    pub listen: Vec<SocketAddr>,
+    pub proxy: bool,
+    /// Routing rules by domain suffix, the ones not covered are resolved using
+    /// `remote_server` and `proxy`
+    pub rules: Vec<ForwardRule>,
+    /// Records answered authoritatively, before any zone, forwarding or
+    /// recursion
+    pub local_data: Vec<DnsRecord>,
+    /// Domains whose whole subtrees are answered from the local data only
+    pub local_zones: Vec<String>,
+    /// Zones answered authoritatively, before any forwarding or recursion
+    pub zones: Vec<ZoneSpec>,
+    /// Zones transferred from their primaries
+    pub secondaries: Vec<SecondarySpec>,
+    /// Clients allowed to query at all
+    pub allow_query: Vec<Cidr>,
+    /// Clients allowed to query names out of the local zones, which are
+    /// forwarded or resolved recursively
+    pub allow_recursion: Vec<Cidr>,
+    /// Clients allowed to transfer the zones
+    pub allow_transfer: Vec<Cidr>,
+    /// Clients allowed to update the zones loaded from master files
+    pub allow_update: Vec<Cidr>,
+    /// Keys for TSIG, with which clients sign their messages and secondaries
+    /// sign their transfers
+    pub tsig_keys: Vec<TsigKey>,
+    /// Names of the keys with which clients are allowed to transfer the
+    /// zones wherever they are
+    pub allow_transfer_keys: Vec<String>,
+    /// Names of the keys with which clients are allowed to update the zones
+    /// wherever they are
+    pub allow_update_keys: Vec<String>,
+    pub cache_size: usize,
+    /// How long expired cache entries may still be served, zero to disable
+    pub stale_window: Duration,
+    /// How long a client waits for fresh resolution before getting stale data
+    pub stale_answer_timeout: Duration,
+    /// Hits needed before an entry close to expiry is refreshed in advance,
+    /// zero to disable
+    pub prefetch_hits: u32,
+    /// Percentage of the original TTL left that triggers prefetching
+    pub prefetch_ttl_percent: u32,
+    /// How long a TCP connection may stay without new queries
+    pub tcp_idle_timeout: Duration,
+    pub max_tcp_connections: usize,
+    /// How long queries being answered may take to finish on shutdown
+    pub shutdown_timeout: Duration,
+    /// Address to serve the metrics on over HTTP
+    pub metrics: Option<SocketAddr>,
+    /// Response Rate Limiting of UDP responses
+    pub rate_limit: RateLimitOptions,
+    /// Lists of domains blocked before any forwarding or recursion, in
+    /// hosts-file or plain-domain format
+    pub blocklists: Vec<PathBuf>,
+    /// Lists of domains never blocked
+    pub allowlists: Vec<PathBuf>,
+    pub block_response: BlockResponse,
+    /// How often the lists are checked for changes, zero to disable
+    pub blocklist_reload_interval: Duration,
+    /// Response policy zones, applied in order to the names out of the local
+    /// zones and to their resolved answers
+    pub rpz: Vec<ZoneSpec>,
+    /// Views for the clients from some networks, the first matching one of
+    /// which answers a query instead of the local data, zones, rules and
+    /// ACLs above
+    pub views: Vec<ViewOptions>,
+}
 
-    let mut response_packet = DnsPacket::default();
+/// The name of the view of the options outside any view.
+const DEFAULT_VIEW: &str = "_default";
 
-    // assuming exactly 1 question
-    match query_packet.questions.pop() {
-        Some(question) => {
-            let result = if proxy {
-                lookup(&question.name, question.query_type, remote_server).await
-            } else {
-                recursive_lookup(&question.name, question.query_type, remote_server, 0).await
-            };
+/// How many queries of a TCP connection may be answered at once.
+const MAX_PIPELINED_QUERIES: usize = 16;
+
+struct Context {
+    options: ServerOptions,
+    // in order, ending with the default one that matches all clients
+    views: Vec<Arc<View>>,
+    secondaries: Vec<Secondary>,
+    rate_limiter: RateLimiter,
+    blocker: Blocker,
+    rpz: Rpz,
+    // so that concurrent updates apply one after another, and not while
+    // reloading, shared by the contexts across reloads
+    update_lock: Arc<Mutex<()>>,
+    // replaced by a reload, after which updates must not apply to it
+    retired: AtomicBool,
+    // shared by the contexts across reloads
+    metrics: Arc<Metrics>,
+}
+
+impl Context {
+    /// The first view matching the client.
+    fn view(&self, ip: IpAddr) -> &Arc<View> {
+        self.views.iter().find(|view| view.matches(ip)).unwrap()
+    }
+
+    /// The view of the options outside any view, whose zones are the ones
+    /// updated dynamically and transferred as secondaries.
+    fn default_view(&self) -> &Arc<View> {
+        self.views.last().unwrap()
+    }
+}
+
+/// Counts the queries and TCP connections being served, so that shutdown can
+/// wait for them.
+#[derive(Default)]
+struct Pending {
+    count: AtomicUsize,
+    finished: Notify,
+}
+
+/// A query or connection counted as pending until dropped.
+struct PendingGuard(Arc<Pending>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.finished.notify_one();
+        }
+    }
+}
+
+impl Pending {
+    fn start(self: &Arc<Self>) -> PendingGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        PendingGuard(self.clone())
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Wait until nothing is pending.
+    async fn drained(&self) {
+        while self.count() > 0 {
+            self.finished.notified().await;
+        }
+    }
+}
+
+/// The context being served, replaced as a whole on reload, so that each query
+/// is answered by either the old or the new one.
+struct Server {
+    context: RwLock<Arc<Context>>,
+    pending: Arc<Pending>,
+    stop: watch::Sender<bool>,
+    stopping: watch::Receiver<bool>,
+}
+
+impl Server {
+    fn new(ctx: Context) -> Self {
+        let (stop, stopping) = watch::channel(false);
+        Self {
+            context: RwLock::new(Arc::new(ctx)),
+            pending: Arc::default(),
+            stop,
+            stopping,
+        }
+    }
 
-            match result {
-                Ok(packet) if proxy => {
-                    let r = &mut response_packet;
-                    r.questions.push(question);
-                    r.header.questions = 1;
-                    r.header.rescode = packet.header.rescode;
+    fn context(&self) -> Arc<Context> {
+        self.context.read().unwrap().clone()
+    }
 
-                    r.answers = packet.answers;
-                    r.header.answers = r.answers.len() as u16;
+    /// Load a new context from `options` and serve it instead of the current
+    /// one, which is kept on error.
+    async fn reload(&self, options: ServerOptions) -> Result<Arc<Context>> {
+        let previous = self.context();
+        if options.listen != previous.options.listen {
+            warn!("Ignoring the change of addresses to listen on until restart");
+        }
+        let _guard = previous.update_lock.lock().await;
+        let ctx = Arc::new(load_context(options, Some(&previous))?);
+        *self.context.write().unwrap() = ctx.clone();
+        previous.retired.store(true, Ordering::SeqCst);
+        Ok(ctx)
+    }
 
-                    r.authorities = packet.authorities;
-                    r.header.authoritative_entries = r.authorities.len() as u16;
+    /// Stop accepting queries and connections, and reading queries from the
+    /// connections.
+    fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
 
-                    r.resources = packet.resources;
-                    r.header.resource_entries = r.resources.len() as u16;
+    fn render_metrics(&self) -> String {
+        let ctx = self.context();
+        let cache_entries: Vec<_> = ctx
+            .views
+            .iter()
+            .map(|view| (view.name.as_str(), view.cache.len()))
+            .collect();
+        ctx.metrics.render(&cache_entries)
+    }
+}
+
+async fn resolve(
+    ctx: &Arc<Context>,
+    view: &Arc<View>,
+    question: &DnsQuestion,
+) -> Result<DnsPacket> {
+    let cached = view.cache.lookup(question);
+    ctx.metrics.cache_lookup(cached.is_some());
+    if let Some(packet) = cached {
+        debug!("Cache hit for {} {:?}", question.name, question.query_type);
+        let options = &ctx.options;
+        if options.prefetch_hits > 0
+            && view.cache.should_prefetch(
+                question,
+                options.prefetch_hits,
+                options.prefetch_ttl_percent,
+            )
+        {
+            info!("Prefetching {} {:?}", question.name, question.query_type);
+            let view = view.clone();
+            let metrics = ctx.metrics.clone();
+            let question = question.clone();
+            tokio::spawn(async move {
+                if let Err(e) = resolve_coalesced(&view, &metrics, &question).await {
+                    warn!("error prefetching {}: {}", question.name, e);
                 }
-                Ok(packet) if !proxy => {
-                    response_packet = packet;
+            });
+        }
+        return Ok(packet);
+    }
+
+    if ctx.options.stale_window.as_secs() == 0 {
+        return resolve_coalesced(view, &ctx.metrics, question).await;
+    }
+
+    // resolve in a separate task, so that the cache still gets refreshed in the
+    // background after a stale answer is sent
+    let mut task = {
+        let view = view.clone();
+        let metrics = ctx.metrics.clone();
+        let question = question.clone();
+        tokio::spawn(async move { resolve_coalesced(&view, &metrics, &question).await })
+    };
+    let result = match timeout(ctx.options.stale_answer_timeout, &mut task).await {
+        Ok(joined) => {
+            joined.unwrap_or_else(|_| Err(Error::ResolutionFailed(question.name.clone())))
+        }
+        Err(_) => {
+            if let Some(packet) = view.cache.lookup_stale(question) {
+                info!("Serving stale answer for {} after timeout", question.name);
+                return Ok(packet);
+            }
+            task.await
+                .unwrap_or_else(|_| Err(Error::ResolutionFailed(question.name.clone())))
+        }
+    };
+
+    // an upstream SERVFAIL is a failure to resolve as well, RFC 8767 section 4
+    let failure = match &result {
+        Ok(packet) if packet.header.rescode == ResultCode::SERVFAIL => "SERVFAIL".to_owned(),
+        Ok(_) => return result,
+        Err(e) => e.to_string(),
+    };
+    match view.cache.lookup_stale(question) {
+        Some(packet) => {
+            info!(
+                "Serving stale answer for {} after error: {}",
+                question.name, failure
+            );
+            Ok(packet)
+        }
+        None => result,
+    }
+}
+
+async fn resolve_coalesced(
+    view: &View,
+    metrics: &Metrics,
+    question: &DnsQuestion,
+) -> Result<DnsPacket> {
+    let key = CacheKey::new(&question.name, question.query_type);
+    view.inflight
+        .resolve(key, || resolve_upstream(view, metrics, question))
+        .await
+}
+
+async fn resolve_upstream(
+    view: &View,
+    metrics: &Metrics,
+    question: &DnsQuestion,
+) -> Result<DnsPacket> {
+    let rule = view.rules.find(&question.name);
+    debug!(
+        "Resolving {} with rule `{}` ({:?})",
+        question.name, rule.suffix, rule.mode
+    );
+
+    let mut result = Err(Error::ResolutionFailed(question.name.clone()));
+    for &upstream in rule.upstreams.iter() {
+        result = match rule.mode {
+            ResolveMode::Forward => {
+                let sent = Instant::now();
+                let result = lookup(&question.name, question.query_type, upstream).await;
+                metrics.upstream_rtt(upstream, sent.elapsed(), result.as_ref().err());
+                result.inspect(|packet| {
+                    // forwarders are trusted with any name
+                    view.cache.insert_response(question, packet, "")
+                })
+            }
+            ResolveMode::Recurse => {
+                recursive_lookup(
+                    &question.name,
+                    question.query_type,
+                    upstream,
+                    Some(&view.cache),
+                    Some(metrics),
+                    0,
+                )
+                .await
+            }
+        };
+        match result {
+            Ok(_) => break,
+            Err(ref e) => warn!(
+                "error resolving {} via {:?}: {}",
+                question.name, upstream, e
+            ),
+        }
+    }
+    result
+}
+
+/// Acknowledge a NOTIFY of RFC 1996, which triggers the refresh of a
+/// secondary zone if it comes from the primary.
+fn handle_notify(ctx: &Context, query_packet: &DnsPacket, from_addr: SocketAddr) -> DnsPacket {
+    let mut response_packet = DnsPacket {
+        questions: query_packet.questions.clone(),
+        ..DnsPacket::default()
+    };
+    let secondary = query_packet.questions.first().and_then(|question| {
+        ctx.secondaries
+            .iter()
+            .find(|s| s.spec.origin.eq_ignore_ascii_case(&question.name))
+    });
+
+    response_packet.header.rescode = match secondary {
+        Some(secondary) if secondary.spec.primary.ip() == from_addr.ip() => {
+            secondary.notify();
+            ResultCode::NOERROR
+        }
+        Some(secondary) => {
+            warn!(
+                "Ignoring NOTIFY of {} from {}",
+                secondary.spec.origin, from_addr
+            );
+            ResultCode::REFUSED
+        }
+        None => ResultCode::NOTAUTH,
+    };
+    response_packet.header = DnsHeader {
+        id: query_packet.header.id,
+        opcode: OPCODE_NOTIFY,
+        authoritative_answer: response_packet.header.rescode == ResultCode::NOERROR,
+        response: true,
+        ..response_packet.header
+    };
+    response_packet.update_header_counts();
+    response_packet
+}
+
+/// Answer a name out of the local zones, rewritten by the response policy
+/// zones if the name or its resolved answer triggers them. `None` if the
+/// policy is to drop the query.
+async fn resolve_with_policy(
+    ctx: &Arc<Context>,
+    view: &Arc<View>,
+    question: DnsQuestion,
+    from_addr: SocketAddr,
+) -> Option<DnsPacket> {
+    // the answer is still checked against the zones before the one the name
+    // triggers, unless there are none
+    let (zones, query_action) = match ctx.rpz.check_query(&question.name) {
+        Some((0, action)) if *action != PolicyAction::PassThru => {
+            info!("Rewrote {} for {} by policy", question.name, from_addr);
+            return rewrite(ctx, view, question, action).await;
+        }
+        Some((index, action)) => (index, Some(action)),
+        None => (ctx.rpz.len(), None),
+    };
+
+    let mut response_packet = DnsPacket::default();
+    match resolve(ctx, view, &question).await {
+        Ok(packet) => {
+            let mut ns_names = view.cache.closest_name_servers(&question.name);
+            ns_names.extend(packet.authorities.iter().filter_map(|r| match r {
+                DnsRecord::NS { host, .. } => Some(host.clone()),
+                _ => None,
+            }));
+            let action = ctx
+                .rpz
+                .check_response(&packet, &ns_names, zones)
+                .or(query_action);
+            match action {
+                None | Some(PolicyAction::PassThru) => {}
+                Some(action) => {
+                    info!("Rewrote {} for {} by policy", question.name, from_addr);
+                    return rewrite(ctx, view, question, action).await;
                 }
-                _ => response_packet.header.rescode = ResultCode::SERVFAIL,
             }
+            let r = &mut response_packet;
+            r.questions.push(question);
+            r.header.rescode = packet.header.rescode;
+            r.answers = packet.answers;
+            r.authorities = packet.authorities;
+            r.resources = packet.resources;
+            r.update_header_counts();
+        }
+        Err(e) => {
+            ctx.metrics.error(&e);
+            if let Some(action) = query_action.filter(|a| **a != PolicyAction::PassThru) {
+                info!("Rewrote {} for {} by policy", question.name, from_addr);
+                return rewrite(ctx, view, question, action).await;
+            }
+            response_packet.header.rescode = ResultCode::SERVFAIL;
+        }
+    }
+    Some(response_packet)
+}
+
+/// Respond by a policy, following the CNAME of local data like that of a real
+/// answer.
+async fn rewrite(
+    ctx: &Arc<Context>,
+    view: &Arc<View>,
+    question: DnsQuestion,
+    action: &PolicyAction,
+) -> Option<DnsPacket> {
+    let response_packet = rpz::respond(action, question)?;
+    Some(follow_cname(ctx, view, response_packet, true).await)
+}
+
+/// Complete an answer ending with a CNAME to a name elsewhere, from the local
+/// data or zones, or by resolving it if the client may recurse.
+async fn follow_cname(
+    ctx: &Arc<Context>,
+    view: &Arc<View>,
+    mut packet: DnsPacket,
+    may_recurse: bool,
+) -> DnsPacket {
+    let query_type = packet.questions[0].query_type;
+    let target = match packet.answers.last() {
+        Some(DnsRecord::CNAME { host, .. }) if query_type != QueryType::CNAME => DnsQuestion {
+            name: host.clone(),
+            query_type,
+        },
+        _ => return packet,
+    };
+    let answers = match (view.local.lookup(&target), view.zones.find(&target.name)) {
+        (Some(local), _) => Some(local.answers),
+        (None, Some(zone)) => Some(zone.lookup(&target).answers),
+        (None, None) if may_recurse => resolve(ctx, view, &target).await.ok().map(|p| p.answers),
+        (None, None) => None,
+    };
+    packet.answers.extend(answers.into_iter().flatten());
+    packet.update_header_counts();
+    packet
+}
+
+/// Answer a query, whichever transport it arrived on. `None` if it is dropped
+/// by a response policy.
+async fn handle_query(
+    ctx: &Arc<Context>,
+    mut query_packet: DnsPacket,
+    from_addr: SocketAddr,
+) -> Option<DnsPacket> {
+    if query_packet.header.opcode == OPCODE_NOTIFY {
+        return Some(handle_notify(ctx, &query_packet, from_addr));
+    }
+    let mut response_packet = DnsPacket::default();
+    let view = ctx.view(from_addr.ip()).clone();
+    let may_recurse = acl::allows(&view.allow_recursion, from_addr.ip());
+
+    // assuming exactly 1 question
+    match query_packet.questions.pop() {
+        Some(question) if !acl::allows(&view.allow_query, from_addr.ip()) => {
+            info!("Refusing query of {} from {}", question.name, from_addr);
+            response_packet.questions.push(question);
+            response_packet.header.rescode = ResultCode::REFUSED;
+            response_packet.update_header_counts();
         }
+        Some(question) => match view.local.lookup(&question) {
+            Some(packet) => {
+                response_packet = follow_cname(ctx, &view, packet, may_recurse).await;
+            }
+            None => match view.zones.find(&question.name) {
+                Some(zone) => response_packet = zone.lookup(&question),
+                None if !may_recurse => {
+                    info!(
+                        "Refusing recursion for {} from {}",
+                        question.name, from_addr
+                    );
+                    response_packet.questions.push(question);
+                    response_packet.header.rescode = ResultCode::REFUSED;
+                    response_packet.update_header_counts();
+                }
+                None if ctx.blocker.is_blocked(&question.name) => {
+                    info!("Blocked {} for {}", question.name, from_addr);
+                    response_packet = ctx.blocker.block(question);
+                }
+                None => {
+                    response_packet = resolve_with_policy(ctx, &view, question, from_addr).await?;
+                }
+            },
+        },
         None => response_packet.header.rescode = ResultCode::FORMERR,
     }
 
     response_packet.header = DnsHeader {
         id: query_packet.header.id,
-        recursion_available: true,
+        recursion_available: may_recurse,
         response: true,
         ..response_packet.header
     };
+    Some(response_packet)
+}
 
-    let mut response_buf = DnsPacketBuf::new();
-    response_packet.write(&mut response_buf)?;
+/// Answer a zone transfer query with a sequence of messages. Over UDP, IXFR is
+/// answered with the current SOA record only, telling the client to retry
+/// over TCP as in RFC 1995, and AXFR is refused.
+fn handle_transfer(
+    ctx: &Context,
+    query_packet: &DnsPacket,
+    from_addr: SocketAddr,
+    tcp: bool,
+    key: Option<&str>,
+) -> Vec<DnsPacket> {
+    let question = &query_packet.questions[0];
+    let error = |rescode| {
+        let mut packet = DnsPacket {
+            questions: query_packet.questions.clone(),
+            ..DnsPacket::default()
+        };
+        packet.header = DnsHeader {
+            id: query_packet.header.id,
+            rescode,
+            response: true,
+            ..packet.header
+        };
+        packet.update_header_counts();
+        vec![packet]
+    };
 
-    let bytes = response_buf.peek_range(0, response_buf.pos)?;
-    socket.send_to(bytes, from_addr).await?;
-    info!("Sent response to {}: {:#?}", from_addr, response_packet);
+    let view = ctx.view(from_addr.ip());
+    if !key_allowed(&ctx.options.allow_transfer_keys, key)
+        && !acl::allows(&view.allow_transfer, from_addr.ip())
+    {
+        warn!("Refusing transfer of {} to {}", question.name, from_addr);
+        return error(ResultCode::REFUSED);
+    }
+    let zone = match view.zones.find(&question.name) {
+        Some(zone) if zone.origin.eq_ignore_ascii_case(&question.name) => zone,
+        _ => return error(ResultCode::NOTAUTH),
+    };
 
-    Ok(())
+    let records = match (tcp, question.query_type) {
+        (true, _) => transfer_records(&zone, query_packet),
+        (false, QueryType::IXFR) => vec![zone.soa().unwrap().clone()],
+        (false, _) => return error(ResultCode::REFUSED),
+    };
+    info!(
+        "Transferring {} records of {} to {}",
+        records.len(),
+        zone.origin,
+        from_addr
+    );
+    pack_messages(query_packet, records)
+}
+
+/// Whether a message signed with `key`, if any, is allowed by its name.
+fn key_allowed(keys: &[String], key: Option<&str>) -> bool {
+    key.is_some_and(|key| keys.iter().any(|k| k == key))
+}
+
+/// Apply a dynamic update of RFC 2136 to a zone loaded from a master file, and
+/// persist the change to its journal, or the master file once the journal is
+/// full, before serving the new version.
+async fn handle_update(
+    ctx: &Context,
+    message: &UpdateMessage,
+    from_addr: SocketAddr,
+    key: Option<&str>,
+) -> DnsPacket {
+    let origin = &message.zone.name;
+    if !key_allowed(&ctx.options.allow_update_keys, key)
+        && !acl::allows(&ctx.options.allow_update, from_addr.ip())
+    {
+        warn!("Refusing update of {} from {}", origin, from_addr);
+        return message.response(ResultCode::REFUSED);
+    }
+    let spec = match ctx
+        .options
+        .zones
+        .iter()
+        .find(|spec| spec.origin.eq_ignore_ascii_case(origin))
+    {
+        Some(spec) => spec,
+        None => return message.response(ResultCode::NOTAUTH),
+    };
+
+    let _guard = ctx.update_lock.lock().await;
+    if ctx.retired.load(Ordering::SeqCst) {
+        // the zone has been reloaded since, so the client should try again
+        return message.response(ResultCode::SERVFAIL);
+    }
+    let zones = &ctx.default_view().zones;
+    let zone = match zones.get(origin) {
+        Some(zone) => zone,
+        None => return message.response(ResultCode::NOTAUTH),
+    };
+    match apply_update(&zone, message) {
+        Ok(Some(zone)) => {
+            // off the runtime, with other updates waiting for the lock
+            let spec = spec.clone();
+            let saved = tokio::task::spawn_blocking(move || spec.save(zone));
+            let zone = match saved
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::from(e).into()))
+            {
+                Ok(zone) => zone,
+                Err(e) => {
+                    error!("error saving the zone {}: {}", origin, e);
+                    return message.response(ResultCode::SERVFAIL);
+                }
+            };
+            info!(
+                "Zone {} updated to serial {} by {}",
+                zone.origin,
+                zone.serial(),
+                from_addr
+            );
+            zones.insert(zone);
+            message.response(ResultCode::NOERROR)
+        }
+        Ok(None) => message.response(ResultCode::NOERROR),
+        Err(rescode) => {
+            info!(
+                "Rejected update of {} from {}: {:?}",
+                origin, from_addr, rescode
+            );
+            message.response(rescode)
+        }
+    }
+}
+
+/// Answer a message of any kind, with a sequence of responses for zone
+/// transfers over TCP, none for dropped queries, or a single one otherwise. Responses to a message
+/// signed with TSIG are signed in turn by the returned signer.
+async fn handle_message(
+    ctx: &Arc<Context>,
+    mut query_buf: DnsPacketBuf,
+    from_addr: SocketAddr,
+    tcp: bool,
+) -> Result<(Vec<DnsPacket>, Option<Signer>)> {
+    let header = DnsHeader::read_from(&mut query_buf)?;
+    let _in_flight = ctx.metrics.start_query();
+    let transport = if tcp { Transport::Tcp } else { Transport::Udp };
+    let signer = match tsig::verify_request(&ctx.options.tsig_keys, &query_buf.buf) {
+        Verified::Unsigned => None,
+        Verified::Valid(signer) => Some(signer),
+        Verified::Invalid(signer) => {
+            warn!(
+                "TSIG verification of message from {} failed with {}",
+                from_addr,
+                tsig::error_name(signer.error())
+            );
+            let mut response_packet = DnsPacket::default();
+            for _ in 0..header.questions {
+                response_packet
+                    .questions
+                    .push(DnsQuestion::read_from(&mut query_buf)?);
+            }
+            response_packet.header = DnsHeader {
+                id: header.id,
+                opcode: header.opcode,
+                response: true,
+                rescode: ResultCode::NOTAUTH,
+                ..DnsHeader::default()
+            };
+            response_packet.update_header_counts();
+            ctx.metrics.query(
+                header.opcode,
+                response_packet
+                    .questions
+                    .first()
+                    .map_or(QueryType::Unknown, |q| q.query_type),
+                Some(ResultCode::NOTAUTH),
+                transport,
+            );
+            return Ok((vec![response_packet], Some(signer)));
+        }
+    };
+    let key = signer.as_ref().map(Signer::key_name);
+
+    query_buf.seek(0);
+    let (query_type, response_packets) = if header.opcode == OPCODE_UPDATE {
+        let response = match UpdateMessage::read_from(&mut query_buf) {
+            Ok(message) => handle_update(ctx, &message, from_addr, key).await,
+            // RFC 2136 section 3.1
+            Err(e) => {
+                warn!("Malformed update from {}: {}", from_addr, e);
+                DnsPacket {
+                    header: DnsHeader {
+                        id: header.id,
+                        opcode: header.opcode,
+                        response: true,
+                        rescode: ResultCode::FORMERR,
+                        ..DnsHeader::default()
+                    },
+                    ..DnsPacket::default()
+                }
+            }
+        };
+        (QueryType::SOA, vec![response])
+    } else {
+        let query_packet = DnsPacket::read_from(&mut query_buf)?;
+        let query_type = query_packet
+            .questions
+            .first()
+            .map_or(QueryType::Unknown, |q| q.query_type);
+        if is_transfer_query(&query_packet) {
+            let responses = handle_transfer(ctx, &query_packet, from_addr, tcp, key);
+            (query_type, responses)
+        } else {
+            let responses = handle_query(ctx, query_packet, from_addr)
+                .await
+                .into_iter()
+                .collect();
+            (query_type, responses)
+        }
+    };
+    ctx.metrics.query(
+        header.opcode,
+        query_type,
+        response_packets.first().map(|p| p.header.rescode),
+        transport,
+    );
+    Ok((response_packets, signer))
+}
+
+/// The response with the header and the question only, and the TC bit set.
+fn truncated(packet: &DnsPacket) -> DnsPacket {
+    let mut truncated = DnsPacket {
+        header: packet.header.clone(),
+        questions: packet.questions.clone(),
+        ..DnsPacket::default()
+    };
+    truncated.header.truncated_message = true;
+    truncated.update_header_counts();
+    truncated
+}
+
+/// Serialize a response into at most `size` bytes, signed if a signer is
+/// given. If it does not fit, only the header and the question are sent, with
+/// the TC bit set.
+fn write_response(
+    packet: &DnsPacket,
+    size: usize,
+    mut signer: Option<&mut Signer>,
+) -> Result<Vec<u8>> {
+    fn write(
+        packet: &DnsPacket,
+        buf: &mut DnsPacketBuf,
+        signer: Option<&mut Signer>,
+    ) -> Result<()> {
+        packet.write(buf)?;
+        match signer {
+            Some(signer) => signer.sign(buf),
+            None => Ok(()),
+        }
+    }
+
+    let mut buf = DnsPacketBuf::with_size(size);
+    match write(packet, &mut buf, signer.as_deref_mut()) {
+        Ok(_) => {}
+        Err(Error::EndOfBuffer(_)) => {
+            buf = DnsPacketBuf::with_size(size);
+            write(&truncated(packet), &mut buf, signer)?;
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(buf.buf[0..buf.pos].to_vec())
 }
 
-async fn prepare_query(
+async fn handle_udp_query(
+    ctx: Arc<Context>,
     socket: Arc<UdpSocket>,
-    remote_server: (Ipv4Addr, u16),
-    proxy: bool,
+    query_buf: DnsPacketBuf,
+    from_addr: SocketAddr,
+    local_addr: Option<LocalAddr>,
 ) -> Result<()> {
+    let (mut response_packets, mut signer) =
+        handle_message(&ctx, query_buf, from_addr, false).await?;
+    if response_packets.is_empty() {
+        debug!("Dropped query from {} by policy", from_addr);
+        return Ok(());
+    }
+    let mut response_packet = response_packets.remove(0);
+    match ctx.rate_limiter.check(from_addr.ip(), &response_packet) {
+        Action::Send => {}
+        Action::Slip => {
+            ctx.metrics.rate_limited(true);
+            response_packet = truncated(&response_packet);
+        }
+        Action::Drop => {
+            ctx.metrics.rate_limited(false);
+            debug!("Dropped response to {}", from_addr);
+            return Ok(());
+        }
+    }
+
+    let bytes = write_response(&response_packet, UDP_PACKET_SIZE, signer.as_mut())?;
+    socket.send_to(&bytes, from_addr, local_addr).await?;
+    info!("Sent response to {}: {:#?}", from_addr, response_packet);
+
+    Ok(())
+}
+
+async fn prepare_query(server: Arc<Server>, socket: Arc<UdpSocket>) -> Result<()> {
     let mut query_buf = DnsPacketBuf::new();
-    let (_, from_addr) = socket.recv_from(&mut query_buf.buf).await?;
+    let (len, from_addr, local_addr) = socket.recv_from(&mut query_buf.buf).await?;
+    query_buf.buf.truncate(len);
 
+    let ctx = server.context();
+    let pending = server.pending.start();
     tokio::spawn(async move {
-        if let Err(e) = handle_query(socket, query_buf, from_addr, remote_server, proxy).await {
+        let metrics = ctx.metrics.clone();
+        if let Err(e) = handle_udp_query(ctx, socket, query_buf, from_addr, local_addr).await {
+            metrics.error(&e);
             error!("error {}", e);
         }
+        drop(pending);
+    });
+
+    Ok(())
+}
+
+/// Serve the length-prefixed queries of a TCP connection. Queries may be
+/// pipelined, up to `MAX_PIPELINED_QUERIES` being answered at once, and each
+/// response is sent as soon as it is ready, so they can be out of order. Each
+/// query is answered by the context current when it is read.
+async fn handle_tcp_connection(
+    server: Arc<Server>,
+    stream: TcpStream,
+    from_addr: SocketAddr,
+) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(16);
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(bytes) = receiver.recv().await {
+            writer.write_u16(bytes.len() as u16).await?;
+            writer.write_all(&bytes).await?;
+        }
+        Ok::<_, std::io::Error>(())
     });
 
+    let queries = Arc::new(Semaphore::new(MAX_PIPELINED_QUERIES));
+    let mut stopping = server.stopping.clone();
+    loop {
+        // in case the server stopped before the connection was accepted
+        if *stopping.borrow() {
+            break;
+        }
+        let ctx = server.context();
+        let read = tokio::select! {
+            read = timeout(ctx.options.tcp_idle_timeout, reader.read_u16()) => read,
+            _ = stopping.changed() => break,
+        };
+        let len = match read {
+            Ok(Ok(len)) => len as usize,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                debug!("Closing idle connection from {}", from_addr);
+                break;
+            }
+        };
+        let mut query_buf = DnsPacketBuf::with_size(len);
+        match timeout(
+            ctx.options.tcp_idle_timeout,
+            reader.read_exact(&mut query_buf.buf),
+        )
+        .await
+        {
+            Ok(read) => read?,
+            Err(_) => {
+                debug!("Closing stalled connection from {}", from_addr);
+                break;
+            }
+        };
+
+        // stop reading until one of the pipelined queries is answered
+        let permit = tokio::select! {
+            permit = queries.clone().acquire_owned() => permit,
+            _ = stopping.changed() => break,
+        };
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let (response_packets, mut signer) =
+                match handle_message(&ctx, query_buf, from_addr, true).await {
+                    Ok(responses) => responses,
+                    Err(e) => {
+                        ctx.metrics.error(&e);
+                        return error!("error {}", e);
+                    }
+                };
+            for response_packet in response_packets.iter() {
+                match write_response(response_packet, TCP_PACKET_SIZE, signer.as_mut()) {
+                    // the connection may have been closed by the client
+                    Ok(bytes) => {
+                        if sender.send(bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        ctx.metrics.error(&e);
+                        return error!("error {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    // pending responses are still sent after the reading side is done
+    drop(sender);
+    writer_task.await.unwrap_or(Ok(()))?;
     Ok(())
 }
 
-pub async fn run(remote_server: (Ipv4Addr, u16), listen_port: u16, proxy: bool) -> Result<()> {
-    let server_socket = Arc::new(UdpSocket::bind(("0.0.0.0", listen_port)).await?);
-    // let forward_server = Arc::new(forward_server);
-    println!("Running on :{}", listen_port);
+async fn accept_tcp_connections(server: Arc<Server>, listener: TcpListener) {
+    let max_connections = server.context().options.max_tcp_connections;
+    let connections = Arc::new(Semaphore::new(max_connections));
+    let mut stopping = server.stopping.clone();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stopping.changed() => break,
+        };
+        let (stream, from_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("error {}", e);
+                continue;
+            }
+        };
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("Too many TCP connections, refusing {}", from_addr);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        let pending = server.pending.start();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp_connection(server.clone(), stream, from_addr).await {
+                server.context().metrics.error(&e);
+                error!("error {}", e);
+            }
+            drop(permit);
+            drop(pending);
+        });
+    }
+}
+
+fn bind_socket(addr: SocketAddr, socket_type: Type) -> Result<Socket> {
+    let domain = if addr.is_ipv6() {
+        Domain::ipv6()
+    } else {
+        Domain::ipv4()
+    };
+    let socket = Socket::new(domain, socket_type, None)?;
+    if addr.is_ipv6() {
+        // so that `[::]` and `0.0.0.0` can be listened on at the same time
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+async fn serve_udp(server: Arc<Server>, socket: Arc<UdpSocket>) {
+    let mut stopping = server.stopping.clone();
     loop {
-        match prepare_query(server_socket.clone(), remote_server, proxy).await {
+        let result = tokio::select! {
+            result = prepare_query(server.clone(), socket.clone()) => result,
+            _ = stopping.changed() => break,
+        };
+        match result {
             Ok(_) => {}
             Err(e) => {
+                server.context().metrics.error(&e);
                 error!("error {}", e);
             }
         };
     }
 }
+
+/// Load the zones and data of a view, which takes the ACLs of the server
+/// where it has none. The cache of the same view before a reload is kept.
+fn load_view(
+    options: &ServerOptions,
+    view: &ViewOptions,
+    previous: Option<&Context>,
+) -> Result<View> {
+    let default_rule = ForwardRule {
+        suffix: String::new(),
+        mode: if options.proxy {
+            ResolveMode::Forward
+        } else {
+            ResolveMode::Recurse
+        },
+        upstreams: vec![options.remote_server],
+    };
+    let mut zones = Vec::new();
+    for spec in view.zones.iter() {
+        let zone = spec.load()?;
+        match view.name.as_str() {
+            DEFAULT_VIEW => println!("Loaded zone {} from {}", zone.origin, spec.path),
+            name => println!(
+                "Loaded zone {} of view {} from {}",
+                zone.origin, name, spec.path
+            ),
+        }
+        zones.push(zone);
+    }
+    let or_server = |acl: &Vec<Cidr>, server: &Vec<Cidr>| {
+        if acl.is_empty() {
+            server.clone()
+        } else {
+            acl.clone()
+        }
+    };
+    let cache = previous
+        .and_then(|ctx| ctx.views.iter().find(|v| v.name == view.name))
+        .map(|previous| previous.cache.clone())
+        .unwrap_or_else(|| {
+            Arc::new(Cache::new(options.cache_size).serve_stale(options.stale_window))
+        });
+
+    Ok(View {
+        name: view.name.clone(),
+        match_clients: view.match_clients.clone(),
+        allow_query: or_server(&view.allow_query, &options.allow_query),
+        allow_recursion: or_server(&view.allow_recursion, &options.allow_recursion),
+        allow_transfer: or_server(&view.allow_transfer, &options.allow_transfer),
+        local: LocalData::new(view.local_data.clone(), view.local_zones.clone()),
+        zones: Catalog::new(zones),
+        rules: ForwardRules::new(view.rules.clone(), default_rule),
+        cache,
+        inflight: InFlight::new(),
+    })
+}
+
+/// Load everything the server answers from, without binding any socket. On a
+/// reload, the secondary zones still configured are served from the previous
+/// context until they are transferred again.
+fn load_context(options: ServerOptions, previous: Option<&Context>) -> Result<Context> {
+    let default_view = ViewOptions {
+        name: DEFAULT_VIEW.into(),
+        match_clients: acl::any(),
+        local_data: options.local_data.clone(),
+        local_zones: options.local_zones.clone(),
+        zones: options.zones.clone(),
+        rules: options.rules.clone(),
+        ..ViewOptions::default()
+    };
+    let mut views = Vec::new();
+    for view in options.views.iter().chain(std::iter::once(&default_view)) {
+        views.push(Arc::new(load_view(&options, view, previous)?));
+    }
+
+    let mut secondaries = Vec::new();
+    for spec in options.secondaries.iter() {
+        let key = match spec.key {
+            Some(ref name) => Some(
+                options
+                    .tsig_keys
+                    .iter()
+                    .find(|k| k.name.eq_ignore_ascii_case(name))
+                    .cloned()
+                    .ok_or_else(|| {
+                        Error::InvalidTsigKey(format!(
+                            "no key `{}` for secondary zone {}",
+                            name, spec.origin
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+        secondaries.push(Secondary::new(spec.clone(), key));
+    }
+    if let Some(previous) = previous {
+        let zones = &views.last().unwrap().zones;
+        for secondary in secondaries.iter() {
+            let origin = &secondary.spec.origin;
+            if let Some(zone) = previous.default_view().zones.get(origin) {
+                if zones.get(origin).is_none() {
+                    zones.insert(zone);
+                }
+            }
+        }
+    }
+
+    Ok(Context {
+        views,
+        secondaries,
+        rate_limiter: RateLimiter::new(options.rate_limit.clone()),
+        blocker: Blocker::new(
+            options.blocklists.clone(),
+            options.allowlists.clone(),
+            options.block_response,
+        )?,
+        rpz: Rpz::load(&options.rpz)?,
+        update_lock: previous
+            .map(|ctx| ctx.update_lock.clone())
+            .unwrap_or_default(),
+        retired: AtomicBool::new(false),
+        metrics: previous.map(|ctx| ctx.metrics.clone()).unwrap_or_default(),
+        options,
+    })
+}
+
+/// Check that the zones, keys and lists of `options` all load.
+pub fn check(options: ServerOptions) -> Result<()> {
+    load_context(options, None).map(|_| ())
+}
+
+/// Spawn `task`, which is cancelled once the sender of `cancelled` is dropped.
+fn spawn_cancellable<F>(mut cancelled: watch::Receiver<()>, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    // rather than `JoinHandle::abort`, which panics outside of the runtime
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = task => {}
+            _ = cancelled.changed() => {}
+        }
+    });
+}
+
+/// Spawn the tasks working for `ctx` in the background, which are cancelled
+/// once the returned handle is dropped.
+fn spawn_background(ctx: &Arc<Context>) -> watch::Sender<()> {
+    let (handle, cancelled) = watch::channel(());
+    if !ctx.options.blocklists.is_empty() && ctx.options.blocklist_reload_interval.as_secs() > 0 {
+        let ctx = ctx.clone();
+        spawn_cancellable(cancelled.clone(), async move {
+            ctx.blocker
+                .watch(ctx.options.blocklist_reload_interval)
+                .await
+        });
+    }
+    for index in 0..ctx.secondaries.len() {
+        let ctx = ctx.clone();
+        spawn_cancellable(cancelled.clone(), async move {
+            ctx.secondaries[index].run(&ctx.default_view().zones).await
+        });
+    }
+    handle
+}
+
+/// Serve until SIGTERM or SIGINT, reloading the configuration by `reload` on
+/// SIGHUP. The sockets and the caches are kept across reloads, so changes of
+/// the addresses to listen on and of the cache options need a restart.
+pub async fn run(options: ServerOptions, reload: impl Fn() -> Result<ServerOptions>) -> Result<()> {
+    let server = Arc::new(Server::new(load_context(options, None)?));
+    let ctx = server.context();
+
+    // bind everything first, so that a bad address fails the startup
+    let mut sockets = Vec::new();
+    for &addr in ctx.options.listen.iter() {
+        let udp_socket = bind_socket(addr, Type::dgram())?.into_udp_socket();
+        let tcp_socket = bind_socket(addr, Type::stream())?;
+        tcp_socket.listen(1024)?;
+        sockets.push((
+            UdpSocket::from_std(udp_socket)?,
+            TcpListener::from_std(tcp_socket.into_tcp_listener())?,
+        ));
+        println!("Running on {}", addr);
+    }
+    let metrics_listener = match ctx.options.metrics {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            println!("Serving metrics on http://{}/metrics", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    let mut background = spawn_background(&ctx);
+    let (metrics_task, cancelled) = watch::channel(());
+    if let Some(listener) = metrics_listener {
+        let server = server.clone();
+        spawn_cancellable(
+            cancelled,
+            metrics::serve(listener, move || server.render_metrics()),
+        );
+    }
+    let mut tasks = Vec::new();
+    for (udp_socket, listener) in sockets {
+        tasks.push(tokio::spawn(accept_tcp_connections(
+            server.clone(),
+            listener,
+        )));
+        tasks.push(tokio::spawn(serve_udp(
+            server.clone(),
+            Arc::new(udp_socket),
+        )));
+    }
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {}
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+        let reloaded = match reload() {
+            Ok(options) => server.reload(options).await,
+            Err(e) => Err(e),
+        };
+        match reloaded {
+            Ok(ctx) => {
+                background = spawn_background(&ctx);
+                println!("Reloaded the configuration");
+            }
+            Err(e) => error!("error reloading, keeping the configuration: {}", e),
+        }
+    }
+
+    println!("Shutting down");
+    server.stop();
+    for task in tasks {
+        if let Err(e) = task.await {
+            error!("error in a serving task: {}", e);
+        }
+    }
+    drop(background);
+    drop(metrics_task);
+    let shutdown_timeout = server.context().options.shutdown_timeout;
+    if timeout(shutdown_timeout, server.pending.drained())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Shutting down with {} queries or connections unfinished",
+            server.pending.count()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::dns_packet::{DnsRecord, QueryType};
+    use crate::update::UpdateRecord;
+    use crate::zone_file::parse_zone;
+
+    #[test]
+    fn truncate_large_response() {
+        let mut packet = DnsPacket::example("bugen.dev", QueryType::A);
+        for i in 0..40 {
+            packet.answers.push(DnsRecord::A {
+                name: "bugen.dev".into(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 300,
+            });
+        }
+        packet.update_header_counts();
+
+        let bytes = write_response(&packet, TCP_PACKET_SIZE, None).unwrap();
+        assert!(bytes.len() > UDP_PACKET_SIZE);
+
+        let bytes = write_response(&packet, UDP_PACKET_SIZE, None).unwrap();
+        let truncated = DnsPacket::read_from(&mut DnsPacketBuf::from_bytes(&bytes)).unwrap();
+        assert!(truncated.header.truncated_message);
+        assert_eq!(truncated.questions, packet.questions);
+        assert!(truncated.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn count_rejected_tsig() {
+        let keys = tsig::parse_keys(include_str!("../res/tsig.key")).unwrap();
+        let mut options = ServerConfig::default().into_options().unwrap();
+        options.tsig_keys = vec![keys[0].clone()];
+        let ctx = Arc::new(load_context(options, None).unwrap());
+
+        // signed with a key the server does not know
+        let query = DnsPacket::example("bugen.dev", QueryType::A);
+        let mut buf = DnsPacketBuf::new();
+        query.write(&mut buf).unwrap();
+        Signer::new(&keys[1]).sign(&mut buf).unwrap();
+        let query_buf = DnsPacketBuf::from_bytes(&buf.buf[..buf.pos]);
+        let from_addr = "127.0.0.1:5353".parse().unwrap();
+        let (responses, _) = handle_message(&ctx, query_buf, from_addr, false)
+            .await
+            .unwrap();
+        assert_eq!(responses[0].header.rescode, ResultCode::NOTAUTH);
+        assert!(ctx.metrics.render(&[]).contains(
+            "dnser_queries_total{opcode=\"QUERY\",type=\"A\",rcode=\"NOTAUTH\",transport=\"udp\"} 1\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn keep_updates_across_reloads() {
+        let dir = std::env::temp_dir().join(format!("dnser-update-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bugen.dev.zone");
+        std::fs::copy("res/bugen.dev.zone", &path).unwrap();
+        let options = || {
+            let mut options = ServerConfig::default().into_options().unwrap();
+            options.zones = vec![format!("bugen.dev={}", path.display()).parse().unwrap()];
+            options.allow_update = vec!["127.0.0.0/8".parse().unwrap()];
+            options
+        };
+        let server = Server::new(load_context(options(), None).unwrap());
+
+        let record = |text: &str| parse_zone(text, "bugen.dev").unwrap().remove(0);
+        let from_addr = "127.0.0.1:5353".parse().unwrap();
+        for update in [
+            UpdateRecord::add(record("new 300 A 10.0.0.9")),
+            UpdateRecord::add(record("new 300 A 10.0.0.10")),
+            UpdateRecord::delete(record("www 0 A 10.0.0.3")),
+        ] {
+            let mut message = UpdateMessage::new("bugen.dev");
+            message.updates.push(update);
+            let response = handle_update(&server.context(), &message, from_addr, None).await;
+            assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        }
+
+        let ctx = server.reload(options()).await.unwrap();
+        let zone = ctx.default_view().zones.get("bugen.dev").unwrap();
+        assert_eq!(zone.serial(), 2020110104);
+        assert_eq!(zone.journal().diffs().len(), 3);
+        let answers = |name: &str| {
+            let question = DnsQuestion {
+                name: name.into(),
+                query_type: QueryType::A,
+            };
+            zone.lookup(&question).answers
+        };
+        assert_eq!(answers("new.bugen.dev").len(), 2);
+        assert_eq!(answers("www.bugen.dev").len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_malformed_update() {
+        let ctx =
+            Arc::new(load_context(ServerConfig::default().into_options().unwrap(), None).unwrap());
+
+        // with an update of an unknown type
+        let message = UpdateMessage::new("bugen.dev");
+        let mut buf = DnsPacketBuf::new();
+        message.write(&mut buf).unwrap();
+        buf.set_u16(8, 1).unwrap();
+        buf.write_name_simple("www.bugen.dev").unwrap();
+        buf.write_u16(65280).unwrap();
+        buf.write_u16(1).unwrap();
+        buf.write_u32(300).unwrap();
+        buf.write_u16(0).unwrap();
+
+        let query_buf = DnsPacketBuf::from_bytes(&buf.buf[..buf.pos]);
+        let from_addr = "127.0.0.1:5353".parse().unwrap();
+        let (responses, _) = handle_message(&ctx, query_buf, from_addr, false)
+            .await
+            .unwrap();
+        assert_eq!(responses[0].header.id, message.header.id);
+        assert_eq!(responses[0].header.opcode, OPCODE_UPDATE);
+        assert_eq!(responses[0].header.rescode, ResultCode::FORMERR);
+    }
+
+    #[tokio::test]
+    async fn reload_context() {
+        let mut options = ServerConfig::default().into_options().unwrap();
+        options.views = vec!["lab=10.0.0.0/8".parse().unwrap()];
+        let server = Server::new(load_context(options, None).unwrap());
+        let previous = server.context();
+        let question = DnsQuestion {
+            name: "bugen.dev".into(),
+            query_type: QueryType::A,
+        };
+        previous.views[0].cache.insert_records(&[DnsRecord::A {
+            name: "bugen.dev".into(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 300,
+        }]);
+        assert!(previous.views[0].cache.lookup(&question).is_some());
+
+        let mut options = ServerConfig::default().into_options().unwrap();
+        options.views = vec!["lab=10.0.0.0/16".parse().unwrap()];
+        options.zones = vec!["bugen.dev=res/bugen.dev.zone".parse().unwrap()];
+        let ctx = server.reload(options).await.unwrap();
+        assert!(previous.retired.load(Ordering::SeqCst));
+        assert!(Arc::ptr_eq(&server.context(), &ctx));
+        assert!(ctx.views[0].cache.lookup(&question).is_some());
+        assert!(ctx.default_view().zones.get("bugen.dev").is_some());
+        assert_eq!(ctx.view("10.1.0.1".parse().unwrap()).name, DEFAULT_VIEW);
+
+        // a bad configuration keeps the current one
+        let mut options = ServerConfig::default().into_options().unwrap();
+        options.zones = vec!["bugen.dev=res/nonexistent.zone".parse().unwrap()];
+        assert!(server.reload(options).await.is_err());
+        assert!(Arc::ptr_eq(&server.context(), &ctx));
+    }
+
+    #[tokio::test]
+    async fn drain_pending() {
+        let pending = Arc::new(Pending::default());
+        pending.drained().await;
+        let first = pending.start();
+        let second = pending.start();
+        assert_eq!(pending.count(), 2);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(first);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(second);
+        });
+        timeout(Duration::from_secs(1), pending.drained())
+            .await
+            .unwrap();
+        assert_eq!(pending.count(), 0);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::dns_packet::{DnsHeader, DnsPacket, DnsRecord, QueryType};
+use crate::dns_packet_buf::{DnsPacketBuf, TCP_PACKET_SIZE};
+use crate::journal::{serial_lt, soa_serial};
+use crate::zone::Zone;
+use log::*;
+use std::iter::once;
+
+/// Records are packed into messages up to this size, far below the limit of
+/// TCP, leaving room for the header and the question.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+pub fn is_transfer_query(packet: &DnsPacket) -> bool {
+    packet
+        .questions
+        .first()
+        .is_some_and(|q| q.query_type == QueryType::AXFR || q.query_type == QueryType::IXFR)
+}
+
+/// The records answering a zone transfer query, as in RFC 5936 for AXFR and
+/// RFC 1995 for IXFR. IXFR falls back to a full transfer if the journal does
+/// not reach back to the serial of the client.
+pub fn transfer_records(zone: &Zone, query: &DnsPacket) -> Vec<DnsRecord> {
+    let soa = zone.soa().unwrap().clone();
+    let is_ixfr = query.questions[0].query_type == QueryType::IXFR;
+    let client_serial = query.authorities.iter().find_map(|r| soa_serial(r).ok());
+
+    if let (true, Some(serial)) = (is_ixfr, client_serial) {
+        if !serial_lt(serial, zone.serial()) {
+            // already up to date
+            return vec![soa];
+        }
+        if let Some(diffs) = zone.journal().since(serial) {
+            debug!(
+                "Incremental transfer of {} from serial {} in {} diffs",
+                zone.origin,
+                serial,
+                diffs.len()
+            );
+            return once(&soa)
+                .chain(diffs.iter().flat_map(|d| d.records()))
+                .chain(once(&soa))
+                .cloned()
+                .collect();
+        }
+    }
+
+    once(&soa)
+        .chain(zone.records())
+        .chain(once(&soa))
+        .cloned()
+        .collect()
+}
+
+/// Split the records of a zone transfer into messages answering `query`.
+pub fn pack_messages(query: &DnsPacket, records: Vec<DnsRecord>) -> Vec<DnsPacket> {
+    let new_message = || DnsPacket {
+        header: DnsHeader {
+            id: query.header.id,
+            recursion_desired: query.header.recursion_desired,
+            authoritative_answer: true,
+            response: true,
+            ..DnsHeader::default()
+        },
+        questions: query.questions.clone(),
+        ..DnsPacket::default()
+    };
+
+    let mut messages = vec![new_message()];
+    let mut size = 0;
+    for record in records {
+        let mut buf = DnsPacketBuf::with_size(TCP_PACKET_SIZE);
+        let len = match record.write(&mut buf) {
+            Ok(_) => buf.pos,
+            Err(e) => {
+                warn!("error writing {} in transfer: {}", record, e);
+                continue;
+            }
+        };
+        if size + len > MAX_MESSAGE_SIZE && !messages.last().unwrap().answers.is_empty() {
+            messages.push(new_message());
+            size = 0;
+        }
+        messages.last_mut().unwrap().answers.push(record);
+        size += len;
+    }
+
+    for message in messages.iter_mut() {
+        message.update_header_counts();
+    }
+    messages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns_packet::DnsQuestion;
+    use crate::journal::Journal;
+    use crate::zone_file::parse_zone;
+
+    fn zone(serial: u32, extra: &str) -> Zone {
+        let text = format!(
+            "$TTL 60\n@ SOA ns admin {} 2 3 4 5\n@ NS ns\nns A 10.0.0.1\n{}",
+            serial, extra
+        );
+        Zone::new("bugen.dev", parse_zone(&text, "bugen.dev").unwrap()).unwrap()
+    }
+
+    fn query(query_type: QueryType, serial: Option<u32>) -> DnsPacket {
+        let mut packet = DnsPacket::example("bugen.dev", query_type);
+        if let Some(serial) = serial {
+            packet
+                .authorities
+                .push(zone(serial, "").soa().unwrap().clone());
+        }
+        packet.update_header_counts();
+        packet
+    }
+
+    #[test]
+    fn axfr() {
+        let zone = zone(1, "www A 10.0.0.2");
+        assert!(is_transfer_query(&query(QueryType::AXFR, None)));
+
+        let records = transfer_records(&zone, &query(QueryType::AXFR, None));
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].query_type(), QueryType::SOA);
+        assert_eq!(records[4].query_type(), QueryType::SOA);
+    }
+
+    #[test]
+    fn ixfr() {
+        let old = zone(1, "www A 10.0.0.2");
+        let new = zone(2, "www A 10.0.0.3");
+        let mut journal = Journal::new();
+        journal.append(old.diff(&new).unwrap()).unwrap();
+        let new = new.with_journal(journal).unwrap();
+
+        // incremental
+        let records = transfer_records(&new, &query(QueryType::IXFR, Some(1)));
+        let serials: Vec<_> = records
+            .iter()
+            .filter(|r| r.query_type() == QueryType::SOA)
+            .map(|r| soa_serial(r).unwrap())
+            .collect();
+        assert_eq!(serials, vec![2, 1, 2, 2]);
+        assert_eq!(records.len(), 6);
+
+        // up to date
+        let records = transfer_records(&new, &query(QueryType::IXFR, Some(2)));
+        assert_eq!(records.len(), 1);
+
+        // out of the journal, or without the serial of the client
+        assert_eq!(
+            transfer_records(&new, &query(QueryType::IXFR, Some(0))).len(),
+            5
+        );
+        assert_eq!(
+            transfer_records(&new, &query(QueryType::IXFR, None)).len(),
+            5
+        );
+    }
+
+    #[test]
+    fn split_into_messages() {
+        let records: Vec<_> = (0..2000)
+            .map(|i| DnsRecord::A {
+                name: format!("host{}.bugen.dev", i),
+                addr: "10.0.0.1".parse().unwrap(),
+                ttl: 60,
+            })
+            .collect();
+        let query = query(QueryType::AXFR, None);
+        let messages = pack_messages(&query, records);
+
+        assert!(messages.len() > 1);
+        assert_eq!(
+            messages.iter().map(|m| m.answers.len()).sum::<usize>(),
+            2000
+        );
+        for message in messages {
+            assert_eq!(message.header.id, query.header.id);
+            assert_eq!(
+                message.questions,
+                vec![DnsQuestion {
+                    name: "bugen.dev".into(),
+                    query_type: QueryType::AXFR
+                }]
+            );
+            let mut buf = DnsPacketBuf::with_size(TCP_PACKET_SIZE);
+            message.write(&mut buf).unwrap();
+        }
+    }
+}
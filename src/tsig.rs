@@ -0,0 +1,653 @@
+use crate::dns_packet::{DnsHeader, DnsQuestion, DnsRecord, QueryType, CLASS_ANY};
+use crate::dns_packet_buf::{DnsPacketBuf, TCP_PACKET_SIZE};
+use crate::error::{Error, Result};
+use hmac::{Hmac, Mac};
+use num_traits::ToPrimitive;
+use sha2::{Sha256, Sha512};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+/// Seconds of clock skew allowed between the signer and the verifier.
+const FUDGE: u16 = 300;
+/// Messages of a stream that may go without a signature in a row, as in
+/// RFC 8945, section 5.3.1.
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+pub fn error_name(error: u16) -> String {
+    match error {
+        0 => "NOERROR".to_owned(),
+        BADSIG => "BADSIG".to_owned(),
+        BADKEY => "BADKEY".to_owned(),
+        BADTIME => "BADTIME".to_owned(),
+        n => n.to_string(),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256",
+            Algorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Ok(Algorithm::HmacSha256),
+            "hmac-sha512" => Ok(Algorithm::HmacSha512),
+            _ => Err(Error::InvalidTsigKey(format!(
+                "unsupported algorithm `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+/// A shared secret for signing messages with TSIG.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: Algorithm,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            Algorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Algorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Check `mac` in constant time.
+    fn verify_mac(&self, data: &[u8], mac: &[u8]) -> bool {
+        match self.algorithm {
+            Algorithm::HmacSha256 => {
+                let mut expected = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+                expected.update(data);
+                expected.verify_slice(mac).is_ok()
+            }
+            Algorithm::HmacSha512 => {
+                let mut expected = Hmac::<Sha512>::new_from_slice(&self.secret).unwrap();
+                expected.update(data);
+                expected.verify_slice(mac).is_ok()
+            }
+        }
+    }
+}
+
+/// Parse keys in the format of BIND, as generated by `tsig-keygen`:
+///
+/// ```text
+/// key "transfer" {
+///     algorithm hmac-sha256;
+///     secret "base64 secret";
+/// };
+/// ```
+pub fn parse_keys(text: &str) -> Result<Vec<TsigKey>> {
+    let error = |message: String| Error::InvalidTsigKey(message);
+
+    // split into words, quoted strings and punctuation, without comments
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => while chars.next().is_some_and(|c| c != '\n') {},
+            '/' if chars.peek() == Some(&'/') => while chars.next().is_some_and(|c| c != '\n') {},
+            '{' | '}' | ';' => tokens.push(c.to_string()),
+            '"' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => token.push(c),
+                        None => return Err(error("unterminated string".into())),
+                    }
+                }
+                tokens.push(token);
+            }
+            c => {
+                let mut token = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{};\"".contains(c) {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    let mut keys = Vec::new();
+    let mut tokens = tokens.iter().map(String::as_str);
+    let expect = |expected: &str, token: Option<&str>| match token {
+        Some(token) if token == expected => Ok(()),
+        token => Err(error(format!(
+            "expected `{}`, found `{}`",
+            expected,
+            token.unwrap_or("end of file")
+        ))),
+    };
+    while let Some(token) = tokens.next() {
+        expect("key", Some(token))?;
+        let name = match tokens.next() {
+            Some(name) if !"{};".contains(name) => name.trim_end_matches('.').to_ascii_lowercase(),
+            _ => return Err(error("expected the name of the key".into())),
+        };
+        expect("{", tokens.next())?;
+        let (mut algorithm, mut secret) = (None, None);
+        loop {
+            match tokens.next() {
+                Some("}") => break,
+                Some("algorithm") => algorithm = tokens.next().map(Algorithm::from_str),
+                Some("secret") => {
+                    secret = tokens.next().map(|s| {
+                        base64::decode(s)
+                            .map_err(|_| error(format!("invalid secret of key `{}`", name)))
+                    })
+                }
+                token => {
+                    return Err(error(format!(
+                        "unexpected `{}` in key `{}`",
+                        token.unwrap_or("end of file"),
+                        name
+                    )))
+                }
+            }
+            expect(";", tokens.next())?;
+        }
+        expect(";", tokens.next())?;
+
+        keys.push(TsigKey {
+            algorithm: algorithm
+                .ok_or_else(|| error(format!("no algorithm for key `{}`", name)))??,
+            secret: secret.ok_or_else(|| error(format!("no secret for key `{}`", name)))??,
+            name,
+        });
+    }
+    Ok(keys)
+}
+
+pub fn load_keys(path: &Path) -> Result<Vec<TsigKey>> {
+    parse_keys(&std::fs::read_to_string(path)?)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// The name in canonical wire format, lowercase and without compression.
+fn canonical_name(name: &str) -> Vec<u8> {
+    let mut buf = DnsPacketBuf::with_size(TCP_PACKET_SIZE);
+    // labels read from the wire always fit
+    buf.write_name_simple(&name.to_ascii_lowercase()).unwrap();
+    buf.buf[..buf.pos].to_vec()
+}
+
+/// The data covered by the MAC of a message, as in RFC 8945, section 4.3. The
+/// first message of a request or response covers all the TSIG variables, and
+/// later messages of a stream cover the timers only.
+#[allow(clippy::too_many_arguments)]
+fn signed_data(
+    prior_mac: Option<&[u8]>,
+    messages: &[u8],
+    subsequent: bool,
+    key_name: &str,
+    algorithm: &str,
+    time_signed: u64,
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    if let Some(mac) = prior_mac {
+        data.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(mac);
+    }
+    data.extend_from_slice(messages);
+    if !subsequent {
+        data.extend(canonical_name(key_name));
+        data.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes()); // TTL
+        data.extend(canonical_name(algorithm));
+    }
+    data.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    data.extend_from_slice(&FUDGE.to_be_bytes());
+    if !subsequent {
+        data.extend_from_slice(&error.to_be_bytes());
+        data.extend_from_slice(&(other.len() as u16).to_be_bytes());
+        data.extend_from_slice(other);
+    }
+    data
+}
+
+/// Split a message into the part before its TSIG record, restored to the
+/// original ID and additional count, and the TSIG record itself, if any.
+fn split_tsig(message: &[u8]) -> Result<Option<(Vec<u8>, DnsRecord)>> {
+    let mut buf = DnsPacketBuf {
+        buf: message.to_vec(),
+        pos: 0,
+    };
+    let header = DnsHeader::read_from(&mut buf)?;
+    if header.resource_entries == 0 {
+        return Ok(None);
+    }
+    for _ in 0..header.questions {
+        DnsQuestion::read_from(&mut buf)?;
+    }
+    let records = header.answers as usize
+        + header.authoritative_entries as usize
+        + header.resource_entries as usize;
+    let mut last = 0;
+    let mut last_type = 0;
+    for _ in 0..records {
+        last = buf.pos;
+        buf.read_name()?;
+        last_type = buf.read_u16()?;
+        buf.step(6); // class and TTL
+        let data_len = buf.read_u16()?;
+        buf.step(data_len as usize);
+    }
+    if last_type != QueryType::TSIG.to_u16().unwrap() {
+        return Ok(None);
+    }
+
+    buf.seek(last);
+    let tsig = DnsRecord::read_from(&mut buf)?;
+    let original_id = match tsig {
+        DnsRecord::TSIG { original_id, .. } => original_id,
+        _ => unreachable!(),
+    };
+    let mut unsigned = message[..last].to_vec();
+    unsigned[0..2].copy_from_slice(&original_id.to_be_bytes());
+    unsigned[10..12].copy_from_slice(&(header.resource_entries - 1).to_be_bytes());
+    Ok(Some((unsigned, tsig)))
+}
+
+/// Signs a request, or the responses to a signed request, each with the MAC
+/// of the previous message. It may also carry a TSIG error back to a client
+/// whose request fails verification, unsigned if the key is not known.
+pub struct Signer {
+    key_name: String,
+    algorithm: String,
+    key: Option<TsigKey>,
+    prior_mac: Option<Vec<u8>>,
+    subsequent: bool,
+    error: u16,
+}
+
+impl Signer {
+    pub fn new(key: &TsigKey) -> Self {
+        Self {
+            key_name: key.name.clone(),
+            algorithm: key.algorithm.name().to_owned(),
+            key: Some(key.clone()),
+            prior_mac: None,
+            subsequent: false,
+            error: 0,
+        }
+    }
+
+    fn response(key: &TsigKey, request_mac: Vec<u8>) -> Self {
+        Self {
+            prior_mac: Some(request_mac),
+            ..Self::new(key)
+        }
+    }
+
+    pub fn error(&self) -> u16 {
+        self.error
+    }
+
+    /// The name of the key, lowercase and without the trailing dot.
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    /// The MAC of the last message signed.
+    pub fn mac(&self) -> &[u8] {
+        self.prior_mac.as_deref().unwrap_or(&[])
+    }
+
+    /// Append a TSIG record to the message written in `buf`.
+    pub fn sign(&mut self, buf: &mut DnsPacketBuf) -> Result<()> {
+        let message = &buf.buf[..buf.pos];
+        let id = u16::from_be_bytes([message[0], message[1]]);
+        let additional = u16::from_be_bytes([message[10], message[11]]);
+        let time_signed = now();
+        let other = match self.error {
+            BADTIME => time_signed.to_be_bytes()[2..].to_vec(),
+            _ => vec![],
+        };
+        let mac = match self.key {
+            Some(ref key) => key.mac(&signed_data(
+                self.prior_mac.as_deref(),
+                message,
+                self.subsequent,
+                &self.key_name,
+                &self.algorithm,
+                time_signed,
+                self.error,
+                &other,
+            )),
+            None => vec![],
+        };
+
+        let tsig = DnsRecord::TSIG {
+            name: self.key_name.clone(),
+            algorithm: self.algorithm.clone(),
+            time_signed,
+            fudge: FUDGE,
+            mac: mac.clone(),
+            original_id: id,
+            error: self.error,
+            other,
+        };
+        tsig.write(buf)?;
+        buf.set_u16(10, additional + 1)?;
+
+        // only responses go on as a stream
+        self.subsequent = self.prior_mac.is_some();
+        self.prior_mac = Some(mac);
+        Ok(())
+    }
+}
+
+/// The outcome of verifying a request.
+pub enum Verified {
+    Unsigned,
+    /// Signed with a known key, with the signer of the responses
+    Valid(Signer),
+    /// Failed, with the signer of the error to answer with
+    Invalid(Signer),
+}
+
+/// Verify a request against the known keys.
+pub fn verify_request(keys: &[TsigKey], message: &[u8]) -> Verified {
+    let (unsigned, tsig) = match split_tsig(message) {
+        Ok(Some(split)) => split,
+        Ok(None) | Err(_) => return Verified::Unsigned,
+    };
+    let (key_name, algorithm, time_signed, fudge, mac, error, other) = match tsig {
+        DnsRecord::TSIG {
+            name,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            error,
+            other,
+            ..
+        } => (name, algorithm, time_signed, fudge, mac, error, other),
+        _ => unreachable!(),
+    };
+    let failed = |error| Signer {
+        key_name: key_name.clone(),
+        algorithm: algorithm.clone(),
+        key: None,
+        prior_mac: None,
+        subsequent: false,
+        error,
+    };
+
+    let key = keys.iter().find(|k| {
+        k.name.eq_ignore_ascii_case(key_name.trim_end_matches('.'))
+            && algorithm.parse().ok() == Some(k.algorithm)
+    });
+    let key = match key {
+        Some(key) => key,
+        None => return Verified::Invalid(failed(BADKEY)),
+    };
+    let data = signed_data(
+        None,
+        &unsigned,
+        false,
+        &key_name,
+        &algorithm,
+        time_signed,
+        error,
+        &other,
+    );
+    if !key.verify_mac(&data, &mac) {
+        return Verified::Invalid(failed(BADSIG));
+    }
+    if now().abs_diff(time_signed) > fudge as u64 {
+        return Verified::Invalid(Signer {
+            error: BADTIME,
+            ..Signer::response(key, mac)
+        });
+    }
+    Verified::Valid(Signer::response(key, mac))
+}
+
+/// Verifies the responses to a signed request, which may be a stream of
+/// messages where not all of them are signed.
+pub struct Verifier {
+    key: TsigKey,
+    prior_mac: Vec<u8>,
+    subsequent: bool,
+    unsigned: Vec<u8>,
+    unsigned_messages: usize,
+}
+
+impl Verifier {
+    pub fn new(request_signer: &Signer) -> Self {
+        Self {
+            key: request_signer.key.clone().unwrap(),
+            prior_mac: request_signer.mac().to_vec(),
+            subsequent: false,
+            unsigned: vec![],
+            unsigned_messages: 0,
+        }
+    }
+
+    pub fn verify(&mut self, message: &[u8]) -> Result<()> {
+        let (unsigned, tsig) = match split_tsig(message)? {
+            Some(split) => split,
+            None if self.subsequent && self.unsigned_messages < MAX_UNSIGNED_MESSAGES => {
+                self.unsigned.extend_from_slice(message);
+                self.unsigned_messages += 1;
+                return Ok(());
+            }
+            None => return Err(Error::TsigFailed("unsigned response".into())),
+        };
+        let (key_name, algorithm, time_signed, fudge, mac, error, other) = match tsig {
+            DnsRecord::TSIG {
+                name,
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                error,
+                other,
+                ..
+            } => (name, algorithm, time_signed, fudge, mac, error, other),
+            _ => unreachable!(),
+        };
+        if error != 0 {
+            return Err(Error::TsigFailed(format!(
+                "the server answered with {}",
+                error_name(error)
+            )));
+        }
+        if !key_name.eq_ignore_ascii_case(&self.key.name)
+            || algorithm.parse().ok() != Some(self.key.algorithm)
+        {
+            return Err(Error::TsigFailed(format!(
+                "response signed with another key `{}`",
+                key_name
+            )));
+        }
+
+        self.unsigned.extend_from_slice(&unsigned);
+        let data = signed_data(
+            Some(&self.prior_mac),
+            &self.unsigned,
+            self.subsequent,
+            &key_name,
+            &algorithm,
+            time_signed,
+            error,
+            &other,
+        );
+        if !self.key.verify_mac(&data, &mac) {
+            return Err(Error::TsigFailed("bad signature of the response".into()));
+        }
+        if now().abs_diff(time_signed) > fudge as u64 {
+            return Err(Error::TsigFailed("response signed at a bad time".into()));
+        }
+
+        self.prior_mac = mac;
+        self.subsequent = true;
+        self.unsigned.clear();
+        self.unsigned_messages = 0;
+        Ok(())
+    }
+
+    /// Check that the last message of a stream is signed.
+    pub fn finish(&self) -> Result<()> {
+        match self.unsigned_messages {
+            0 => Ok(()),
+            _ => Err(Error::TsigFailed("the last response is not signed".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns_packet::DnsPacket;
+
+    fn key(name: &str) -> TsigKey {
+        parse_keys(include_str!("../res/tsig.key"))
+            .unwrap()
+            .into_iter()
+            .find(|k| k.name == name)
+            .unwrap()
+    }
+
+    fn valid(verified: Verified) -> Signer {
+        match verified {
+            Verified::Valid(signer) => signer,
+            _ => panic!("the request is not valid"),
+        }
+    }
+
+    fn invalid(verified: Verified) -> u16 {
+        match verified {
+            Verified::Invalid(signer) => signer.error(),
+            _ => panic!("the request is not invalid"),
+        }
+    }
+
+    fn write(packet: &DnsPacket, signer: Option<&mut Signer>) -> Vec<u8> {
+        let mut buf = DnsPacketBuf::with_size(TCP_PACKET_SIZE);
+        packet.write(&mut buf).unwrap();
+        if let Some(signer) = signer {
+            signer.sign(&mut buf).unwrap();
+        }
+        buf.buf[..buf.pos].to_vec()
+    }
+
+    #[test]
+    fn parse_key_file() {
+        let keys = parse_keys(include_str!("../res/tsig.key")).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].name, "transfer");
+        assert_eq!(keys[0].algorithm, Algorithm::HmacSha256);
+        assert_eq!(keys[0].secret.len(), 32);
+        assert_eq!(keys[1].algorithm, Algorithm::HmacSha512);
+
+        assert!(parse_keys("key k { algorithm hmac-md5; secret \"AAAA\"; };").is_err());
+        assert!(parse_keys("key k { algorithm hmac-sha256; };").is_err());
+        assert!(parse_keys("key k { secret \"AAAA\" };").is_err());
+    }
+
+    #[test]
+    fn sign_and_verify_request() {
+        let key = key("transfer");
+        let query = DnsPacket::example("bugen.dev", QueryType::AXFR);
+        let mut signer = Signer::new(&key);
+        let mut bytes = write(&query, Some(&mut signer));
+
+        let parsed = DnsPacket::read_from(&mut DnsPacketBuf::from_bytes(&bytes)).unwrap();
+        assert_eq!(parsed.resources[0].query_type(), QueryType::TSIG);
+        let keys = std::slice::from_ref(&key);
+        valid(verify_request(keys, &bytes));
+        assert!(matches!(
+            verify_request(keys, &write(&query, None)),
+            Verified::Unsigned
+        ));
+
+        // unknown key, or tampered with
+        let other = self::key("update");
+        assert_eq!(invalid(verify_request(&[other], &bytes)), BADKEY);
+        bytes[3] ^= 1;
+        assert_eq!(invalid(verify_request(keys, &bytes)), BADSIG);
+    }
+
+    #[test]
+    fn verify_response_stream() {
+        let key = key("transfer");
+        let query = DnsPacket::example("bugen.dev", QueryType::AXFR);
+        let mut request_signer = Signer::new(&key);
+        let request = write(&query, Some(&mut request_signer));
+
+        let keys = std::slice::from_ref(&key);
+        let mut response_signer = valid(verify_request(keys, &request));
+        let mut response = query.clone();
+        response.header.response = true;
+
+        let mut verifier = Verifier::new(&request_signer);
+        for _ in 0..3 {
+            verifier
+                .verify(&write(&response, Some(&mut response_signer)))
+                .unwrap();
+        }
+        verifier.finish().unwrap();
+
+        // the last message must be signed
+        verifier.verify(&write(&response, None)).unwrap();
+        assert!(verifier.finish().is_err());
+
+        // a message of the stream tampered with
+        let mut tampered = write(&response, Some(&mut response_signer));
+        tampered[2] ^= 1;
+        assert!(verifier.verify(&tampered).is_err());
+
+        // a response signed for another request
+        let mut verifier = Verifier::new(&Signer::new(&key));
+        let mut response_signer = valid(verify_request(keys, &request));
+        assert!(verifier
+            .verify(&write(&response, Some(&mut response_signer)))
+            .is_err());
+    }
+}
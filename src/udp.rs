@@ -0,0 +1,231 @@
+use socket2::SockAddr;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use tokio::io::unix::AsyncFd;
+
+/// The local address a datagram arrived on, to send the reply from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LocalAddr {
+    pub ip: IpAddr,
+    // the interface, which scopes link-local IPv6 addresses
+    ifindex: u32,
+}
+
+/// A UDP socket that tells the local address of each datagram received, and
+/// sends from a given one, with `IP_PKTINFO` or `IPV6_RECVPKTINFO`. So a
+/// socket bound to a wildcard address replies from the address that was
+/// queried, not the one the system picks by the route.
+pub struct UdpSocket {
+    inner: AsyncFd<std::net::UdpSocket>,
+}
+
+// large enough and aligned for a header and an `in6_pktinfo`
+type Control = [u64; 8];
+
+impl UdpSocket {
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        let (level, name) = match socket.local_addr()? {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+        };
+        let on: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Receive a datagram, with the address it came from and the local
+    /// address it arrived on.
+    pub async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<LocalAddr>)> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.with_io(|| self.recv_msg(buf)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a datagram to `target`, from `local` if given.
+    pub async fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        local: Option<LocalAddr>,
+    ) -> io::Result<usize> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            match guard.with_io(|| self.send_msg(buf, target, local)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn recv_msg(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<LocalAddr>)> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control: Control = [0; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let len = unsafe { libc::recvmsg(self.inner.as_raw_fd(), &mut msg, 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let from = unsafe { SockAddr::from_raw_parts(msg.msg_name as *const _, msg.msg_namelen) }
+            .as_std()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP address"))?;
+
+        let mut local = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let data = libc::CMSG_DATA(cmsg);
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        let info = ptr::read_unaligned(data as *const libc::in_pktinfo);
+                        local = Some(LocalAddr {
+                            ip: Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)).into(),
+                            ifindex: 0,
+                        });
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        let info = ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                        local = Some(LocalAddr {
+                            ip: Ipv6Addr::from(info.ipi6_addr.s6_addr).into(),
+                            ifindex: info.ipi6_ifindex,
+                        });
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((len as usize, from, local))
+    }
+
+    fn send_msg(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        local: Option<LocalAddr>,
+    ) -> io::Result<usize> {
+        let target = SockAddr::from(target);
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control: Control = [0; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = target.as_ptr() as *mut libc::c_void;
+        msg.msg_namelen = target.len();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        if let Some(local) = local {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            unsafe {
+                let (level, kind, len) = match local.ip {
+                    IpAddr::V4(_) => (
+                        libc::IPPROTO_IP,
+                        libc::IP_PKTINFO,
+                        mem::size_of::<libc::in_pktinfo>(),
+                    ),
+                    IpAddr::V6(_) => (
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_PKTINFO,
+                        mem::size_of::<libc::in6_pktinfo>(),
+                    ),
+                };
+                msg.msg_controllen = libc::CMSG_SPACE(len as u32) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = level;
+                (*cmsg).cmsg_type = kind;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+                let data = libc::CMSG_DATA(cmsg);
+                match local.ip {
+                    IpAddr::V4(ip) => {
+                        let info = libc::in_pktinfo {
+                            ipi_ifindex: 0,
+                            ipi_spec_dst: libc::in_addr {
+                                s_addr: u32::from(ip).to_be(),
+                            },
+                            ipi_addr: libc::in_addr { s_addr: 0 },
+                        };
+                        ptr::write_unaligned(data as *mut libc::in_pktinfo, info);
+                    }
+                    IpAddr::V6(ip) => {
+                        let info = libc::in6_pktinfo {
+                            ipi6_addr: libc::in6_addr {
+                                s6_addr: ip.octets(),
+                            },
+                            ipi6_ifindex: local.ifindex,
+                        };
+                        ptr::write_unaligned(data as *mut libc::in6_pktinfo, info);
+                    }
+                }
+            }
+        }
+
+        let len = unsafe { libc::sendmsg(self.inner.as_raw_fd(), &msg, 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn reply_from_local_addr() {
+        let std_socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = std_socket.local_addr().unwrap().port();
+        let socket = UdpSocket::from_std(std_socket).unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // another loopback address than the one of the client
+        client.send_to(b"ping", ("127.0.0.2", port)).await.unwrap();
+        let mut buf = [0; 16];
+        let (len, from, local) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, client.local_addr().unwrap());
+        let local = local.unwrap();
+        assert_eq!(local.ip, "127.0.0.2".parse::<IpAddr>().unwrap());
+
+        socket.send_to(b"pong", from, Some(local)).await.unwrap();
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, SocketAddr::from(([127, 0, 0, 2], port)));
+    }
+}
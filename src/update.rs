@@ -0,0 +1,637 @@
+use crate::dns_packet::{
+    DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, CLASS_ANY, CLASS_IN,
+    CLASS_NONE, OPCODE_UPDATE,
+};
+use crate::dns_packet_buf::DnsPacketBuf;
+use crate::error::{Error, Result};
+use crate::journal::serial_lt;
+use crate::utils::is_subdomain_of;
+use crate::zone::Zone;
+use crate::zone_file::parse_zone;
+use num_traits::{FromPrimitive, ToPrimitive};
+
+/// A resource record in the prerequisite or update section of an UPDATE
+/// message, whose class and empty RDATA carry meaning, as in RFC 2136.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdateRecord {
+    pub name: String,
+    pub query_type: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Option<DnsRecord>,
+}
+
+impl UpdateRecord {
+    pub fn read_from(buf: &mut DnsPacketBuf) -> Result<Self> {
+        let start = buf.pos;
+        let name = buf.read_name()?;
+        let query_type_num = buf.read_u16()?;
+        let class = buf.read_u16()?;
+        let ttl = buf.read_u32()?;
+        let data_len = buf.read_u16()?;
+
+        let query_type = QueryType::from_u16(query_type_num).ok_or(Error::UnknownQuery {
+            query_type_num,
+            name: name.clone(),
+            data_len,
+            ttl,
+        })?;
+        let data = if data_len == 0 {
+            None
+        } else {
+            // read it again as an ordinary record
+            buf.seek(start);
+            Some(DnsRecord::read_from(buf)?)
+        };
+        Ok(Self {
+            name,
+            query_type,
+            class,
+            ttl,
+            data,
+        })
+    }
+
+    pub fn write(&self, buf: &mut DnsPacketBuf) -> Result<()> {
+        match &self.data {
+            Some(record) => {
+                let mut record = record.clone();
+                record.set_ttl(self.ttl);
+                record.write_with_class(buf, self.class)
+            }
+            None => {
+                buf.write_name_simple(&self.name)?;
+                buf.write_u16(self.query_type.to_u16().unwrap())?;
+                buf.write_u16(self.class)?;
+                buf.write_u32(self.ttl)?;
+                buf.write_u16(0) // data_len
+            }
+        }
+    }
+
+    fn without_data(name: &str, query_type: QueryType, class: u16) -> Self {
+        Self {
+            name: name.trim_end_matches('.').to_owned(),
+            query_type,
+            class,
+            ttl: 0,
+            data: None,
+        }
+    }
+
+    /// Add `record` to the zone.
+    pub fn add(record: DnsRecord) -> Self {
+        Self {
+            name: record.name().to_owned(),
+            query_type: record.query_type(),
+            class: CLASS_IN,
+            ttl: record.ttl(),
+            data: Some(record),
+        }
+    }
+
+    /// Delete the record with the same RDATA as `record` from the zone.
+    pub fn delete(record: DnsRecord) -> Self {
+        Self {
+            name: record.name().to_owned(),
+            query_type: record.query_type(),
+            class: CLASS_NONE,
+            ttl: 0,
+            data: Some(record),
+        }
+    }
+
+    /// Delete the RRset of `query_type` at `name`, or all of them with
+    /// `QueryType::ANY`.
+    pub fn delete_rrset(name: &str, query_type: QueryType) -> Self {
+        Self::without_data(name, query_type, CLASS_ANY)
+    }
+
+    /// Require the RRset of `query_type` at `name` to exist, or the name to be
+    /// in use with `QueryType::ANY`.
+    pub fn exists(name: &str, query_type: QueryType) -> Self {
+        Self::without_data(name, query_type, CLASS_ANY)
+    }
+
+    /// Require the RRset of `query_type` at `name` not to exist, or the name
+    /// not to be in use with `QueryType::ANY`.
+    pub fn absent(name: &str, query_type: QueryType) -> Self {
+        Self::without_data(name, query_type, CLASS_NONE)
+    }
+}
+
+/// An UPDATE message, whose sections are the zone, the prerequisites and the
+/// updates, instead of those of a query.
+#[derive(Clone, Debug)]
+pub struct UpdateMessage {
+    pub header: DnsHeader,
+    pub zone: DnsQuestion,
+    pub prerequisites: Vec<UpdateRecord>,
+    pub updates: Vec<UpdateRecord>,
+}
+
+impl UpdateMessage {
+    pub fn new(zone: &str) -> Self {
+        let mut header = DnsPacket::example(zone, QueryType::SOA).header;
+        header.opcode = OPCODE_UPDATE;
+        header.recursion_desired = false;
+        Self {
+            header,
+            zone: DnsQuestion {
+                name: zone.trim_end_matches('.').to_owned(),
+                query_type: QueryType::SOA,
+            },
+            prerequisites: vec![],
+            updates: vec![],
+        }
+    }
+
+    pub fn read_from(buf: &mut DnsPacketBuf) -> Result<Self> {
+        let header = DnsHeader::read_from(buf)?;
+        if header.questions != 1 {
+            return Err(Error::InvalidUpdate(format!(
+                "{} records in the zone section",
+                header.questions
+            )));
+        }
+        let zone = DnsQuestion::read_from(buf)?;
+        let prerequisites = (0..header.answers)
+            .map(|_| UpdateRecord::read_from(buf))
+            .collect::<Result<_>>()?;
+        let updates = (0..header.authoritative_entries)
+            .map(|_| UpdateRecord::read_from(buf))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            header,
+            zone,
+            prerequisites,
+            updates,
+        })
+    }
+
+    pub fn write(&self, buf: &mut DnsPacketBuf) -> Result<()> {
+        let header = DnsHeader {
+            questions: 1,
+            answers: self.prerequisites.len() as u16,
+            authoritative_entries: self.updates.len() as u16,
+            resource_entries: 0,
+            ..self.header.clone()
+        };
+        header.write(buf)?;
+        self.zone.write(buf)?;
+        for r in self.prerequisites.iter().chain(self.updates.iter()) {
+            r.write(buf)?;
+        }
+        Ok(())
+    }
+
+    /// The response to this message with `rescode`.
+    pub fn response(&self, rescode: ResultCode) -> DnsPacket {
+        let mut packet = DnsPacket {
+            header: DnsHeader {
+                id: self.header.id,
+                opcode: OPCODE_UPDATE,
+                response: true,
+                recursion_desired: false,
+                rescode,
+                ..DnsHeader::default()
+            },
+            questions: vec![self.zone.clone()],
+            ..DnsPacket::default()
+        };
+        packet.update_header_counts();
+        packet
+    }
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+fn same_rdata(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.query_type() == b.query_type()
+        && same_name(a.name(), b.name())
+        && a.rdata_text() == b.rdata_text()
+}
+
+fn in_rrset(record: &DnsRecord, name: &str, query_type: QueryType) -> bool {
+    same_name(record.name(), name)
+        && (query_type == QueryType::ANY || record.query_type() == query_type)
+}
+
+/// Check the prerequisites against the records of a zone, as in RFC 2136,
+/// section 3.2.
+fn check_prerequisites(
+    origin: &str,
+    records: &[DnsRecord],
+    prerequisites: &[UpdateRecord],
+) -> std::result::Result<(), ResultCode> {
+    let mut expected_rrsets = Vec::new();
+    for p in prerequisites {
+        if !is_subdomain_of(&p.name, origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+        let exists = records.iter().any(|r| in_rrset(r, &p.name, p.query_type));
+        match (p.class, &p.data) {
+            (CLASS_ANY, None) | (CLASS_NONE, None) if p.ttl != 0 => {
+                return Err(ResultCode::FORMERR)
+            }
+            (CLASS_ANY, None) if !exists => {
+                return Err(match p.query_type {
+                    QueryType::ANY => ResultCode::NXDOMAIN,
+                    _ => ResultCode::NXRRSET,
+                })
+            }
+            (CLASS_NONE, None) if exists => {
+                return Err(match p.query_type {
+                    QueryType::ANY => ResultCode::YXDOMAIN,
+                    _ => ResultCode::YXRRSET,
+                })
+            }
+            (CLASS_ANY, None) | (CLASS_NONE, None) => {}
+            (CLASS_IN, Some(record)) if p.ttl == 0 => expected_rrsets.push(record),
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    // RRsets that must exist with exactly the given records
+    for expected in expected_rrsets.iter() {
+        let matches = |a: &[&DnsRecord], b: &[&DnsRecord]| {
+            a.iter().all(|x| b.iter().any(|y| same_rdata(x, y)))
+        };
+        let expected_rrset: Vec<_> = expected_rrsets
+            .iter()
+            .cloned()
+            .filter(|r| in_rrset(r, expected.name(), expected.query_type()))
+            .collect();
+        let rrset: Vec<_> = records
+            .iter()
+            .filter(|r| in_rrset(r, expected.name(), expected.query_type()))
+            .collect();
+        if !matches(&expected_rrset, &rrset) || !matches(&rrset, &expected_rrset) {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+    Ok(())
+}
+
+/// Check the update section before applying anything, as in RFC 2136,
+/// section 3.4.1.
+fn prescan_updates(origin: &str, updates: &[UpdateRecord]) -> std::result::Result<(), ResultCode> {
+    for u in updates {
+        if !is_subdomain_of(&u.name, origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+        let valid = match (u.class, &u.data) {
+            (CLASS_IN, Some(_)) => true,
+            (CLASS_ANY, None) => u.ttl == 0,
+            (CLASS_NONE, Some(_)) => u.ttl == 0,
+            _ => false,
+        };
+        if !valid {
+            return Err(ResultCode::FORMERR);
+        }
+    }
+    Ok(())
+}
+
+/// Apply an UPDATE message to `zone` atomically, after checking its
+/// prerequisites, as in RFC 2136, section 3. The serial is increased unless
+/// the update sets a greater one itself, and the change is recorded in the
+/// journal of the new zone. Returns `None` if nothing changes.
+pub fn apply_update(
+    zone: &Zone,
+    message: &UpdateMessage,
+) -> std::result::Result<Option<Zone>, ResultCode> {
+    if message.zone.query_type != QueryType::SOA {
+        return Err(ResultCode::FORMERR);
+    }
+    let origin = zone.origin.as_str();
+    let old_soa = zone.soa().unwrap().clone();
+    let mut records: Vec<DnsRecord> = std::iter::once(old_soa.clone())
+        .chain(zone.records().cloned())
+        .collect();
+
+    check_prerequisites(origin, &records, &message.prerequisites)?;
+    prescan_updates(origin, &message.updates)?;
+
+    let mut changed = false;
+    for u in message.updates.iter() {
+        let at_apex = same_name(&u.name, origin);
+        // the SOA and NS records at the apex are never deleted as a whole
+        let protected = |r: &DnsRecord| {
+            at_apex && (r.query_type() == QueryType::SOA || r.query_type() == QueryType::NS)
+        };
+
+        match (u.class, u.data.as_ref()) {
+            (CLASS_IN, Some(record)) => {
+                let has = |query_type: QueryType, same: bool| {
+                    records.iter().any(|r| {
+                        same_name(r.name(), record.name()) && (r.query_type() == query_type) == same
+                    })
+                };
+                match record.query_type() {
+                    QueryType::SOA => {
+                        let soa_index = records
+                            .iter()
+                            .position(|r| r.query_type() == QueryType::SOA)
+                            .unwrap();
+                        let newer = match (&records[soa_index], record) {
+                            (
+                                DnsRecord::SOA { serial: old, .. },
+                                DnsRecord::SOA { serial: new, .. },
+                            ) => serial_lt(*old, *new),
+                            _ => false,
+                        };
+                        if at_apex && newer {
+                            records[soa_index] = record.clone();
+                            changed = true;
+                        }
+                        continue;
+                    }
+                    // a CNAME cannot coexist with other data
+                    QueryType::CNAME if has(QueryType::CNAME, false) => continue,
+                    QueryType::CNAME => records.retain(|r| {
+                        !in_rrset(r, record.name(), QueryType::CNAME) || same_rdata(r, record)
+                    }),
+                    _ if has(QueryType::CNAME, true) => continue,
+                    _ => {}
+                }
+
+                changed |= match records.iter_mut().find(|r| same_rdata(r, record)) {
+                    Some(existing) if existing.ttl() == record.ttl() => false,
+                    Some(existing) => {
+                        existing.set_ttl(record.ttl());
+                        true
+                    }
+                    None => {
+                        records.push(record.clone());
+                        true
+                    }
+                };
+            }
+            (CLASS_ANY, None) => {
+                let len = records.len();
+                records.retain(|r| !in_rrset(r, &u.name, u.query_type) || protected(r));
+                changed |= records.len() != len;
+            }
+            (CLASS_NONE, Some(record)) => {
+                let last_ns = at_apex
+                    && record.query_type() == QueryType::NS
+                    && records
+                        .iter()
+                        .filter(|r| in_rrset(r, origin, QueryType::NS))
+                        .count()
+                        <= 1;
+                if record.query_type() == QueryType::SOA || last_ns {
+                    continue;
+                }
+                let len = records.len();
+                records.retain(|r| !same_rdata(r, record));
+                changed |= records.len() != len;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if !changed {
+        return Ok(None);
+    }
+    let soa = records
+        .iter_mut()
+        .find(|r| r.query_type() == QueryType::SOA)
+        .unwrap();
+    if let (DnsRecord::SOA { serial, .. }, DnsRecord::SOA { serial: old, .. }) =
+        (&mut *soa, &old_soa)
+    {
+        if !serial_lt(*old, *serial) {
+            *serial = old.wrapping_add(1);
+        }
+    }
+
+    let new_zone = Zone::new(origin, records).map_err(|_| ResultCode::SERVFAIL)?;
+    let diff = zone.diff(&new_zone).map_err(|_| ResultCode::SERVFAIL)?;
+    zone.apply(&diff)
+        .map(Some)
+        .map_err(|_| ResultCode::SERVFAIL)
+}
+
+/// Parse `<name> [TYPE]` given on the command line, with names relative to
+/// `zone` unless ending with a dot, as in master files.
+pub fn parse_rrset(text: &str, zone: &str) -> Result<(String, QueryType)> {
+    let mut tokens = text.split_whitespace();
+    let name = match tokens.next() {
+        Some("@") => zone.to_owned(),
+        Some(name) if name.ends_with('.') => name.trim_end_matches('.').to_owned(),
+        Some(name) => format!("{}.{}", name, zone),
+        None => return Err(Error::InvalidUpdate(format!("no name in `{}`", text))),
+    };
+    let query_type = match (tokens.next(), tokens.next()) {
+        (None, _) => QueryType::ANY,
+        (Some(t), None) => t
+            .parse()
+            .map_err(|_| Error::InvalidUpdate(format!("unknown type in `{}`", text)))?,
+        (Some(_), Some(_)) => {
+            return Err(Error::InvalidUpdate(format!("trailing data in `{}`", text)))
+        }
+    };
+    Ok((name, query_type))
+}
+
+/// Parse `<name> [TYPE [rdata]]` given on the command line into the deletion
+/// of a single record, an RRset, or all the RRsets at a name.
+pub fn parse_delete(text: &str, zone: &str) -> Result<UpdateRecord> {
+    if text.split_whitespace().count() <= 2 {
+        let (name, query_type) = parse_rrset(text, zone)?;
+        return Ok(UpdateRecord::delete_rrset(&name, query_type));
+    }
+    let mut tokens = text.trim_start().splitn(2, char::is_whitespace);
+    let (name, rest) = (tokens.next().unwrap(), tokens.next().unwrap());
+    let record = parse_zone(&format!("{} 0 {}", name, rest), zone)?.remove(0);
+    Ok(UpdateRecord::delete(record))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    lazy_static! {
+        static ref ZONE: Zone = Zone::new(
+            "bugen.dev",
+            parse_zone(include_str!("../res/bugen.dev.zone"), "bugen.dev").unwrap()
+        )
+        .unwrap();
+    }
+
+    fn record(text: &str) -> DnsRecord {
+        parse_zone(text, "bugen.dev").unwrap().remove(0)
+    }
+
+    fn update(prerequisites: Vec<UpdateRecord>, updates: Vec<UpdateRecord>) -> UpdateMessage {
+        let mut message = UpdateMessage::new("bugen.dev");
+        message.prerequisites = prerequisites;
+        message.updates = updates;
+        message
+    }
+
+    fn rrset(zone: &Zone, name: &str, query_type: QueryType) -> Vec<DnsRecord> {
+        zone.records()
+            .filter(|r| in_rrset(r, name, query_type))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let message = update(
+            vec![
+                UpdateRecord::exists("www.bugen.dev", QueryType::A),
+                UpdateRecord::absent("new.bugen.dev", QueryType::ANY),
+            ],
+            vec![
+                UpdateRecord::add(record("new 300 A 10.0.0.9")),
+                UpdateRecord::delete(record("www 300 A 10.0.0.3")),
+                UpdateRecord::delete_rrset("blog.bugen.dev", QueryType::ANY),
+            ],
+        );
+        let mut buf = DnsPacketBuf::new();
+        message.write(&mut buf).unwrap();
+
+        buf.seek(0);
+        let read = UpdateMessage::read_from(&mut buf).unwrap();
+        assert_eq!(read.header.opcode, OPCODE_UPDATE);
+        assert_eq!(read.zone, message.zone);
+        assert_eq!(read.prerequisites, message.prerequisites);
+        // deletions are written with a zero TTL
+        let mut updates = message.updates.clone();
+        updates[1].data.as_mut().unwrap().set_ttl(0);
+        assert_eq!(read.updates, updates);
+    }
+
+    #[test]
+    fn add_and_delete() {
+        let message = update(
+            vec![],
+            vec![
+                UpdateRecord::add(record("new 300 A 10.0.0.9")),
+                UpdateRecord::delete(record("www 0 A 10.0.0.3")),
+                UpdateRecord::delete_rrset("blog.bugen.dev", QueryType::ANY),
+            ],
+        );
+        let zone = apply_update(&ZONE, &message).unwrap().unwrap();
+
+        assert_eq!(zone.serial(), ZONE.serial() + 1);
+        assert_eq!(rrset(&zone, "new.bugen.dev", QueryType::A).len(), 1);
+        assert_eq!(rrset(&zone, "www.bugen.dev", QueryType::A).len(), 1);
+        assert!(rrset(&zone, "blog.bugen.dev", QueryType::ANY).is_empty());
+
+        let diffs = zone.journal().diffs();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].removed.len(), 2);
+        assert_eq!(diffs[0].added.len(), 1);
+
+        // the same update again changes nothing
+        let message = update(vec![], message.updates[..2].to_vec());
+        assert!(apply_update(&zone, &message).unwrap().is_none());
+    }
+
+    #[test]
+    fn protected_records() {
+        let message = update(
+            vec![],
+            vec![
+                UpdateRecord::delete_rrset("bugen.dev", QueryType::ANY),
+                UpdateRecord::delete(record("@ 0 NS ns1")),
+                UpdateRecord::add(record("www 300 CNAME @")),
+                UpdateRecord::add(record("blog 300 A 10.0.0.9")),
+            ],
+        );
+        let zone = apply_update(&ZONE, &message).unwrap().unwrap();
+        assert!(zone.soa().is_some());
+        assert_eq!(rrset(&zone, "bugen.dev", QueryType::NS).len(), 1);
+        assert!(rrset(&zone, "bugen.dev", QueryType::MX).is_empty());
+        assert!(rrset(&zone, "www.bugen.dev", QueryType::CNAME).is_empty());
+        assert!(rrset(&zone, "blog.bugen.dev", QueryType::A).is_empty());
+
+        // an explicit SOA update sets the serial
+        let message = update(
+            vec![],
+            vec![UpdateRecord::add(record(
+                "@ 3600 SOA ns1 admin 2020120101 7200 3600 1209600 300",
+            ))],
+        );
+        let zone = apply_update(&ZONE, &message).unwrap().unwrap();
+        assert_eq!(zone.serial(), 2020120101);
+    }
+
+    #[test]
+    fn prerequisites() {
+        let add = vec![UpdateRecord::add(record("new 300 A 10.0.0.9"))];
+        let check = |prerequisite: UpdateRecord| {
+            apply_update(&ZONE, &update(vec![prerequisite], add.clone())).map(|z| z.is_some())
+        };
+
+        assert_eq!(
+            check(UpdateRecord::exists("www.bugen.dev", QueryType::A)),
+            Ok(true)
+        );
+        assert_eq!(
+            check(UpdateRecord::exists("nope.bugen.dev", QueryType::ANY)),
+            Err(ResultCode::NXDOMAIN)
+        );
+        assert_eq!(
+            check(UpdateRecord::exists("www.bugen.dev", QueryType::MX)),
+            Err(ResultCode::NXRRSET)
+        );
+        assert_eq!(
+            check(UpdateRecord::absent("www.bugen.dev", QueryType::ANY)),
+            Err(ResultCode::YXDOMAIN)
+        );
+        assert_eq!(
+            check(UpdateRecord::absent("www.bugen.dev", QueryType::A)),
+            Err(ResultCode::YXRRSET)
+        );
+        assert_eq!(
+            check(UpdateRecord::absent("www.bugen.com", QueryType::A)),
+            Err(ResultCode::NOTZONE)
+        );
+
+        // value-dependent
+        let exact = |records: &[&str]| {
+            let prerequisites = records
+                .iter()
+                .map(|r| UpdateRecord {
+                    ttl: 0,
+                    ..UpdateRecord::add(record(r))
+                })
+                .collect();
+            apply_update(&ZONE, &update(prerequisites, add.clone())).map(|z| z.is_some())
+        };
+        assert_eq!(exact(&["www 0 A 10.0.0.3", "www 0 A 10.0.0.4"]), Ok(true));
+        assert_eq!(exact(&["www 0 A 10.0.0.3"]), Err(ResultCode::NXRRSET));
+    }
+
+    #[test]
+    fn parse_command_line() {
+        assert_eq!(
+            parse_rrset("www", "bugen.dev").unwrap(),
+            ("www.bugen.dev".to_owned(), QueryType::ANY)
+        );
+        assert_eq!(
+            parse_rrset("www.bugen.dev. mx", "bugen.dev").unwrap(),
+            ("www.bugen.dev".to_owned(), QueryType::MX)
+        );
+        assert!(parse_rrset("www BOGUS", "bugen.dev").is_err());
+        assert!(parse_rrset("", "bugen.dev").is_err());
+
+        assert_eq!(
+            parse_delete("@ NS", "bugen.dev").unwrap(),
+            UpdateRecord::delete_rrset("bugen.dev", QueryType::NS)
+        );
+        assert_eq!(
+            parse_delete("www A 10.0.0.1", "bugen.dev").unwrap(),
+            UpdateRecord::delete(record("www 0 A 10.0.0.1"))
+        );
+    }
+}
@@ -1,6 +1,147 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
 #[macro_export]
 macro_rules! buf {
     ($path:literal) => {
         DnsPacketBuf::from_bytes(include_bytes!($path))
     };
 }
+
+/// Replace the file at `path` with `text` at once, through a temporary file
+/// next to it, so that a crash never leaves a partial one.
+pub fn replace_file(path: &Path, text: &str) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// Whether `name` equals `zone` or lies below it, ignoring ASCII case.
+/// The root zone is written as an empty string.
+pub fn is_subdomain_of(name: &str, zone: &str) -> bool {
+    if zone.is_empty() {
+        return true;
+    }
+    // as bytes, since the split point may not be a char boundary
+    let (name, zone) = (name.as_bytes(), zone.as_bytes());
+    if name.len() < zone.len() {
+        return false;
+    }
+    let (prefix, suffix) = name.split_at(name.len() - zone.len());
+    suffix.eq_ignore_ascii_case(zone) && (prefix.is_empty() || ends_with_dot(prefix))
+}
+
+/// Whether `name` ends with a dot that is not escaped by a backslash.
+fn ends_with_dot(name: &[u8]) -> bool {
+    let backslashes = name
+        .iter()
+        .rev()
+        .skip(1)
+        .take_while(|&&b| b == b'\\')
+        .count();
+    name.ends_with(b".") && backslashes % 2 == 0
+}
+
+/// The absolute form of `name` with the trailing dot, as in master files.
+pub fn fqdn(name: &str) -> String {
+    if ends_with_dot(name.as_bytes()) {
+        name.to_owned()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// The labels of `name`, split at the dots not escaped by a backslash, which
+/// are kept escaped in names as in master files.
+pub fn labels(name: &str) -> Vec<&str> {
+    let mut labels = Vec::new();
+    let (mut start, mut escaped) = (0, false);
+    for (i, b) in name.bytes().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'.' => {
+                labels.push(&name[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    labels.push(&name[start..]);
+    labels
+}
+
+/// The bytes of a label, without the backslashes escaping its dots and
+/// backslashes.
+pub fn unescape_label(label: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(label.len());
+    let mut escaped = false;
+    for b in label.bytes() {
+        if b == b'\\' && !escaped {
+            escaped = true;
+            continue;
+        }
+        bytes.push(b);
+        escaped = false;
+    }
+    bytes
+}
+
+/// A label of raw bytes as in names, with its dots and backslashes escaped.
+pub fn escape_label(bytes: &[u8]) -> String {
+    let mut label = String::with_capacity(bytes.len());
+    for c in String::from_utf8_lossy(bytes).chars() {
+        if c == '.' || c == '\\' {
+            label.push('\\');
+        }
+        label.push(c);
+    }
+    label
+}
+
+/// Quote a character-string for master files, escaping quotes, backslashes
+/// and the non-printable bytes.
+pub fn quote_character_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for &b in s.as_bytes() {
+        match b {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(b as char);
+            }
+            0x20..=0x7e => quoted.push(b as char),
+            _ => quoted.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn match_subdomains() {
+        assert!(is_subdomain_of("www.Bugen.dev", "bugen.DEV"));
+        assert!(is_subdomain_of("bugen.dev", "bugen.dev"));
+        assert!(is_subdomain_of("bugen.dev", ""));
+        assert!(!is_subdomain_of("notbugen.dev", "bugen.dev"));
+        assert!(!is_subdomain_of("dev", "bugen.dev"));
+        // below an escaped dot, which is not a separator
+        assert!(!is_subdomain_of("a\\.bugen.dev", "bugen.dev"));
+        assert!(is_subdomain_of("a\\\\.bugen.dev", "bugen.dev"));
+        assert_eq!(labels("a\\.b\\\\.c"), ["a\\.b\\\\", "c"]);
+        assert_eq!(fqdn("a\\."), "a\\..");
+
+        // labels decoded lossily may hold multi-byte chars
+        assert!(!is_subdomain_of("\u{FFFD}", "om"));
+        assert!(!is_subdomain_of("x\u{FFFD}.com", "\u{FFFD}.com"));
+        assert!(is_subdomain_of("a.\u{FFFD}.com", "\u{FFFD}.com"));
+        assert!(is_subdomain_of("\u{FFFD}x.com", "com"));
+    }
+}
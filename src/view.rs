@@ -0,0 +1,186 @@
+use crate::acl::{self, Cidr};
+use crate::cache::Cache;
+use crate::config;
+use crate::dns_packet::DnsRecord;
+use crate::error::{Error, Result};
+use crate::forward::{ForwardRule, ForwardRules};
+use crate::inflight::InFlight;
+use crate::local::{self, LocalData};
+use crate::zone::{Catalog, ZoneSpec};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A view of the server for the clients from some networks, with its own data
+/// to answer and ways to resolve. Empty ACLs are the ones of the server.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ViewOptions {
+    pub name: String,
+    pub match_clients: Vec<Cidr>,
+    #[serde(default, deserialize_with = "config::deserialize_records")]
+    pub local_data: Vec<DnsRecord>,
+    #[serde(default)]
+    pub local_zones: Vec<String>,
+    #[serde(default)]
+    pub zones: Vec<ZoneSpec>,
+    #[serde(default)]
+    pub rules: Vec<ForwardRule>,
+    #[serde(default)]
+    pub allow_query: Vec<Cidr>,
+    #[serde(default)]
+    pub allow_recursion: Vec<Cidr>,
+    #[serde(default)]
+    pub allow_transfer: Vec<Cidr>,
+}
+
+/// Parse a view written as `<name>=<cidr>[,<cidr>...]`, without any data yet.
+impl FromStr for ViewOptions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |message: &str| Error::InvalidView(format!("`{}`: {}", s, message));
+        let mut parts = s.splitn(2, '=');
+        let (name, networks) = match (parts.next(), parts.next()) {
+            (Some(name), Some(networks)) if !name.is_empty() && !networks.is_empty() => {
+                (name, networks)
+            }
+            _ => return Err(invalid("not in the form of `<name>=<cidr>[,<cidr>...]`")),
+        };
+        let match_clients = networks
+            .split(',')
+            .map(|network| network.parse())
+            .collect::<Result<_>>()
+            .map_err(|e| invalid(&e.to_string()))?;
+        Ok(Self {
+            name: name.to_owned(),
+            match_clients,
+            ..Self::default()
+        })
+    }
+}
+
+/// An option of the view named before the first colon, like
+/// `internal:10.0.0.0/8`.
+#[derive(Clone, Debug)]
+pub struct Scoped<T> {
+    pub view: String,
+    pub value: T,
+}
+
+impl<T> Scoped<T> {
+    fn parse_with<E: fmt::Display>(
+        s: &str,
+        parse: impl Fn(&str) -> std::result::Result<T, E>,
+    ) -> Result<Self> {
+        let invalid = |message: &str| Error::InvalidView(format!("`{}`: {}", s, message));
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(view), Some(value)) if !view.is_empty() => Ok(Self {
+                view: view.to_owned(),
+                value: parse(value).map_err(|e| invalid(&e.to_string()))?,
+            }),
+            _ => Err(invalid("not in the form of `<view>:<value>`")),
+        }
+    }
+}
+
+impl<T> FromStr for Scoped<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse_with(s, T::from_str)
+    }
+}
+
+/// Parse a record of the local data of a view, like `internal:db A 10.0.0.5`.
+pub fn parse_scoped_record(s: &str) -> Result<Scoped<DnsRecord>> {
+    Scoped::parse_with(s, local::parse_record)
+}
+
+/// Move the scoped values into the field of the views they name.
+pub fn distribute<T>(
+    views: &mut [ViewOptions],
+    values: Vec<Scoped<T>>,
+    field: impl Fn(&mut ViewOptions) -> &mut Vec<T>,
+) -> Result<()> {
+    for scoped in values {
+        let view = views
+            .iter_mut()
+            .find(|view| view.name == scoped.view)
+            .ok_or_else(|| Error::InvalidView(format!("no view named `{}`", scoped.view)))?;
+        field(view).push(scoped.value);
+    }
+    Ok(())
+}
+
+/// The state of a view while serving.
+pub struct View {
+    pub name: String,
+    pub match_clients: Vec<Cidr>,
+    pub allow_query: Vec<Cidr>,
+    pub allow_recursion: Vec<Cidr>,
+    pub allow_transfer: Vec<Cidr>,
+    pub local: LocalData,
+    pub zones: Catalog,
+    pub rules: ForwardRules,
+    // answers may differ by the rules, so they are not shared with others, but
+    // kept across reloads
+    pub cache: Arc<Cache>,
+    pub inflight: InFlight,
+}
+
+impl View {
+    pub fn matches(&self, ip: IpAddr) -> bool {
+        acl::allows(&self.match_clients, ip)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_views() {
+        let view: ViewOptions = "internal=10.0.0.0/8,fd00::/8".parse().unwrap();
+        assert_eq!(view.name, "internal");
+        assert_eq!(view.match_clients.len(), 2);
+        assert!("internal".parse::<ViewOptions>().is_err());
+        assert!("=10.0.0.0/8".parse::<ViewOptions>().is_err());
+        assert!("internal=10.0.0.0/33".parse::<ViewOptions>().is_err());
+
+        let scoped: Scoped<Cidr> = "internal:fd00::/8".parse().unwrap();
+        assert_eq!(scoped.view, "internal");
+        assert_eq!(scoped.value.to_string(), "fd00::/8");
+        let scoped: Scoped<ZoneSpec> = "internal:bugen.dev=res/bugen.dev.zone".parse().unwrap();
+        assert_eq!(scoped.value.origin, "bugen.dev");
+        let scoped = parse_scoped_record("internal:db.internal A 10.0.0.5").unwrap();
+        assert_eq!(scoped.value.name(), "db.internal");
+        assert!("fd00::/8".parse::<Scoped<Cidr>>().is_err());
+        assert!(parse_scoped_record("internal:db.internal A").is_err());
+    }
+
+    #[test]
+    fn distribute_to_views() {
+        let mut views: Vec<ViewOptions> = vec![
+            "internal=10.0.0.0/8".parse().unwrap(),
+            "guest=192.168.0.0/16".parse().unwrap(),
+        ];
+        let zones = vec![
+            "guest:a.test=a.zone".parse().unwrap(),
+            "internal:b.test=b.zone".parse().unwrap(),
+            "guest:c.test=c.zone".parse().unwrap(),
+        ];
+        distribute(&mut views, zones, |view| &mut view.zones).unwrap();
+        assert_eq!(views[0].zones.len(), 1);
+        assert_eq!(views[1].zones[1].origin, "c.test");
+
+        let zones = vec!["other:a.test=a.zone".parse().unwrap()];
+        assert!(distribute(&mut views, zones, |view| &mut view.zones).is_err());
+    }
+}
@@ -0,0 +1,674 @@
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use crate::error::{Error, Result};
+use crate::journal::{soa_serial, Diff, Journal, MAX_DIFFS};
+use crate::utils::{is_subdomain_of, replace_file};
+use crate::zone_file::{parse_zone_file, write_zone};
+use log::*;
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The parent of `name`, or `None` for the root.
+fn parent(name: &str) -> Option<&str> {
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.find('.').map_or("", |i| &name[i + 1..]))
+    }
+}
+
+/// An authoritative zone held in memory.
+pub struct Zone {
+    pub origin: String,
+    // lowercased owner -> records
+    records: BTreeMap<String, Vec<DnsRecord>>,
+    // all owners and the empty non-terminals between them and the origin
+    names: HashSet<String>,
+    serial: u32,
+    journal: Journal,
+}
+
+impl Zone {
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> Result<Self> {
+        let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+        let mut zone = Self {
+            origin,
+            records: BTreeMap::new(),
+            names: HashSet::new(),
+            serial: 0,
+            journal: Journal::new(),
+        };
+
+        for record in records {
+            if !is_subdomain_of(record.name(), &zone.origin) {
+                return Err(Error::InvalidZone(format!(
+                    "`{}` is out of zone `{}`",
+                    record.name(),
+                    zone.origin
+                )));
+            }
+            let owner = record.name().to_ascii_lowercase();
+            let mut name = owner.as_str();
+            while zone.names.insert(name.to_owned()) && name != zone.origin {
+                name = parent(name).unwrap();
+            }
+            zone.records.entry(owner).or_default().push(record);
+        }
+
+        zone.serial = match zone.soa() {
+            Some(soa) => soa_serial(soa)?,
+            None => {
+                return Err(Error::InvalidZone(format!(
+                    "no SOA record at `{}`",
+                    zone.origin
+                )))
+            }
+        };
+        Ok(zone)
+    }
+
+    /// Attach the history of this zone, which must end at its current serial.
+    pub fn with_journal(mut self, journal: Journal) -> Result<Self> {
+        match journal.last_serial() {
+            Some(serial) if serial != self.serial() => Err(Error::InvalidZone(format!(
+                "journal of `{}` ends at serial {} instead of {}",
+                self.origin,
+                serial,
+                self.serial()
+            ))),
+            _ => {
+                self.journal = journal;
+                Ok(self)
+            }
+        }
+    }
+
+    pub fn soa(&self) -> Option<&DnsRecord> {
+        self.rrset(&self.origin, QueryType::SOA).next()
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    /// All records of this zone except the SOA one.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records
+            .values()
+            .flatten()
+            .filter(|r| r.query_type() != QueryType::SOA)
+    }
+
+    /// The next version of this zone with `diff` applied, which is recorded in
+    /// the journal.
+    pub fn apply(&self, diff: &Diff) -> Result<Zone> {
+        if diff.old_serial() != self.serial() {
+            return Err(Error::InvalidZone(format!(
+                "diff from serial {} does not apply to `{}` at serial {}",
+                diff.old_serial(),
+                self.origin,
+                self.serial()
+            )));
+        }
+
+        let mut records: Vec<_> = self.records().cloned().collect();
+        for removed in diff.removed.iter() {
+            match records.iter().position(|r| r == removed) {
+                Some(index) => drop(records.remove(index)),
+                None => {
+                    return Err(Error::InvalidZone(format!(
+                        "`{}` to remove is not in `{}`",
+                        removed, self.origin
+                    )))
+                }
+            }
+        }
+        records.extend(diff.added.iter().cloned());
+        records.push(diff.new_soa().clone());
+
+        let mut journal = self.journal.clone();
+        journal.append(diff.clone())?;
+        Zone::new(&self.origin, records)?.with_journal(journal)
+    }
+
+    /// The changes from this version of the zone to `new`.
+    pub fn diff(&self, new: &Zone) -> Result<Diff> {
+        let old_records: Vec<_> = self.records().collect();
+        let new_records: Vec<_> = new.records().collect();
+        let mut diff = Diff::new(self.soa().unwrap().clone(), new.soa().unwrap().clone())?;
+        diff.removed = old_records
+            .iter()
+            .filter(|r| !new_records.contains(r))
+            .map(|&r| r.clone())
+            .collect();
+        diff.added = new_records
+            .iter()
+            .filter(|r| !old_records.contains(r))
+            .map(|&r| r.clone())
+            .collect();
+        Ok(diff)
+    }
+
+    fn rrset<'a>(
+        &'a self,
+        name: &str,
+        query_type: QueryType,
+    ) -> impl Iterator<Item = &'a DnsRecord> + 'a {
+        self.records
+            .get(&name.to_ascii_lowercase())
+            .into_iter()
+            .flatten()
+            .filter(move |r| r.query_type() == query_type)
+    }
+
+    /// The SOA record to put into negative answers, whose TTL is the one of
+    /// negative caching as in RFC 2308.
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().unwrap().clone();
+        if let DnsRecord::SOA { ttl, minimum, .. } = soa {
+            soa.set_ttl(ttl.min(minimum));
+        }
+        soa
+    }
+
+    /// The topmost zone cut between the origin and `name`, excluding the origin.
+    fn find_delegation(&self, name: &str) -> Option<String> {
+        let mut cut = None;
+        let mut current = Some(name);
+        while let Some(name) = current {
+            if name.len() <= self.origin.len() {
+                break;
+            }
+            if self.rrset(name, QueryType::NS).next().is_some() {
+                cut = Some(name.to_owned());
+            }
+            current = parent(name);
+        }
+        cut
+    }
+
+    /// The wildcard records at the closest encloser of `name`, the nearest
+    /// ancestor that exists in the zone.
+    fn find_wildcard(&self, name: &str) -> Option<&Vec<DnsRecord>> {
+        let mut current = parent(name);
+        while let Some(encloser) = current {
+            if self.names.contains(encloser) {
+                let wildcard = format!("*.{}", encloser);
+                return self.records.get(wildcard.trim_end_matches('.'));
+            }
+            if encloser.len() <= self.origin.len() {
+                break;
+            }
+            current = parent(encloser);
+        }
+        None
+    }
+
+    /// Add the addresses of `hosts` in this zone into the additional section.
+    fn add_glue(&self, packet: &mut DnsPacket, hosts: &[String]) {
+        for host in hosts {
+            for query_type in [QueryType::A, QueryType::AAAA].iter() {
+                packet
+                    .resources
+                    .extend(self.rrset(host, *query_type).cloned());
+            }
+        }
+    }
+
+    /// Answer `question` with the data of this zone, following RFC 1034,
+    /// section 4.3.2, and RFC 4592 for wildcards.
+    pub fn lookup(&self, question: &DnsQuestion) -> DnsPacket {
+        let mut packet = DnsPacket::default();
+        packet.questions.push(question.clone());
+        packet.header.authoritative_answer = true;
+
+        let query_type = question.query_type;
+        let mut name = question.name.to_ascii_lowercase();
+        for _ in 0..MAX_CNAME_CHAIN {
+            if !is_subdomain_of(&name, &self.origin) {
+                // CNAME out of zone, left for the resolver of the client
+                break;
+            }
+
+            if let Some(cut) = self.find_delegation(&name) {
+                // referral, which is not authoritative
+                let nss: Vec<_> = self.rrset(&cut, QueryType::NS).cloned().collect();
+                let hosts: Vec<_> = nss
+                    .iter()
+                    .filter_map(|ns| match ns {
+                        DnsRecord::NS { host, .. } => Some(host.to_ascii_lowercase()),
+                        _ => None,
+                    })
+                    .collect();
+                packet.header.authoritative_answer = !packet.answers.is_empty();
+                packet.authorities.extend(nss);
+                self.add_glue(&mut packet, &hosts);
+                break;
+            }
+
+            let records = match self.records.get(&name) {
+                Some(records) => records.clone(),
+                None if self.names.contains(&name) => vec![], // empty non-terminal
+                None => match self.find_wildcard(&name) {
+                    Some(records) => records
+                        .iter()
+                        .cloned()
+                        .map(|r| synthesize(r, &name))
+                        .collect(),
+                    None => {
+                        packet.header.rescode = ResultCode::NXDOMAIN;
+                        packet.authorities.push(self.negative_soa());
+                        break;
+                    }
+                },
+            };
+
+            let cname = records
+                .iter()
+                .find(|r| r.query_type() == QueryType::CNAME)
+                .cloned();
+            if let Some(cname @ DnsRecord::CNAME { .. }) = cname {
+                if query_type != QueryType::CNAME {
+                    if let DnsRecord::CNAME { ref host, .. } = cname {
+                        name = host.to_ascii_lowercase();
+                    }
+                    packet.answers.push(cname);
+                    continue;
+                }
+            }
+
+            let answers: Vec<_> = records
+                .into_iter()
+                .filter(|r| r.query_type() == query_type)
+                .collect();
+            if answers.is_empty() {
+                packet.authorities.push(self.negative_soa());
+            } else {
+                let hosts: Vec<_> = answers
+                    .iter()
+                    .filter_map(|r| match r {
+                        DnsRecord::NS { host, .. } | DnsRecord::MX { host, .. } => {
+                            Some(host.to_ascii_lowercase())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                packet.answers.extend(answers);
+                self.add_glue(&mut packet, &hosts);
+            }
+            break;
+        }
+
+        packet.update_header_counts();
+        packet
+    }
+}
+
+/// Make a record synthesized from a wildcard owned by `name`.
+pub fn synthesize(mut record: DnsRecord, name: &str) -> DnsRecord {
+    match &mut record {
+        DnsRecord::A { name: owner, .. }
+        | DnsRecord::NS { name: owner, .. }
+        | DnsRecord::CNAME { name: owner, .. }
+        | DnsRecord::SOA { name: owner, .. }
+        | DnsRecord::MX { name: owner, .. }
+        | DnsRecord::TXT { name: owner, .. }
+        | DnsRecord::AAAA { name: owner, .. }
+        | DnsRecord::TSIG { name: owner, .. } => *owner = name.to_owned(),
+    }
+    record
+}
+
+/// A zone to be loaded from a master file, written as `<origin>=<path>`.
+#[derive(Clone, Debug)]
+pub struct ZoneSpec {
+    pub origin: String,
+    pub path: String,
+}
+
+impl FromStr for ZoneSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(origin), Some(path)) if !path.is_empty() => Ok(Self {
+                origin: origin.trim_end_matches('.').to_owned(),
+                path: path.to_owned(),
+            }),
+            _ => Err(Error::InvalidZone(format!(
+                "`{}` is not in the form of `<origin>=<path>`",
+                s
+            ))),
+        }
+    }
+}
+
+impl ZoneSpec {
+    /// The journal of a zone is kept next to its master file, with the
+    /// extension `.jnl` appended.
+    pub fn journal_path(&self) -> String {
+        format!("{}.jnl", self.path)
+    }
+
+    /// Load the zone from its master file, and replay the changes in the
+    /// journal made after it, like by dynamic updates.
+    pub fn load(&self) -> Result<Zone> {
+        let records = parse_zone_file(self.path.as_ref(), &self.origin)?;
+        let zone = Zone::new(&self.origin, records)?;
+
+        let journal = Journal::load(self.journal_path().as_ref(), &self.origin)?;
+        let (history, pending) = match journal.split_at(zone.serial()) {
+            Some(split) => split,
+            None => {
+                // the master file has been edited by hand since
+                warn!(
+                    "Ignoring the journal of {} which does not pass serial {}",
+                    zone.origin,
+                    zone.serial()
+                );
+                return Ok(zone);
+            }
+        };
+
+        let mut zone = zone.with_journal(history)?;
+        for diff in pending.iter() {
+            zone = zone.apply(diff)?;
+        }
+        Ok(zone)
+    }
+
+    /// Persist a new version of this zone, like after a dynamic update, to the
+    /// journal. Once the journal grows beyond `MAX_DIFFS`, the zone is written
+    /// to its master file first, so that the oldest diffs can be trimmed.
+    /// Returns the zone with its journal as saved.
+    pub fn save(&self, mut zone: Zone) -> Result<Zone> {
+        if zone.journal.diffs().len() > MAX_DIFFS {
+            let records: Vec<_> = zone
+                .soa()
+                .into_iter()
+                .chain(zone.records())
+                .cloned()
+                .collect();
+            replace_file(self.path.as_ref(), &write_zone(&zone.origin, &records))?;
+            zone.journal.trim();
+        }
+        zone.journal.save(self.journal_path().as_ref())?;
+        Ok(zone)
+    }
+}
+
+/// The zones served authoritatively, which may be replaced while serving.
+pub struct Catalog {
+    zones: RwLock<Vec<Arc<Zone>>>,
+}
+
+impl Catalog {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        Self {
+            zones: RwLock::new(zones.into_iter().map(Arc::new).collect()),
+        }
+    }
+
+    /// Find the most specific zone that `name` belongs to.
+    pub fn find(&self, name: &str) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|z| is_subdomain_of(name, &z.origin))
+            .max_by_key(|z| z.origin.len())
+            .cloned()
+    }
+
+    /// Get the zone of exactly `origin`.
+    pub fn get(&self, origin: &str) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .find(|z| z.origin.eq_ignore_ascii_case(origin.trim_end_matches('.')))
+            .cloned()
+    }
+
+    /// Add a zone, replacing the one with the same origin.
+    pub fn insert(&self, zone: impl Into<Arc<Zone>>) {
+        let zone = zone.into();
+        let mut zones = self.zones.write().unwrap();
+        zones.retain(|z| !z.origin.eq_ignore_ascii_case(&zone.origin));
+        zones.push(zone);
+    }
+
+    pub fn remove(&self, origin: &str) -> Option<Arc<Zone>> {
+        let mut zones = self.zones.write().unwrap();
+        let index = zones
+            .iter()
+            .position(|z| z.origin.eq_ignore_ascii_case(origin.trim_end_matches('.')))?;
+        Some(zones.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zone_file::parse_zone;
+
+    lazy_static! {
+        static ref ZONE: Zone = Zone::new(
+            "bugen.dev",
+            parse_zone(include_str!("../res/bugen.dev.zone"), "bugen.dev").unwrap()
+        )
+        .unwrap();
+    }
+
+    fn lookup(name: &str, query_type: QueryType) -> DnsPacket {
+        ZONE.lookup(&DnsQuestion {
+            name: name.into(),
+            query_type,
+        })
+    }
+
+    #[test]
+    fn exact_match() {
+        let packet = lookup("WWW.bugen.dev", QueryType::A);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers.len(), 2);
+    }
+
+    #[test]
+    fn mx_with_additional() {
+        let packet = lookup("bugen.dev", QueryType::MX);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.resources.len(), 1);
+        assert_eq!(packet.resources[0].name(), "mail.bugen.dev");
+    }
+
+    #[test]
+    fn cname_chain() {
+        let packet = lookup("blog.bugen.dev", QueryType::A);
+        assert_eq!(packet.answers.len(), 4);
+        assert_eq!(packet.answers[1].query_type(), QueryType::CNAME);
+        assert_eq!(packet.answers[2].name(), "www.bugen.dev");
+        assert_eq!(packet.answers[3].query_type(), QueryType::A);
+
+        let packet = lookup("blog.bugen.dev", QueryType::CNAME);
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn referral_with_glue() {
+        let packet = lookup("host.sub.bugen.dev", QueryType::A);
+        assert!(!packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities.len(), 1);
+        assert_eq!(packet.authorities[0].query_type(), QueryType::NS);
+        assert_eq!(packet.resources.len(), 1);
+        assert_eq!(packet.resources[0].name(), "ns.sub.bugen.dev");
+    }
+
+    #[test]
+    fn nodata() {
+        let packet = lookup("www.bugen.dev", QueryType::MX);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].query_type(), QueryType::SOA);
+        assert_eq!(packet.authorities[0].ttl(), 300);
+
+        // empty non-terminal
+        let packet = lookup("ent.bugen.dev", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn nxdomain() {
+        let packet = lookup("nope.bugen.dev", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.authorities[0].query_type(), QueryType::SOA);
+    }
+
+    #[test]
+    fn wildcard() {
+        let packet = lookup("a.b.wild.bugen.dev", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].name(), "a.b.wild.bugen.dev");
+
+        let packet = lookup("x.wild.bugen.dev", QueryType::AAAA);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+
+        // the closest encloser has no wildcard
+        let packet = lookup("x.host.ent.bugen.dev", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        let packet = lookup("x.ent.bugen.dev", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+    }
+
+    #[test]
+    fn catalog() {
+        let catalog = Catalog::new(vec![Zone::new(
+            "dev",
+            parse_zone("@ 60 SOA ns admin 1 2 3 4 5", "dev").unwrap(),
+        )
+        .unwrap()]);
+        assert_eq!(catalog.find("bugen.dev").unwrap().origin, "dev");
+        assert!(catalog.find("bugen.com").is_none());
+
+        catalog.insert(Zone::new("bugen.dev", ZONE.soa().into_iter().cloned().collect()).unwrap());
+        assert_eq!(catalog.find("www.bugen.dev").unwrap().origin, "bugen.dev");
+        assert!(catalog.get("bugen.dev.").is_some());
+        assert!(catalog.remove("bugen.dev").is_some());
+        assert_eq!(catalog.find("www.bugen.dev").unwrap().origin, "dev");
+
+        // replacing a zone regardless of case
+        catalog.insert(
+            Zone::new(
+                "DEV",
+                parse_zone("@ 60 SOA ns admin 2 2 3 4 5", "DEV").unwrap(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(catalog.get("dev").unwrap().serial(), 2);
+        assert!(catalog.remove("dev").is_some());
+        assert!(catalog.find("bugen.dev").is_none());
+    }
+
+    #[test]
+    fn diff_versions() {
+        let new_zone = Zone::new(
+            "bugen.dev",
+            ZONE.records()
+                .filter(|r| r.name() != "mail.bugen.dev")
+                .cloned()
+                .chain(
+                    parse_zone(
+                        "@ 60 SOA ns1 admin 2020110102 2 3 4 5\nnew 60 A 10.0.0.9",
+                        "bugen.dev",
+                    )
+                    .unwrap(),
+                )
+                .collect(),
+        )
+        .unwrap();
+
+        let diff = ZONE.diff(&new_zone).unwrap();
+        assert_eq!(diff.old_serial(), 2020110101);
+        assert_eq!(diff.new_serial(), 2020110102);
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].name(), "mail.bugen.dev");
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].name(), "new.bugen.dev");
+
+        let applied = ZONE.apply(&diff).unwrap();
+        assert_eq!(applied.serial(), 2020110102);
+        assert_eq!(applied.records().count(), new_zone.records().count());
+        let question = DnsQuestion {
+            name: "new.bugen.dev".into(),
+            query_type: QueryType::A,
+        };
+        assert_eq!(applied.lookup(&question).answers.len(), 1);
+        assert!(applied.apply(&diff).is_err());
+
+        let mut journal = Journal::new();
+        journal.append(diff).unwrap();
+        let new_zone = new_zone.with_journal(journal.clone()).unwrap();
+        assert_eq!(new_zone.journal().since(2020110101).unwrap().len(), 1);
+        assert!(
+            Zone::new("bugen.dev", ZONE.soa().into_iter().cloned().collect())
+                .unwrap()
+                .with_journal(journal)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn save_and_reload_updates() {
+        let dir = std::env::temp_dir().join(format!("dnser-zone-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bugen.dev.zone");
+        std::fs::copy("res/bugen.dev.zone", &path).unwrap();
+        let spec = ZoneSpec {
+            origin: "bugen.dev".into(),
+            path: path.to_str().unwrap().into(),
+        };
+
+        // beyond the diffs kept in the journal
+        let mut zone = spec.load().unwrap();
+        let updates = MAX_DIFFS as u32 + 5;
+        for i in 0..updates {
+            let mut soa = zone.soa().unwrap().clone();
+            if let DnsRecord::SOA { serial, .. } = &mut soa {
+                *serial += 1;
+            }
+            let mut diff = Diff::new(zone.soa().unwrap().clone(), soa).unwrap();
+            let text = format!("host{} 60 A 10.1.0.1", i);
+            diff.added = parse_zone(&text, "bugen.dev").unwrap();
+            zone = spec.save(zone.apply(&diff).unwrap()).unwrap();
+        }
+        assert_eq!(zone.journal().diffs().len(), MAX_DIFFS);
+
+        let loaded = spec.load().unwrap();
+        assert_eq!(loaded.serial(), ZONE.serial() + updates);
+        assert_eq!(
+            loaded.records().count(),
+            ZONE.records().count() + updates as usize
+        );
+        assert_eq!(loaded.journal().diffs(), zone.journal().diffs());
+        let question = DnsQuestion {
+            name: "host0.bugen.dev".into(),
+            query_type: QueryType::A,
+        };
+        assert_eq!(loaded.lookup(&question).answers.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
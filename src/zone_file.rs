@@ -0,0 +1,777 @@
+use crate::dns_packet::{DnsRecord, QueryType};
+use crate::error::{Error, Result};
+use crate::utils::{fqdn, labels, unescape_label};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    quoted: bool,
+    line: usize,
+    column: usize,
+}
+
+/// A logical line of a master file, which may span several physical lines
+/// within parentheses.
+struct Entry {
+    tokens: Vec<Token>,
+    // whether the line starts with a blank, meaning the last owner is reused
+    blank_owner: bool,
+}
+
+struct Lexer<'a> {
+    file: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer<'_> {
+    fn error(&self, line: usize, column: usize, message: impl Into<String>) -> Error {
+        Error::ZoneFile {
+            file: self.file.to_owned(),
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    /// Read the character after a backslash into `bytes`, decoding `\DDD`
+    /// into a single byte. Dots and backslashes are kept escaped in names, so
+    /// that they are not taken as the separators of labels.
+    fn escaped(&mut self, bytes: &mut Vec<u8>, name: bool) -> Result<()> {
+        let (line, column) = (self.line, self.column);
+        let digits: String = self.chars[self.pos..]
+            .iter()
+            .take(3)
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        let b = if digits.len() == 3 {
+            self.pos += 3;
+            self.column += 3;
+            digits
+                .parse::<u8>()
+                .map_err(|_| self.error(line, column, format!("bad escape `\\{}`", digits)))?
+        } else {
+            match self.bump() {
+                Some(c) if c.is_ascii() => c as u8,
+                Some(c) => {
+                    push_char(bytes, c);
+                    return Ok(());
+                }
+                None => return Err(self.error(line, column, "unexpected end of file after `\\`")),
+            }
+        };
+        if name && (b == b'.' || b == b'\\') {
+            bytes.push(b'\\');
+        }
+        bytes.push(b);
+        Ok(())
+    }
+
+    /// The text of a token read as `bytes`.
+    fn text(&self, bytes: Vec<u8>, line: usize, column: usize) -> Result<String> {
+        String::from_utf8(bytes)
+            .map_err(|_| self.error(line, column, "escaped bytes are not valid UTF-8"))
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut tokens = Vec::new();
+        let mut blank_owner = false;
+        let mut line_start = true;
+        // position of the outermost open parenthesis
+        let mut open_paren: Vec<(usize, usize)> = Vec::new();
+
+        while let Some(c) = self.peek() {
+            let (line, column) = (self.line, self.column);
+            if c == '\n' {
+                self.bump();
+                if open_paren.is_empty() {
+                    if !tokens.is_empty() {
+                        entries.push(Entry {
+                            tokens: std::mem::take(&mut tokens),
+                            blank_owner,
+                        });
+                    }
+                    line_start = true;
+                }
+                continue;
+            }
+            if line_start {
+                blank_owner = c == ' ' || c == '\t';
+                line_start = false;
+            }
+
+            match c {
+                ';' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                }
+                '(' => {
+                    self.bump();
+                    open_paren.push((line, column));
+                }
+                ')' => {
+                    self.bump();
+                    if open_paren.pop().is_none() {
+                        return Err(self.error(line, column, "unbalanced `)`"));
+                    }
+                }
+                '"' => {
+                    self.bump();
+                    let mut bytes = Vec::new();
+                    loop {
+                        match self.bump() {
+                            Some('"') => break,
+                            Some('\\') => self.escaped(&mut bytes, false)?,
+                            Some(c) => push_char(&mut bytes, c),
+                            None => return Err(self.error(line, column, "unterminated string")),
+                        }
+                    }
+                    tokens.push(Token {
+                        text: self.text(bytes, line, column)?,
+                        quoted: true,
+                        line,
+                        column,
+                    });
+                }
+                c if c.is_whitespace() => {
+                    self.bump();
+                }
+                _ => {
+                    let mut bytes = Vec::new();
+                    while let Some(c) = self.peek() {
+                        if c.is_whitespace() || ";()\"".contains(c) {
+                            break;
+                        }
+                        self.bump();
+                        if c == '\\' {
+                            self.escaped(&mut bytes, true)?;
+                        } else {
+                            push_char(&mut bytes, c);
+                        }
+                    }
+                    tokens.push(Token {
+                        text: self.text(bytes, line, column)?,
+                        quoted: false,
+                        line,
+                        column,
+                    });
+                }
+            }
+        }
+
+        if let Some(&(line, column)) = open_paren.first() {
+            return Err(self.error(line, column, "unbalanced `(`"));
+        }
+        if !tokens.is_empty() {
+            entries.push(Entry {
+                tokens,
+                blank_owner,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+fn push_char(bytes: &mut Vec<u8>, c: char) {
+    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+/// Parse a TTL, either in seconds or in the BIND form like `1h30m`.
+fn parse_ttl(text: &str) -> Option<u32> {
+    if let Ok(ttl) = text.parse() {
+        return Some(ttl);
+    }
+
+    let mut total: u32 = 0;
+    let mut number: Option<u32> = None;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(number.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(number.take()?.checked_mul(unit)?)?;
+    }
+    match number {
+        Some(_) => None,
+        None => Some(total),
+    }
+}
+
+struct Parser {
+    file: String,
+    dir: PathBuf,
+    origin: String,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    depth: usize,
+}
+
+impl Parser {
+    fn error(&self, token: &Token, message: impl Into<String>) -> Error {
+        Error::ZoneFile {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    /// Make `name` absolute, without the trailing dot.
+    fn absolute_name(&self, token: &Token) -> Result<String> {
+        let name = token.text.as_str();
+        match name {
+            "@" if !token.quoted => return Ok(self.origin.clone()),
+            "." if !token.quoted => return Ok(String::new()),
+            _ => {}
+        }
+        // with a trailing empty label if absolute
+        let labels = labels(name);
+        let (last, labels) = labels.split_last().unwrap();
+        if token.quoted || name.is_empty() || labels.iter().any(|l| l.is_empty()) {
+            return Err(self.error(token, format!("bad name `{}`", name)));
+        }
+        Ok(if last.is_empty() {
+            name[..name.len() - 1].to_owned()
+        } else if self.origin.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}.{}", name, self.origin)
+        })
+    }
+
+    fn parse_number<T: std::str::FromStr>(&self, token: &Token) -> Result<T> {
+        token
+            .text
+            .parse()
+            .map_err(|_| self.error(token, format!("bad number `{}`", token.text)))
+    }
+
+    fn parse_addr<T: std::str::FromStr>(&self, token: &Token) -> Result<T> {
+        token
+            .text
+            .parse()
+            .map_err(|_| self.error(token, format!("bad address `{}`", token.text)))
+    }
+
+    fn parse(&mut self, entries: Vec<Entry>) -> Result<Vec<DnsRecord>> {
+        let mut records = Vec::new();
+        for entry in entries {
+            let first = &entry.tokens[0];
+            if !entry.blank_owner && !first.quoted && first.text.starts_with('$') {
+                records.extend(self.parse_directive(&entry.tokens)?);
+            } else {
+                records.push(self.parse_record(&entry)?);
+            }
+        }
+        Ok(records)
+    }
+
+    fn parse_directive(&mut self, tokens: &[Token]) -> Result<Vec<DnsRecord>> {
+        let directive = &tokens[0];
+        let args = &tokens[1..];
+        let expect_args = |min: usize, max: usize| {
+            if args.len() < min || args.len() > max {
+                let token = args.get(max).unwrap_or(directive);
+                Err(self.error(
+                    token,
+                    format!("wrong number of arguments for `{}`", directive.text),
+                ))
+            } else {
+                Ok(())
+            }
+        };
+
+        match directive.text.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => {
+                expect_args(1, 1)?;
+                self.origin = self.absolute_name(&args[0])?;
+            }
+            "$TTL" => {
+                expect_args(1, 1)?;
+                let ttl = parse_ttl(&args[0].text)
+                    .ok_or_else(|| self.error(&args[0], format!("bad TTL `{}`", args[0].text)))?;
+                self.default_ttl = Some(ttl);
+            }
+            "$INCLUDE" => {
+                expect_args(1, 2)?;
+                if self.depth >= MAX_INCLUDE_DEPTH {
+                    return Err(self.error(directive, "too many nested `$INCLUDE`s"));
+                }
+                let path = self.dir.join(&args[0].text);
+                let text = std::fs::read_to_string(&path).map_err(|e| {
+                    self.error(
+                        &args[0],
+                        format!("cannot include `{}`: {}", args[0].text, e),
+                    )
+                })?;
+                // the origin of this file is not changed by the included one
+                let origin = match args.get(1) {
+                    Some(origin) => self.absolute_name(origin)?,
+                    None => self.origin.clone(),
+                };
+                let mut parser = Parser {
+                    file: path.display().to_string(),
+                    dir: path.parent().map(Path::to_owned).unwrap_or_default(),
+                    origin,
+                    default_ttl: self.default_ttl,
+                    last_ttl: self.last_ttl,
+                    last_owner: None,
+                    depth: self.depth + 1,
+                };
+                return parser.parse_text(&text);
+            }
+            _ => {
+                return Err(self.error(directive, format!("unknown directive `{}`", directive.text)))
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn parse_text(&mut self, text: &str) -> Result<Vec<DnsRecord>> {
+        let lexer = Lexer {
+            file: &self.file,
+            chars: text.chars().collect(),
+            pos: 0,
+            line: 1,
+            column: 1,
+        };
+        let entries = lexer.tokenize()?;
+        self.parse(entries)
+    }
+
+    fn parse_record(&mut self, entry: &Entry) -> Result<DnsRecord> {
+        let mut tokens = &entry.tokens[..];
+
+        let name = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or_else(|| self.error(&tokens[0], "no owner name"))?
+        } else {
+            let name = self.absolute_name(&tokens[0])?;
+            tokens = &tokens[1..];
+            name
+        };
+        self.last_owner = Some(name.clone());
+
+        // TTL and class can be given in any order
+        let mut ttl = None;
+        let type_token = loop {
+            let token = tokens
+                .first()
+                .ok_or_else(|| self.error(entry.tokens.last().unwrap(), "missing record type"))?;
+            tokens = &tokens[1..];
+            if token.text.eq_ignore_ascii_case("IN") {
+                continue;
+            } else if token.text.starts_with(|c: char| c.is_ascii_digit()) {
+                let parsed = parse_ttl(&token.text)
+                    .ok_or_else(|| self.error(token, format!("bad TTL `{}`", token.text)))?;
+                ttl = Some(parsed);
+            } else {
+                break token;
+            }
+        };
+        let query_type = match type_token.text.parse::<QueryType>() {
+            Ok(QueryType::Unknown)
+            | Ok(QueryType::IXFR)
+            | Ok(QueryType::AXFR)
+            | Ok(QueryType::ANY)
+            | Ok(QueryType::TSIG)
+            | Err(_) => {
+                return Err(self.error(
+                    type_token,
+                    format!("unsupported record type `{}`", type_token.text),
+                ))
+            }
+            Ok(query_type) => query_type,
+        };
+
+        let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+            Some(ttl) => ttl,
+            None => return Err(self.error(type_token, "no TTL specified")),
+        };
+        self.last_ttl = Some(ttl);
+
+        self.parse_rdata(name, ttl, query_type, type_token, tokens)
+    }
+
+    fn parse_rdata(
+        &self,
+        name: String,
+        ttl: u32,
+        query_type: QueryType,
+        type_token: &Token,
+        rdata: &[Token],
+    ) -> Result<DnsRecord> {
+        let expect = |n: usize| match rdata.len().cmp(&n) {
+            Ordering::Equal => Ok(()),
+            Ordering::Less => Err(self.error(
+                rdata.last().unwrap_or(type_token),
+                format!("missing fields of {:?} record", query_type),
+            )),
+            Ordering::Greater => Err(self.error(
+                &rdata[n],
+                format!("too many fields of {:?} record", query_type),
+            )),
+        };
+        let record = match query_type {
+            QueryType::A => {
+                expect(1)?;
+                let addr = self.parse_addr(&rdata[0])?;
+                DnsRecord::A { name, addr, ttl }
+            }
+            QueryType::AAAA => {
+                expect(1)?;
+                let addr = self.parse_addr(&rdata[0])?;
+                DnsRecord::AAAA { name, addr, ttl }
+            }
+            QueryType::NS => {
+                expect(1)?;
+                let host = self.absolute_name(&rdata[0])?;
+                DnsRecord::NS { name, host, ttl }
+            }
+            QueryType::CNAME => {
+                expect(1)?;
+                let host = self.absolute_name(&rdata[0])?;
+                DnsRecord::CNAME { name, host, ttl }
+            }
+            QueryType::MX => {
+                expect(2)?;
+                DnsRecord::MX {
+                    name,
+                    preference: self.parse_number(&rdata[0])?,
+                    host: self.absolute_name(&rdata[1])?,
+                    ttl,
+                }
+            }
+            QueryType::SOA => {
+                expect(7)?;
+                let parse_time = |token: &Token| {
+                    parse_ttl(&token.text)
+                        .ok_or_else(|| self.error(token, format!("bad time `{}`", token.text)))
+                };
+                DnsRecord::SOA {
+                    name,
+                    mname: self.absolute_name(&rdata[0])?,
+                    rname: self.absolute_name(&rdata[1])?,
+                    serial: self.parse_number(&rdata[2])?,
+                    refresh: parse_time(&rdata[3])?,
+                    retry: parse_time(&rdata[4])?,
+                    expire: parse_time(&rdata[5])?,
+                    minimum: parse_time(&rdata[6])?,
+                    ttl,
+                }
+            }
+            QueryType::TXT => {
+                if rdata.is_empty() {
+                    expect(1)?;
+                }
+                let data = rdata
+                    .iter()
+                    .map(|token| {
+                        // unquoted ones are lexed like names
+                        let text = if token.quoted {
+                            token.text.clone()
+                        } else {
+                            String::from_utf8_lossy(&unescape_label(&token.text)).into()
+                        };
+                        match text.len() {
+                            0..=255 => Ok(text),
+                            _ => Err(self.error(token, "character string longer than 255 bytes")),
+                        }
+                    })
+                    .collect::<Result<_>>()?;
+                DnsRecord::TXT { name, data, ttl }
+            }
+            QueryType::Unknown
+            | QueryType::TSIG
+            | QueryType::IXFR
+            | QueryType::AXFR
+            | QueryType::ANY => {
+                return Err(self.error(
+                    type_token,
+                    format!("unsupported record type `{}`", type_token.text),
+                ))
+            }
+        };
+        Ok(record)
+    }
+}
+
+/// Parse the records of an RFC 1035 master file. Relative names are completed
+/// with `origin`, until changed by `$ORIGIN`, and `$INCLUDE`d files are found
+/// relative to the working directory.
+pub fn parse_zone(text: &str, origin: &str) -> Result<Vec<DnsRecord>> {
+    Parser {
+        file: "<zone>".into(),
+        dir: PathBuf::new(),
+        origin: origin.trim_end_matches('.').to_owned(),
+        default_ttl: None,
+        last_ttl: None,
+        last_owner: None,
+        depth: 0,
+    }
+    .parse_text(text)
+}
+
+/// Parse the master file at `path`, whose `$INCLUDE`d files are found relative
+/// to the directory of it.
+pub fn parse_zone_file(path: &Path, origin: &str) -> Result<Vec<DnsRecord>> {
+    let text = std::fs::read_to_string(path)?;
+    Parser {
+        file: path.display().to_string(),
+        dir: path.parent().map(Path::to_owned).unwrap_or_default(),
+        origin: origin.trim_end_matches('.').to_owned(),
+        default_ttl: None,
+        last_ttl: None,
+        last_owner: None,
+        depth: 0,
+    }
+    .parse_text(&text)
+}
+
+/// Compare names in the canonical order of RFC 4034, section 6.1.
+/// Labels are compared as lowercased bytes, without the escapes of names.
+fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let key = |name: &str| -> Vec<Vec<u8>> {
+        labels(&name.to_ascii_lowercase())
+            .into_iter()
+            .rev()
+            .map(unescape_label)
+            .collect()
+    };
+    key(a).cmp(&key(b))
+}
+
+/// Write the canonical master file of a zone: the SOA record comes first, and
+/// the others are sorted by name in the canonical order, then by type.
+pub fn write_zone(origin: &str, records: &[DnsRecord]) -> String {
+    let mut records: Vec<_> = records.iter().collect();
+    records.sort_by(|a, b| {
+        let not_soa = |r: &DnsRecord| r.query_type() != QueryType::SOA;
+        not_soa(a)
+            .cmp(&not_soa(b))
+            .then_with(|| canonical_cmp(a.name(), b.name()))
+            .then_with(|| (a.query_type() as u16).cmp(&(b.query_type() as u16)))
+    });
+
+    let mut text = format!("$ORIGIN {}\n", fqdn(origin));
+    for record in records {
+        text.push_str(&record.to_string());
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_simple_zone() {
+        let text = "
+$ORIGIN bugen.dev.
+$TTL 3600
+@       IN  SOA ns1 admin.bugen.dev. 1 7200 3600 1209600 300
+        IN  NS  ns1     ; inherits the owner
+ns1     60  A   10.0.0.1
+www     IN 120  CNAME @
+@           MX  10 mail.bugenzhao.com.
+v6          AAAA ::1
+";
+        let records = parse_zone(text, "").unwrap();
+        assert_eq!(records.len(), 6);
+        assert_eq!(
+            records[1],
+            DnsRecord::NS {
+                name: "bugen.dev".into(),
+                host: "ns1.bugen.dev".into(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(records[2].ttl(), 60);
+        assert_eq!(
+            records[3],
+            DnsRecord::CNAME {
+                name: "www.bugen.dev".into(),
+                host: "bugen.dev".into(),
+                ttl: 120,
+            }
+        );
+        assert_eq!(records[4].name(), "bugen.dev");
+        assert_eq!(records[5].name(), "v6.bugen.dev");
+    }
+
+    #[test]
+    fn parse_parentheses_and_strings() {
+        let text = r#"
+$TTL 1h
+@   IN  SOA ns1 admin (
+            2020110101  ; serial
+            2h          ; refresh
+            1h          ; retry
+            2w          ; expire
+            5m )        ; minimum
+txt     TXT "v=spf1 -all" "with \"quotes\" ; and (parens)"
+        TXT ( "multi"
+              "line" )
+"#;
+        let records = parse_zone(text, "bugen.dev").unwrap();
+        assert_eq!(records.len(), 3);
+        match &records[0] {
+            DnsRecord::SOA {
+                serial,
+                refresh,
+                expire,
+                minimum,
+                ttl,
+                ..
+            } => {
+                assert_eq!(*serial, 2020110101);
+                assert_eq!(*refresh, 7200);
+                assert_eq!(*expire, 1209600);
+                assert_eq!(*minimum, 300);
+                assert_eq!(*ttl, 3600);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(
+            records[1],
+            DnsRecord::TXT {
+                name: "txt.bugen.dev".into(),
+                data: vec![
+                    "v=spf1 -all".into(),
+                    "with \"quotes\" ; and (parens)".into()
+                ],
+                ttl: 3600,
+            }
+        );
+        assert_eq!(records[2].name(), "txt.bugen.dev");
+    }
+
+    #[test]
+    fn parse_include() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("res/include.zone");
+        let records = parse_zone_file(&path, "bugen.dev").unwrap();
+        assert_eq!(records.len(), 3);
+        // the included file has its own origin
+        assert_eq!(records[1].name(), "www.sub.bugen.dev");
+        // which does not leak into the including one
+        assert_eq!(records[2].name(), "mail.bugen.dev");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let position = |text: &str| match parse_zone(text, "bugen.dev").unwrap_err() {
+            Error::ZoneFile { line, column, .. } => (line, column),
+            e => panic!("unexpected {}", e),
+        };
+
+        assert_eq!(position("www 300 A 1.2.3"), (1, 11));
+        assert_eq!(position("\n\nwww A 1.2.3.4"), (3, 5));
+        assert_eq!(position("www 300 HINFO a b"), (1, 9));
+        assert_eq!(position("www 300 MX 10 mail extra"), (1, 20));
+        assert_eq!(position("@ 300 SOA ns admin (\n 1 2 3 4 5"), (1, 20));
+        assert_eq!(position("@ 300 TXT \"unterminated"), (1, 11));
+        assert_eq!(position("$INCLUDE"), (1, 1));
+        assert_eq!(position("$GENERATE 1-2 a A 1.2.3.4"), (1, 1));
+    }
+
+    #[test]
+    fn write_and_parse_back() {
+        let text = include_str!("../res/bugen.dev.zone");
+        let records = parse_zone(text, "bugen.dev").unwrap();
+        let written = write_zone("bugen.dev", &records);
+
+        assert!(written.starts_with("$ORIGIN bugen.dev.\nbugen.dev.\t3600\tIN\tSOA\t"));
+        assert!(written.contains("mail.bugen.dev.\t3600\tIN\tA\t10.0.0.2\n"));
+
+        let mut parsed_back = parse_zone(&written, "").unwrap();
+        let mut records = records;
+        let key = |r: &DnsRecord| format!("{:?}", r);
+        parsed_back.sort_by_key(key);
+        records.sort_by_key(key);
+        assert_eq!(parsed_back, records);
+    }
+
+    #[test]
+    fn canonical_order() {
+        assert_eq!(canonical_cmp("bugen.dev", "a.bugen.dev"), Ordering::Less);
+        assert_eq!(canonical_cmp("A.bugen.dev", "a.bugen.dev"), Ordering::Equal);
+        assert_eq!(
+            canonical_cmp("z.a.bugen.dev", "b.bugen.dev"),
+            Ordering::Less
+        );
+        // a single label with an escaped dot
+        assert_eq!(
+            canonical_cmp("a\\.z.bugen.dev", "b.bugen.dev"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn write_txt_record() {
+        let record = DnsRecord::TXT {
+            name: "bugen.dev".into(),
+            data: vec!["say \"hi\"".into(), "\u{7}".into()],
+            ttl: 60,
+        };
+        assert_eq!(
+            record.to_string(),
+            "bugen.dev.\t60\tIN\tTXT\t\"say \\\"hi\\\"\" \"\\007\""
+        );
+        assert_eq!(parse_zone(&record.to_string(), "").unwrap(), vec![record]);
+    }
+
+    #[test]
+    fn parse_escapes() {
+        let records = parse_zone("a\\.b 60 TXT \"caf\\195\\169\" x\\.y\n", "bugen.dev").unwrap();
+        assert_eq!(records[0].name(), "a\\.b.bugen.dev");
+        assert_eq!(
+            records[0].to_string(),
+            "a\\.b.bugen.dev.\t60\tIN\tTXT\t\"caf\\195\\169\" \"x.y\""
+        );
+        assert_eq!(parse_zone(&records[0].to_string(), "").unwrap(), records);
+
+        // a name ending with an escaped dot is still relative
+        let records = parse_zone("a\\. 60 CNAME b\\046\\\\.\n", "bugen.dev").unwrap();
+        assert_eq!(records[0].name(), "a\\..bugen.dev");
+        assert_eq!(records[0].rdata_text(), "b\\.\\\\.");
+
+        // lone bytes that are not UTF-8, and meta types
+        assert!(parse_zone("@ 60 TXT \"\\200\"\n", "bugen.dev").is_err());
+        assert!(parse_zone("@ 60 ANY 1.2.3.4\n", "bugen.dev").is_err());
+    }
+}